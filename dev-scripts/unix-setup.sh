@@ -23,6 +23,10 @@ fi
 echo "Installing SQLite..."
 brew install sqlite
 
+# Install libpq, which the PostgreSQL backend links against
+echo "Installing libpq..."
+brew install libpq
+
 # Install protobuf compiler
 echo "Installing protobuf compiler..."
 brew install protobuf
@@ -5,6 +5,10 @@ $ErrorActionPreference = "Stop"
 Write-Host "Installing SQLite..."
 choco install sqlite -y
 
+# Install PostgreSQL, which provides the libpq the PostgreSQL backend links against
+Write-Host "Installing PostgreSQL..."
+choco install postgresql -y
+
 # Install protobuf compiler
 Write-Host "Installing protobuf compiler..."
 choco install protoc -y
@@ -0,0 +1,326 @@
+//! Bulk imports of stock listings.
+//!
+//! A listing is either a CSV file with a header row or a JSON array of objects. Both carry
+//! `name`, `symbol` and `exchange_name` (or `exchange`) for every stock. Stocks that aren't in the
+//! database yet are added, ones that are get their name and exchange updated and are relisted.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use diesel::{ExpressionMethods, RunQueryDsl};
+use serde::Deserialize;
+
+use crate::db::filter::Filter;
+use crate::db::manager::{db_error, DBManager, DatabaseImpl, InsertRowsError, Transaction};
+use crate::db::models::stock::{schema::stock, Stock, StockBuilder};
+
+/// The longest symbol a listing may contain.
+const MAX_SYMBOL_LEN: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ListingFormat {
+    Csv,
+    Json,
+}
+
+impl ListingFormat {
+    /// Picks the format from the file extension.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Ok(ListingFormat::Csv),
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Ok(ListingFormat::Json),
+            _ => Err(anyhow!(
+                "Can't tell the format of {} from its extension, pass it explicitly",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// One stock in a listing file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ListingRow {
+    pub name: String,
+    pub symbol: String,
+    #[serde(alias = "exchange")]
+    pub exchange_name: String,
+}
+
+/// A row that was left out of an import. Rows are numbered from 1, not counting a CSV header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedRow {
+    pub row: usize,
+    pub reason: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    pub added: usize,
+    pub updated: usize,
+    /// Rows that matched the database exactly.
+    pub unchanged: usize,
+    pub rejected: Vec<RejectedRow>,
+}
+
+impl ImportReport {
+    fn reject(&mut self, row: usize, reason: impl Into<String>) {
+        self.rejected.push(RejectedRow {
+            row,
+            reason: reason.into(),
+        });
+    }
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} updated, {} unchanged, {} rejected",
+            self.added,
+            self.updated,
+            self.unchanged,
+            self.rejected.len()
+        )
+    }
+}
+
+/// Parses every row of a listing. A row that can't be parsed is returned as the reason why
+/// instead of failing the whole file.
+pub fn read_listing(
+    reader: impl Read,
+    format: ListingFormat,
+) -> Result<Vec<(usize, Result<ListingRow, String>)>> {
+    let rows = match format {
+        ListingFormat::Csv => csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader)
+            .deserialize::<ListingRow>()
+            .map(|row| row.map_err(|e| e.to_string()))
+            .collect::<Vec<_>>(),
+        ListingFormat::Json => serde_json::from_reader::<_, Vec<serde_json::Value>>(reader)
+            .context("A JSON listing must be an array of objects")?
+            .into_iter()
+            .map(|row| serde_json::from_value::<ListingRow>(row).map_err(|e| e.to_string()))
+            .collect(),
+    };
+    Ok((1..).zip(rows).collect())
+}
+
+/// Imports the listing at `path`, guessing its format from the extension if `format` is `None`.
+pub fn import_file(
+    db_manager: &DBManager,
+    path: &Path,
+    format: Option<ListingFormat>,
+) -> Result<ImportReport> {
+    let format = format.map_or_else(|| ListingFormat::from_path(path), Ok)?;
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    import_listing(db_manager, read_listing(BufReader::new(file), format)?)
+}
+
+/// Adds or updates every valid row of a listing in one transaction. Invalid rows and symbols
+/// that appear more than once are rejected without affecting the rest.
+pub fn import_listing(
+    db_manager: &DBManager,
+    listing: Vec<(usize, Result<ListingRow, String>)>,
+) -> Result<ImportReport> {
+    let mut report = ImportReport::default();
+    let mut seen = HashSet::new();
+    let mut rows = Vec::new();
+    for (row, parsed) in listing {
+        match parsed.and_then(validate) {
+            Ok(listing) if !seen.insert(listing.symbol.clone()) => {
+                report.reject(row, format!("{} appears more than once", listing.symbol))
+            }
+            Ok(listing) => rows.push((row, listing)),
+            Err(reason) => report.reject(row, reason),
+        }
+    }
+
+    let mut report = db_manager.transaction(|tx| upsert(tx, &rows, report.clone()))?;
+    report.rejected.sort_by_key(|r| r.row);
+    Ok(report)
+}
+
+fn validate(row: ListingRow) -> Result<ListingRow, String> {
+    let symbol = row.symbol.trim().to_ascii_uppercase();
+    let valid = symbol.len() <= MAX_SYMBOL_LEN
+        && symbol.starts_with(|c: char| c.is_ascii_uppercase())
+        && symbol
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '.' || c == '-');
+    if !valid {
+        return Err(format!("Invalid symbol {:?}", row.symbol));
+    }
+
+    let name = row.name.trim();
+    if name.is_empty() {
+        return Err(format!("{symbol} has no name"));
+    }
+    let exchange_name = row.exchange_name.trim();
+    if exchange_name.is_empty() {
+        return Err(format!("{symbol} has no exchange"));
+    }
+
+    Ok(ListingRow {
+        name: name.to_owned(),
+        symbol,
+        exchange_name: exchange_name.to_owned(),
+    })
+}
+
+fn upsert(
+    tx: &Transaction,
+    rows: &[(usize, ListingRow)],
+    mut report: ImportReport,
+) -> Result<ImportReport> {
+    let existing = tx.with_connection(|conn| {
+        stock::table
+            .load::<Stock>(conn)
+            .map_err(db_error("Load stocks"))
+    })?;
+    let existing = existing
+        .into_iter()
+        .map(|s| (s.symbol.clone(), s))
+        .collect::<HashMap<_, _>>();
+
+    let mut new = Vec::new();
+    for (row, listing) in rows {
+        match existing.get(&listing.symbol) {
+            None => new.push((
+                *row,
+                StockBuilder::default()
+                    .id(None)
+                    .name(listing.name.clone())
+                    .symbol(listing.symbol.clone())
+                    .exchange_name(listing.exchange_name.clone())
+                    .build()?,
+            )),
+            Some(stock)
+                if stock.name == listing.name
+                    && stock.exchange_name == listing.exchange_name
+                    && !stock.delisted =>
+            {
+                report.unchanged += 1
+            }
+            Some(_) => {
+                tx.update_rows(
+                    stock::table,
+                    vec![Filter::eq(stock::symbol, &listing.symbol)],
+                    (
+                        stock::name.eq(&listing.name),
+                        stock::exchange_name.eq(&listing.exchange_name),
+                        stock::delisted.eq(false),
+                    ),
+                )?;
+                report.updated += 1;
+            }
+        }
+    }
+
+    // insert_rows adds all of the rows or none, so drop the ones it rejects and go again
+    loop {
+        let err = match tx.insert_rows(stock::table, new.iter().map(|(_, s)| s).collect()) {
+            Ok(added) => {
+                report.added += added;
+                return Ok(report);
+            }
+            Err(err) => err,
+        };
+        let failed = match err.downcast_ref::<InsertRowsError>() {
+            Some(failed) if !failed.failed.is_empty() => failed
+                .failed
+                .iter()
+                .map(|(index, e)| (*index, e.to_string()))
+                .collect::<HashMap<_, _>>(),
+            _ => return Err(err),
+        };
+
+        new = new
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, (row, stock))| match failed.get(&index) {
+                Some(reason) => {
+                    report.reject(row, format!("{}: {reason}", stock.symbol));
+                    None
+                }
+                None => Some((row, stock)),
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::test_utils::memory_db_manager;
+
+    #[tokio::test]
+    async fn test_import_csv() {
+        let db_manager = memory_db_manager();
+        crate::admin::add_stock(&db_manager, "Apple", "AAPL", "NASDAQ")
+            .await
+            .unwrap();
+        crate::admin::add_stock(&db_manager, "Microsoft", "MSFT", "NYSE")
+            .await
+            .unwrap();
+
+        let csv = "\
+name,symbol,exchange_name
+Apple,AAPL,NASDAQ
+Microsoft,MSFT,NASDAQ
+Berkshire Hathaway,brk.b,NYSE
+Nvidia,NVDA,NASDAQ
+Not A Stock,$$$,NASDAQ
+Nvidia Again,NVDA,NASDAQ
+No Exchange,NOEX,
+";
+        let listing = read_listing(csv.as_bytes(), ListingFormat::Csv).unwrap();
+        let report = import_listing(&db_manager, listing).unwrap();
+
+        assert_eq!(report.added, 2);
+        assert_eq!(report.updated, 1);
+        assert_eq!(report.unchanged, 1);
+        assert_eq!(
+            report.rejected.iter().map(|r| r.row).collect::<Vec<_>>(),
+            vec![5, 6, 7]
+        );
+
+        let stocks = crate::admin::list_stocks(&db_manager).await.unwrap();
+        let msft = stocks.iter().find(|s| s.symbol == "MSFT").unwrap();
+        assert_eq!(msft.exchange_name, "NASDAQ");
+        assert!(stocks.iter().any(|s| s.symbol == "BRK.B"));
+        assert_eq!(stocks.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_import_json_relists() {
+        let db_manager = memory_db_manager();
+        crate::admin::add_stock(&db_manager, "Apple", "AAPL", "NASDAQ")
+            .await
+            .unwrap();
+        crate::admin::delist_stock(&db_manager, "AAPL")
+            .await
+            .unwrap();
+
+        let json = r#"[
+            {"name": "Apple", "symbol": "AAPL", "exchange": "NASDAQ"},
+            {"name": "Tesla", "symbol": "TSLA", "exchange_name": "NASDAQ"},
+            {"name": "Missing symbol", "exchange": "NASDAQ"}
+        ]"#;
+        let listing = read_listing(json.as_bytes(), ListingFormat::Json).unwrap();
+        let report = import_listing(&db_manager, listing).unwrap();
+        assert_eq!(
+            report.to_string(),
+            "1 added, 1 updated, 0 unchanged, 1 rejected"
+        );
+
+        let stocks = crate::admin::list_stocks(&db_manager).await.unwrap();
+        assert!(stocks.iter().all(|s| !s.delisted));
+
+        assert!(read_listing("{}".as_bytes(), ListingFormat::Json).is_err());
+    }
+}
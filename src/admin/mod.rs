@@ -0,0 +1,270 @@
+//! Operator tasks behind the admin commands of the backend binary.
+//!
+//! Everything here goes through [`DBManager`] and the model builders, the same way the gRPC
+//! services do, so the admin commands can't write rows the server wouldn't.
+
+pub mod import;
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use crate::db::error::DbError;
+use crate::db::filter::Filter;
+use crate::db::manager::{DBManager, DatabaseImpl};
+use crate::db::models::{
+    session::schema::sessions,
+    stock::{Stock, StockBuilder},
+    user::{User, UserBuilder},
+    wallet::Wallet,
+};
+use crate::db::repository::{
+    stock::{StockRepository, StockRepositoryImpl},
+    user::{UserRepository, UserRepositoryImpl},
+    wallet::{WalletRepository, WalletRepositoryImpl},
+};
+use crate::money::Amount;
+use crate::passwords::Password;
+use crate::trading::{market::DEFAULT_QUOTE_CURRENCY, settlement};
+
+/// Creates a user who can log in with `password`.
+pub async fn create_user(
+    db_manager: &DBManager,
+    email: &str,
+    password: &str,
+    first_name: &str,
+    last_name: &str,
+) -> Result<User> {
+    let password = Password::new(password).map_err(|e| anyhow!("Invalid password: {e}"))?;
+    let user = UserBuilder::default()
+        .id(None)
+        .email(email.to_owned())
+        .password(password.hashed().to_owned())
+        .first_name(first_name.to_owned())
+        .last_name(last_name.to_owned())
+        .build()?;
+
+    users(db_manager)
+        .create(user)
+        .await
+        .map_err(|e| match e.downcast_ref::<DbError>() {
+            Some(DbError::UniqueViolation(_)) => {
+                anyhow!("A user with email {email} already exists")
+            }
+            _ => e,
+        })
+}
+
+pub async fn list_users(db_manager: &DBManager) -> Result<Vec<User>> {
+    users(db_manager).list().await
+}
+
+/// Stops a user from logging in and ends the sessions they have in the database. Sessions a
+/// server keeps in memory end on their next request, when the server finds the user disabled.
+/// Their wallets and orders are left as they are.
+pub async fn disable_user(db_manager: &DBManager, email: &str) -> Result<()> {
+    if !users(db_manager).set_disabled(email, true).await? {
+        return Err(anyhow!("No user with email {email}"));
+    }
+
+    // disabled first, so a login that races this can't leave a session behind
+    let user_id = user_id(db_manager, email).await?;
+    db_manager
+        .run(move |db| {
+            db.delete_rows(
+                sessions::table,
+                vec![Filter::eq(sessions::user_id, user_id)],
+            )
+        })
+        .await?;
+    Ok(())
+}
+
+/// Lists a stock. The trading service opens a market for it on its next sync.
+pub async fn add_stock(
+    db_manager: &DBManager,
+    name: &str,
+    symbol: &str,
+    exchange_name: &str,
+) -> Result<Stock> {
+    let stock = StockBuilder::default()
+        .id(None)
+        .name(name.to_owned())
+        .symbol(symbol.to_owned())
+        .exchange_name(exchange_name.to_owned())
+        .build()?;
+
+    stocks(db_manager)
+        .create(stock)
+        .await
+        .map_err(|e| match e.downcast_ref::<DbError>() {
+            Some(DbError::UniqueViolation(_)) => anyhow!("{symbol} is already listed"),
+            _ => e,
+        })
+}
+
+/// Lists every stock, delisted ones included.
+pub async fn list_stocks(db_manager: &DBManager) -> Result<Vec<Stock>> {
+    stocks(db_manager).list().await
+}
+
+/// Delists a stock. The trading service cancels the orders resting in its market and drops the
+/// market on its next sync, while wallets keep holding it.
+pub async fn delist_stock(db_manager: &DBManager, symbol: &str) -> Result<()> {
+    if symbol == DEFAULT_QUOTE_CURRENCY {
+        return Err(anyhow!(
+            "{symbol} is the quote currency and can't be delisted"
+        ));
+    }
+    if !stocks(db_manager).set_delisted(symbol, true).await? {
+        return Err(anyhow!("No stock is listed as {symbol}"));
+    }
+    Ok(())
+}
+
+/// Adds `amount` of `symbol` to a user's wallet, opening the wallet if they don't have one.
+///
+/// # Returns
+/// The wallet after the credit.
+pub async fn credit_wallet(
+    db_manager: &DBManager,
+    email: &str,
+    symbol: &str,
+    amount: Amount,
+) -> Result<Wallet> {
+    if !amount.is_positive() {
+        return Err(anyhow!("Credit amount must be positive, got {amount}"));
+    }
+    let user_id = user_id(db_manager, email).await?;
+    let symbol = symbol.to_owned();
+
+    db_manager
+        .run(move |db| db.transaction(|tx| Ok(settlement::deposit(tx, user_id, &symbol, amount)?)))
+        .await
+}
+
+/// Every wallet a user holds, paired with the symbol of its stock.
+pub async fn wallets(db_manager: &DBManager, email: &str) -> Result<Vec<(String, Wallet)>> {
+    let user_id = user_id(db_manager, email).await?;
+    let symbols = list_stocks(db_manager)
+        .await?
+        .into_iter()
+        .filter_map(|s| Some((s.id?, s.symbol)))
+        .collect::<HashMap<_, _>>();
+
+    let wallets = WalletRepository::new(db_manager.clone())
+        .for_user(user_id)
+        .await?;
+    Ok(wallets
+        .into_iter()
+        .map(|w| (symbols.get(&w.stock_id).cloned().unwrap_or_default(), w))
+        .collect())
+}
+
+fn users(db_manager: &DBManager) -> UserRepository {
+    UserRepository::new(db_manager.clone())
+}
+
+fn stocks(db_manager: &DBManager) -> StockRepository {
+    StockRepository::new(db_manager.clone())
+}
+
+async fn user_id(db_manager: &DBManager, email: &str) -> Result<i32> {
+    users(db_manager)
+        .find_by_email(email)
+        .await?
+        .ok_or_else(|| anyhow!("No user with email {email}"))?
+        .id
+        .ok_or_else(|| anyhow!("User row is missing an id"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::test_utils::memory_db_manager;
+    use crate::session::database::DbSessionManager;
+    use crate::session::manager::{ClientInfo, SessionConfig, SessionManagerImpl};
+
+    #[tokio::test]
+    async fn test_users() {
+        let db_manager = memory_db_manager();
+        let user = create_user(&db_manager, "ada@example.com", "hunter2", "Ada", "Lovelace")
+            .await
+            .unwrap();
+        assert!(user.verify_password("hunter2").unwrap());
+        assert!(!user.disabled);
+
+        let err = create_user(&db_manager, "ada@example.com", "other", "Ada", "L")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+
+        let sessions = DbSessionManager::new(SessionConfig::default(), db_manager.clone());
+        let issued = sessions
+            .new_session(user, ClientInfo::default())
+            .await
+            .unwrap();
+        disable_user(&db_manager, "ada@example.com").await.unwrap();
+        assert!(sessions.user_sessions(1).await.unwrap().is_empty());
+        assert!(sessions
+            .refresh_session(issued.refresh_token)
+            .await
+            .is_err());
+        assert!(disable_user(&db_manager, "nobody@example.com")
+            .await
+            .is_err());
+
+        let users = list_users(&db_manager).await.unwrap();
+        assert_eq!(users.len(), 1);
+        assert!(users[0].disabled);
+    }
+
+    #[tokio::test]
+    async fn test_stocks_and_wallets() {
+        let db_manager = memory_db_manager();
+        create_user(&db_manager, "ada@example.com", "hunter2", "Ada", "Lovelace")
+            .await
+            .unwrap();
+        add_stock(&db_manager, "US Dollar", DEFAULT_QUOTE_CURRENCY, "CASH")
+            .await
+            .unwrap();
+        add_stock(&db_manager, "Apple", "AAPL", "NASDAQ")
+            .await
+            .unwrap();
+        assert!(add_stock(&db_manager, "Apple", "AAPL", "NYSE")
+            .await
+            .is_err());
+
+        let amount = Amount::try_from(2.5).unwrap();
+        credit_wallet(&db_manager, "ada@example.com", "AAPL", amount)
+            .await
+            .unwrap();
+        let wallet = credit_wallet(&db_manager, "ada@example.com", "AAPL", amount)
+            .await
+            .unwrap();
+        assert_eq!(wallet.balance, Amount::try_from(5.0).unwrap());
+        assert!(
+            credit_wallet(&db_manager, "ada@example.com", "AAPL", Amount::ZERO)
+                .await
+                .is_err()
+        );
+        assert!(
+            credit_wallet(&db_manager, "ada@example.com", "MSFT", amount)
+                .await
+                .is_err()
+        );
+
+        delist_stock(&db_manager, "AAPL").await.unwrap();
+        assert!(delist_stock(&db_manager, DEFAULT_QUOTE_CURRENCY)
+            .await
+            .is_err());
+        let stocks = list_stocks(&db_manager).await.unwrap();
+        assert!(stocks.iter().any(|s| s.symbol == "AAPL" && s.delisted));
+
+        // the wallet outlives the listing
+        let held = wallets(&db_manager, "ada@example.com").await.unwrap();
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].0, "AAPL");
+        assert_eq!(held[0].1.balance, Amount::try_from(5.0).unwrap());
+    }
+}
@@ -1,54 +1,296 @@
-use std::{net::Ipv4Addr, sync::Arc};
+use std::{net::Ipv4Addr, path::PathBuf, sync::Arc, time::Duration};
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
 use moss_street_libs::{
+    admin::{
+        self,
+        import::{self, ListingFormat},
+    },
     db::{
+        backup::{restore, BackupSchedule},
+        connection::{DatabaseUri, DbConnectionManager, JournalMode, SqlitePragmas, Synchronous},
         manager::DBManager,
-        models::{stock::Stock, user::User, wallet::Wallet},
+        migrations::{current_version, run_migrations, MIGRATIONS},
+    },
+    http::{
+        dependencies::{Repositories, ServerDependencies},
+        server::Server,
     },
-    http::{dependencies::ServerDependencies, server::Server},
-    session::manager::SessionManager,
+    money::Amount,
+    session::manager::{SessionConfig, SessionManager, SessionStore},
 };
 
-use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::r2d2::Pool;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    serve: ServeArgs,
+
+    /// Location of a SQLite database, or a postgres:// uri to use PostgreSQL instead
+    #[arg(short, long, global = true, default_value = "local.db")]
+    database_uri: DatabaseUri,
+
+    /// Seconds to wait for a free database connection before failing a request
+    #[arg(long, global = true, default_value = "5")]
+    db_connection_timeout: u64,
+
+    /// Most database connections to keep open at once
+    #[arg(long, global = true, default_value = "10")]
+    db_pool_size: u32,
+
+    /// SQLite journal mode
+    #[arg(long, global = true, value_enum, default_value = "wal")]
+    sqlite_journal_mode: JournalMode,
+
+    /// Milliseconds a SQLite connection waits for another to release a lock before failing
+    #[arg(long, global = true, default_value = "5000")]
+    sqlite_busy_timeout: u64,
+
+    /// How often SQLite waits for writes to reach the disk
+    #[arg(long, global = true, value_enum, default_value = "normal")]
+    sqlite_synchronous: Synchronous,
+}
+
+/// Settings for the server. They are global so they can be given with or without the `serve`
+/// command, which is also what runs when no command is given.
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
     /// Server ip to start the server on
-    #[arg(short, long, default_value = "127.0.0.1")]
+    #[arg(short, long, global = true, default_value = "127.0.0.1")]
     ip: Ipv4Addr,
 
     /// Server port to start the server on
-    #[arg(short, long, default_value = "8080")]
+    #[arg(short, long, global = true, default_value = "8080")]
     port: u32,
 
-    /// Location of database or uri of database
-    #[arg(short, long, default_value = "local.db")]
-    database_uri: String,
+    /// Directory to take periodic backups of the SQLite database into. No backups are taken
+    /// unless this is set
+    #[arg(long, global = true)]
+    backup_dir: Option<PathBuf>,
+
+    /// Seconds between periodic backups
+    #[arg(long, global = true, default_value = "3600")]
+    backup_interval: u64,
+
+    /// How many periodic backups to keep before deleting the oldest
+    #[arg(long, global = true, default_value = "24")]
+    backup_keep: usize,
+
+    /// Seconds an access token is accepted for
+    #[arg(long, global = true, default_value = "300")]
+    session_timeout: i64,
+
+    /// Seconds a refresh token can be traded for a new access token
+    #[arg(long, global = true, default_value = "86400")]
+    refresh_token_timeout: i64,
+
+    /// Extend a session's expiry every time its access token is used
+    #[arg(long, global = true)]
+    sliding_sessions: bool,
+
+    /// Where to keep sessions. Sessions in the database survive restarts and are shared by
+    /// every server using it
+    #[arg(long, global = true, value_enum, default_value = "memory")]
+    session_store: SessionStore,
+}
+
+/// Runs the server when no command is given.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Apply any pending migrations and run the server
+    Serve,
+    /// Apply any pending migrations and exit
+    Migrate,
+    /// Manage users
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage listed stocks
+    #[command(subcommand)]
+    Stock(StockCommand),
+    /// Manage user wallets
+    #[command(subcommand)]
+    Wallet(WalletCommand),
+    /// Back up the SQLite database to a file. Safe to run while the server is running
+    Backup { destination: PathBuf },
+    /// Restore a backup into a new SQLite database file
+    Restore {
+        backup: PathBuf,
+        destination: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum UserCommand {
+    /// Create a user
+    Create {
+        email: String,
+        #[arg(long)]
+        first_name: String,
+        #[arg(long)]
+        last_name: String,
+        /// Read from standard input when left out, which keeps it out of the shell history
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// List every user
+    List,
+    /// Stop a user from logging in
+    Disable { email: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum StockCommand {
+    /// List a stock so it can be traded
+    Add {
+        symbol: String,
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        exchange: String,
+    },
+    /// Add or update every stock in a CSV or JSON listing file
+    Import {
+        file: PathBuf,
+        /// Guessed from the file extension when left out
+        #[arg(long, value_enum)]
+        format: Option<ListingFormat>,
+    },
+    /// List every stock, delisted ones included
+    List,
+    /// Stop trading a stock. Wallets keep holding it
+    Delist { symbol: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum WalletCommand {
+    /// Add funds to a user's wallet
+    Credit {
+        email: String,
+        symbol: String,
+        amount: Amount,
+    },
+    /// Show every wallet a user holds
+    Show { email: String },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    let command = args.command.unwrap_or(Command::Serve);
 
-    let manager = ConnectionManager::new(args.database_uri);
-    let pool = Pool::new(manager)?;
-
-    let db_manager = Arc::new(DBManager::new(pool));
+    if let Command::Restore {
+        backup,
+        destination,
+    } = &command
+    {
+        restore(backup, destination)?;
+        println!(
+            "Restored {} into {}",
+            backup.display(),
+            destination.display()
+        );
+        return Ok(());
+    }
 
-    let Some(mut connection) = db_manager.connection_pool.try_get() else {
-        return Err(anyhow::anyhow!("bad connection"));
+    let pragmas = SqlitePragmas {
+        journal_mode: args.sqlite_journal_mode,
+        busy_timeout: Duration::from_millis(args.sqlite_busy_timeout),
+        synchronous: args.sqlite_synchronous,
     };
+    let manager = DbConnectionManager::new(args.database_uri);
+    let pool = Pool::builder()
+        .max_size(args.db_pool_size)
+        .connection_customizer(Box::new(pragmas))
+        .build(manager)?;
+    let max_blocking = pool.max_size() as usize;
 
-    let _ = User::initialize_database(&mut connection);
-    let _ = Stock::initialize_database(&mut connection);
-    let _ = Wallet::initialize_database(&mut connection);
+    let db_manager = Arc::new(DBManager::with_limits(
+        pool,
+        max_blocking,
+        Duration::from_secs(args.db_connection_timeout),
+    ));
 
-    let session_manager = Arc::new(SessionManager::default());
+    match command {
+        Command::Serve => {
+            migrate(&db_manager)?;
+            serve(db_manager, args.serve).await
+        }
+        Command::Migrate => migrate(&db_manager),
+        Command::User(command) => {
+            check_schema(&db_manager)?;
+            user_command(&db_manager, command).await
+        }
+        Command::Stock(command) => {
+            check_schema(&db_manager)?;
+            stock_command(&db_manager, command).await
+        }
+        Command::Wallet(command) => {
+            check_schema(&db_manager)?;
+            wallet_command(&db_manager, command).await
+        }
+        Command::Backup { destination } => {
+            db_manager.backup(&destination)?;
+            println!("Backed up database to {}", destination.display());
+            Ok(())
+        }
+        Command::Restore { .. } => unreachable!("restore runs before the pool is built"),
+    }
+}
+
+fn migrate(db_manager: &DBManager) -> Result<()> {
+    let mut connection = db_manager.connection()?;
+    for migration in run_migrations(&mut connection)? {
+        println!(
+            "Applied migration {}: {}",
+            migration.version, migration.name
+        );
+    }
+    Ok(())
+}
+
+/// Admin commands don't migrate on their own, so they never change the schema under a server
+/// that is still running an older build.
+fn check_schema(db_manager: &DBManager) -> Result<()> {
+    let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+    let current = current_version(&mut *db_manager.connection()?)?;
+    if current < latest {
+        return Err(anyhow!(
+            "The database is at schema version {current} but {latest} is required, run the migrate command first"
+        ));
+    }
+    Ok(())
+}
+
+async fn serve(db_manager: Arc<DBManager>, args: ServeArgs) -> Result<()> {
+    if let Some(dir) = args.backup_dir {
+        let schedule = BackupSchedule {
+            dir,
+            period: Duration::from_secs(args.backup_interval),
+            keep: args.backup_keep,
+        };
+        tokio::spawn(DBManager::clone(&db_manager).watch_backups(schedule));
+    }
+
+    let session_config = SessionConfig {
+        token_timeout: chrono::Duration::seconds(args.session_timeout),
+        refresh_token_timeout: chrono::Duration::seconds(args.refresh_token_timeout),
+        sliding_expiry: args.sliding_sessions,
+    };
+    let session_manager = Arc::new(match args.session_store {
+        SessionStore::Memory => SessionManager::new(session_config),
+        SessionStore::Database => {
+            SessionManager::database(session_config, DBManager::clone(&db_manager))
+        }
+    });
 
-    let dependencies = ServerDependencies::new(db_manager, session_manager);
+    let repositories = Repositories::database(&db_manager);
+    let dependencies = ServerDependencies::new(db_manager, session_manager, repositories);
 
     let ip = format!("{}:{}", args.ip, args.port);
     let addr = ip.parse()?;
@@ -64,3 +306,147 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+async fn user_command(db_manager: &DBManager, command: UserCommand) -> Result<()> {
+    match command {
+        UserCommand::Create {
+            email,
+            first_name,
+            last_name,
+            password,
+        } => {
+            let password = match password {
+                Some(password) => password,
+                None => read_password()?,
+            };
+            let user =
+                admin::create_user(db_manager, &email, &password, &first_name, &last_name).await?;
+            println!(
+                "Created user {} ({})",
+                user.email,
+                user.id.unwrap_or_default()
+            );
+        }
+        UserCommand::List => {
+            println!("{:>6}  {:<32}  {:<24}  STATUS", "ID", "EMAIL", "NAME");
+            for user in admin::list_users(db_manager).await? {
+                println!(
+                    "{:>6}  {:<32}  {:<24}  {}",
+                    user.id.unwrap_or_default(),
+                    user.email,
+                    format!("{} {}", user.first_name, user.last_name),
+                    if user.disabled { "disabled" } else { "active" }
+                );
+            }
+        }
+        UserCommand::Disable { email } => {
+            admin::disable_user(db_manager, &email).await?;
+            println!("Disabled {email}");
+        }
+    }
+    Ok(())
+}
+
+async fn stock_command(db_manager: &DBManager, command: StockCommand) -> Result<()> {
+    match command {
+        StockCommand::Add {
+            symbol,
+            name,
+            exchange,
+        } => {
+            let stock = admin::add_stock(db_manager, &name, &symbol, &exchange).await?;
+            println!("Listed {} ({})", stock.symbol, stock.id.unwrap_or_default());
+        }
+        StockCommand::Import { file, format } => {
+            let report = db_manager
+                .run(move |db| import::import_file(db, &file, format))
+                .await?;
+            for rejected in &report.rejected {
+                println!("Rejected row {}: {}", rejected.row, rejected.reason);
+            }
+            println!("{report}");
+        }
+        StockCommand::List => {
+            println!(
+                "{:>6}  {:<8}  {:<32}  {:<12}  STATUS",
+                "ID", "SYMBOL", "NAME", "EXCHANGE"
+            );
+            for stock in admin::list_stocks(db_manager).await? {
+                println!(
+                    "{:>6}  {:<8}  {:<32}  {:<12}  {}",
+                    stock.id.unwrap_or_default(),
+                    stock.symbol,
+                    stock.name,
+                    stock.exchange_name,
+                    if stock.delisted { "delisted" } else { "listed" }
+                );
+            }
+        }
+        StockCommand::Delist { symbol } => {
+            admin::delist_stock(db_manager, &symbol).await?;
+            println!("Delisted {symbol}");
+        }
+    }
+    Ok(())
+}
+
+async fn wallet_command(db_manager: &DBManager, command: WalletCommand) -> Result<()> {
+    match command {
+        WalletCommand::Credit {
+            email,
+            symbol,
+            amount,
+        } => {
+            let wallet = admin::credit_wallet(db_manager, &email, &symbol, amount).await?;
+            println!(
+                "Credited {amount} {symbol} to {email}, balance is now {}",
+                wallet.balance
+            );
+        }
+        WalletCommand::Show { email } => {
+            println!(
+                "{:<8}  {:>20}  {:>20}  {:>20}",
+                "SYMBOL", "BALANCE", "RESERVED", "AVAILABLE"
+            );
+            for (symbol, wallet) in admin::wallets(db_manager, &email).await? {
+                println!(
+                    "{:<8}  {:>20}  {:>20}  {:>20}",
+                    symbol,
+                    wallet.balance.to_string(),
+                    wallet.reserved.to_string(),
+                    wallet.available().to_string()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_password() -> Result<String> {
+    eprint!("Password: ");
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']).to_owned();
+    if password.is_empty() {
+        return Err(anyhow!("No password given"));
+    }
+    Ok(password)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serve_flags_parse_with_or_without_the_command() {
+        for argv in [
+            &["backend", "--port", "9000"][..],
+            &["backend", "serve", "--port", "9000"],
+            &["backend", "--port", "9000", "serve"],
+        ] {
+            let args = Args::try_parse_from(argv).unwrap();
+            assert!(matches!(args.command, None | Some(Command::Serve)));
+            assert_eq!(args.serve.port, 9000);
+        }
+    }
+}
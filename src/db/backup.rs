@@ -0,0 +1,310 @@
+//! Online backups of a SQLite database.
+//!
+//! Backups are taken with SQLite's online backup API, so they are a consistent snapshot even
+//! while the server keeps writing. Every backup is written next to its destination first and
+//! renamed into place once complete, so a backup file is never left half written.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use diesel::sql_types::Text;
+use diesel::{QueryableByName, RunQueryDsl};
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, OpenFlags};
+
+use super::connection::DbConnection;
+use super::manager::DBManager;
+
+/// How long to wait before retrying a backup step that found the database locked.
+const BACKUP_RETRY_PAUSE: Duration = Duration::from_millis(10);
+
+/// How long a backup keeps retrying a database that stays locked before it gives up.
+const BACKUP_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where and how often [`DBManager::watch_backups`] takes backups.
+#[derive(Debug, Clone)]
+pub struct BackupSchedule {
+    pub dir: PathBuf,
+    pub period: Duration,
+    /// How many of the newest backups to keep. Older ones are deleted after every backup.
+    pub keep: usize,
+}
+
+#[derive(QueryableByName)]
+struct DatabaseFile {
+    #[diesel(sql_type = Text)]
+    file: String,
+}
+
+impl DBManager {
+    /// Copies the database to `destination`, which is replaced if it exists.
+    pub fn backup(&self, destination: &Path) -> Result<()> {
+        let source =
+            Connection::open_with_flags(self.database_file()?, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .context("Failed to open the database for backup")?;
+        source.busy_timeout(Duration::from_secs(5))?;
+        copy(&source, destination, BACKUP_BUSY_TIMEOUT)
+    }
+
+    /// Takes a backup into `schedule.dir` and deletes the ones that are no longer kept.
+    ///
+    /// # Returns
+    /// The path of the new backup.
+    pub fn backup_now(&self, schedule: &BackupSchedule) -> Result<PathBuf> {
+        let source = self.database_file()?;
+        let stem = backup_stem(&source);
+        let destination = schedule.dir.join(format!(
+            "{stem}-{}.db",
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+        ));
+
+        fs::create_dir_all(&schedule.dir)
+            .with_context(|| format!("Failed to create {}", schedule.dir.display()))?;
+        self.backup(&destination)?;
+        prune(&schedule.dir, &stem, schedule.keep)?;
+        Ok(destination)
+    }
+
+    /// Takes a backup every `schedule.period` until the task is dropped.
+    pub async fn watch_backups(self, schedule: BackupSchedule) {
+        let mut interval = tokio::time::interval(schedule.period);
+        // the first tick completes straight away, there is nothing new to back up at startup
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let schedule = schedule.clone();
+            match self.run(move |db| db.backup_now(&schedule)).await {
+                Ok(path) => println!("Backed up database to {}", path.display()),
+                Err(e) => eprintln!("Failed to back up database: {e:#}"),
+            }
+        }
+    }
+
+    /// The file the database lives in.
+    fn database_file(&self) -> Result<PathBuf> {
+        let mut conn = self.connection()?;
+        let DbConnection::Sqlite(conn) = &mut *conn else {
+            return Err(anyhow!(
+                "Only SQLite databases can be backed up, use pg_dump for PostgreSQL"
+            ));
+        };
+
+        let file = diesel::sql_query("SELECT file FROM pragma_database_list WHERE name = 'main'")
+            .get_result::<DatabaseFile>(conn)
+            .map_err(|e| anyhow!("Failed to find the database file: {e:#?}"))?
+            .file;
+        if file.is_empty() {
+            return Err(anyhow!("In-memory databases can't be backed up"));
+        }
+        Ok(PathBuf::from(file))
+    }
+}
+
+/// Restores `backup` into a new database at `destination`, which must not exist yet.
+pub fn restore(backup: &Path, destination: &Path) -> Result<()> {
+    if destination.exists() {
+        return Err(anyhow!(
+            "{} already exists, restore into a new file instead",
+            destination.display()
+        ));
+    }
+
+    let source = Connection::open_with_flags(backup, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open backup {}", backup.display()))?;
+    let integrity = source
+        .query_row("PRAGMA integrity_check", [], |row| row.get::<_, String>(0))
+        .with_context(|| format!("{} is not a SQLite database", backup.display()))?;
+    if integrity != "ok" {
+        return Err(anyhow!(
+            "Backup {} is corrupt: {integrity}",
+            backup.display()
+        ));
+    }
+
+    copy(&source, destination, BACKUP_BUSY_TIMEOUT)
+}
+
+/// Copies `source` to `destination`, failing if `source` stays locked for longer than
+/// `busy_timeout`.
+fn copy(source: &Connection, destination: &Path, busy_timeout: Duration) -> Result<()> {
+    let partial = PathBuf::from(format!("{}.partial", destination.display()));
+    let _ = fs::remove_file(&partial);
+
+    let result = (|| {
+        let mut target = Connection::open(&partial)?;
+        {
+            let backup = Backup::new(source, &mut target)?;
+            // Copy every page in one step. In WAL mode that reads a consistent snapshot without
+            // blocking writers, where smaller steps would start over whenever the server wrote
+            // in between.
+            let started = Instant::now();
+            loop {
+                match backup.step(-1)? {
+                    StepResult::Done => break,
+                    StepResult::More => {}
+                    _ if started.elapsed() >= busy_timeout => {
+                        return Err(anyhow!(
+                            "The database stayed locked for {busy_timeout:?}, giving up"
+                        ));
+                    }
+                    _ => std::thread::sleep(BACKUP_RETRY_PAUSE),
+                }
+            }
+        }
+        // the copy inherits WAL mode from the live database, switch back so a backup is one file
+        target.pragma_update(None, "journal_mode", "DELETE")?;
+        target.close().map_err(|(_, e)| e)?;
+        fs::rename(&partial, destination)
+            .with_context(|| format!("Failed to move backup into {}", destination.display()))
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result.with_context(|| format!("Backup to {} failed", destination.display()))
+}
+
+fn backup_stem(database: &Path) -> String {
+    database
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "database".to_owned())
+}
+
+/// Deletes all but the newest `keep` backups of `stem` in `dir`.
+fn prune(dir: &Path, stem: &str, keep: usize) -> Result<()> {
+    let prefix = format!("{stem}-");
+    let mut backups = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".db"))
+        })
+        .collect::<Vec<_>>();
+    // the timestamp in the name sorts oldest first
+    backups.sort();
+
+    let expired = backups.len().saturating_sub(keep);
+    for path in &backups[..expired] {
+        fs::remove_file(path).with_context(|| format!("Failed to delete {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::manager::DatabaseImpl;
+    use crate::db::models::stock::{schema::stock, Stock, StockBuilder};
+    use crate::db::test_utils::{memory_db_manager, sqlite_db_manager};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn db_manager(path: &Path) -> DBManager {
+        sqlite_db_manager(path.to_str().unwrap())
+    }
+
+    async fn symbols(db_manager: &DBManager) -> Vec<String> {
+        let stocks: Vec<Stock> = db_manager.query_rows(stock::table, vec![]).await.unwrap();
+        stocks.into_iter().map(|s| s.symbol).collect()
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let dir = temp_dir("backup");
+        let live = db_manager(&dir.join("live.db"));
+        let stock = StockBuilder::default()
+            .id(None)
+            .name("Apple".to_owned())
+            .symbol("AAPL".to_owned())
+            .exchange_name("NASDAQ".to_owned())
+            .build()
+            .unwrap();
+        live.insert_row(stock::table, &stock).unwrap();
+
+        // taken while the pool still holds open connections
+        let _held = live.connection().unwrap();
+        let backup = dir.join("snapshot.db");
+        live.backup(&backup).unwrap();
+
+        let restored = dir.join("restored.db");
+        restore(&backup, &restored).unwrap();
+        assert_eq!(symbols(&db_manager(&restored)).await, vec!["AAPL"]);
+
+        // never overwrites a database that is already there
+        let err = restore(&backup, &restored).unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_old_backups_are_pruned() {
+        let dir = temp_dir("prune");
+        for name in [
+            "live-20240101T000000.000Z.db",
+            "live-20240102T000000.000Z.db",
+            "live-20240103T000000.000Z.db",
+            "other-20240101T000000.000Z.db",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        prune(&dir, "live", 2).unwrap();
+
+        let mut left = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(
+            left,
+            vec![
+                "live-20240102T000000.000Z.db",
+                "live-20240103T000000.000Z.db",
+                "other-20240101T000000.000Z.db",
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_locked_database_gives_up() {
+        let dir = temp_dir("locked");
+        let live = dir.join("live.db");
+        let writer = Connection::open(&live).unwrap();
+        writer
+            .execute_batch("CREATE TABLE t (x INTEGER); BEGIN EXCLUSIVE; INSERT INTO t VALUES (1);")
+            .unwrap();
+
+        let source = Connection::open_with_flags(&live, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+        // rusqlite waits 5s for a lock by default, skip that so each step fails straight away
+        source.busy_timeout(Duration::ZERO).unwrap();
+        let destination = dir.join("snapshot.db");
+        let err = copy(&source, &destination, Duration::from_millis(50)).unwrap_err();
+        assert!(format!("{err:#}").contains("stayed locked"));
+        assert!(!destination.exists());
+
+        drop(writer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_in_memory_database_is_refused() {
+        let err = memory_db_manager()
+            .backup(&std::env::temp_dir().join("never.db"))
+            .unwrap_err();
+        assert!(err.to_string().contains("In-memory"));
+    }
+}
@@ -0,0 +1,323 @@
+//! The database backends the server can run against.
+//!
+//! Every query goes through [`DbConnection`], which wraps either a SQLite or a PostgreSQL
+//! connection. Which one is picked by the scheme of the database uri.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use diesel::connection::SimpleConnection;
+use diesel::pg::PgConnection;
+use diesel::r2d2::{self, R2D2Connection};
+use diesel::sqlite::SqliteConnection;
+use diesel::Connection;
+
+#[derive(diesel::MultiConnection)]
+pub enum DbConnection {
+    Sqlite(SqliteConnection),
+    Postgres(PgConnection),
+}
+
+/// The backend type of [`DbConnection`], dispatching to whichever database it is connected to.
+pub type DbBackend = MultiBackend;
+
+impl fmt::Debug for DbConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbConnection::Sqlite(_) => f.write_str("DbConnection::Sqlite"),
+            DbConnection::Postgres(_) => f.write_str("DbConnection::Postgres"),
+        }
+    }
+}
+
+/// Where the database lives.
+///
+/// `postgres://` and `postgresql://` uris connect to PostgreSQL. Anything else is a SQLite
+/// database: either a `sqlite://` uri, a plain file path or `:memory:`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DatabaseUri {
+    Sqlite(String),
+    Postgres(String),
+}
+
+impl FromStr for DatabaseUri {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("postgres://") || s.starts_with("postgresql://") {
+            return Ok(DatabaseUri::Postgres(s.to_owned()));
+        }
+        if let Some(path) = s.strip_prefix("sqlite://") {
+            return Ok(DatabaseUri::Sqlite(path.to_owned()));
+        }
+        if let Some((scheme, _)) = s.split_once("://") {
+            return Err(anyhow!("Unsupported database uri scheme {scheme:?}"));
+        }
+        if s.is_empty() {
+            return Err(anyhow!("Database uri is empty"));
+        }
+        Ok(DatabaseUri::Sqlite(s.to_owned()))
+    }
+}
+
+impl fmt::Display for DatabaseUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseUri::Sqlite(path) => write!(f, "sqlite://{path}"),
+            DatabaseUri::Postgres(url) => f.write_str(url),
+        }
+    }
+}
+
+/// An r2d2 connection manager that opens connections to the backend named by a [`DatabaseUri`].
+#[derive(Debug, Clone)]
+pub struct DbConnectionManager {
+    uri: DatabaseUri,
+}
+
+impl DbConnectionManager {
+    pub fn new(uri: DatabaseUri) -> Self {
+        Self { uri }
+    }
+
+    pub fn uri(&self) -> &DatabaseUri {
+        &self.uri
+    }
+}
+
+impl r2d2::ManageConnection for DbConnectionManager {
+    type Connection = DbConnection;
+    type Error = r2d2::Error;
+
+    fn connect(&self) -> Result<DbConnection, r2d2::Error> {
+        match &self.uri {
+            DatabaseUri::Sqlite(path) => {
+                let mut conn =
+                    SqliteConnection::establish(path).map_err(r2d2::Error::ConnectionError)?;
+                // SQLite only enforces foreign keys when asked to, and only per connection. The
+                // schema relies on them, so they are not left to the configurable pragmas
+                conn.batch_execute("PRAGMA foreign_keys = ON;")
+                    .map_err(r2d2::Error::QueryError)?;
+                Ok(DbConnection::Sqlite(conn))
+            }
+            DatabaseUri::Postgres(url) => PgConnection::establish(url)
+                .map(DbConnection::Postgres)
+                .map_err(r2d2::Error::ConnectionError),
+        }
+    }
+
+    fn is_valid(&self, conn: &mut DbConnection) -> Result<(), r2d2::Error> {
+        conn.ping().map(|_| ()).map_err(r2d2::Error::QueryError)
+    }
+
+    fn has_broken(&self, conn: &mut DbConnection) -> bool {
+        conn.is_broken()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+/// Pragmas set on every SQLite connection the pool opens. PostgreSQL connections are left alone.
+///
+/// The defaults suit a server with concurrent writers: WAL lets readers carry on while a write is
+/// in progress, and the busy timeout makes a writer wait for the lock rather than fail straight
+/// away with `database is locked`.
+///
+/// ```ignore
+/// let pool = Pool::builder()
+///     .connection_customizer(Box::new(SqlitePragmas::default()))
+///     .build(DbConnectionManager::new(uri))?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqlitePragmas {
+    pub journal_mode: JournalMode,
+    pub busy_timeout: Duration,
+    pub synchronous: Synchronous,
+}
+
+impl Default for SqlitePragmas {
+    fn default() -> Self {
+        Self {
+            journal_mode: JournalMode::Wal,
+            busy_timeout: Duration::from_secs(5),
+            // safe in WAL mode, a power loss can only lose the last few commits
+            synchronous: Synchronous::Normal,
+        }
+    }
+}
+
+impl SqlitePragmas {
+    fn to_sql(&self) -> String {
+        // busy_timeout goes first so that switching the journal mode waits for other connections
+        format!(
+            "PRAGMA busy_timeout = {}; PRAGMA journal_mode = {}; PRAGMA synchronous = {};",
+            self.busy_timeout.as_millis(),
+            self.journal_mode.as_sql(),
+            self.synchronous.as_sql(),
+        )
+    }
+}
+
+impl r2d2::CustomizeConnection<DbConnection, r2d2::Error> for SqlitePragmas {
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), r2d2::Error> {
+        match conn {
+            DbConnection::Sqlite(conn) => conn
+                .batch_execute(&self.to_sql())
+                .map_err(r2d2::Error::QueryError),
+            DbConnection::Postgres(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use diesel::sql_types::{BigInt, Text};
+    use diesel::{QueryableByName, RunQueryDsl};
+
+    #[derive(QueryableByName)]
+    struct Pragmas {
+        #[diesel(sql_type = Text)]
+        journal_mode: String,
+        #[diesel(sql_type = BigInt)]
+        timeout: i64,
+        #[diesel(sql_type = BigInt)]
+        synchronous: i64,
+        #[diesel(sql_type = BigInt)]
+        foreign_keys: i64,
+    }
+
+    #[derive(QueryableByName)]
+    struct ForeignKeys {
+        #[diesel(sql_type = BigInt)]
+        foreign_keys: i64,
+    }
+
+    #[test]
+    fn test_pragmas_are_set_on_every_connection() {
+        let path = std::env::temp_dir().join(format!("pragmas-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pragmas = SqlitePragmas {
+            busy_timeout: Duration::from_millis(1234),
+            ..SqlitePragmas::default()
+        };
+        let pool = r2d2::Pool::builder()
+            .max_size(2)
+            .connection_customizer(Box::new(pragmas))
+            .build(DbConnectionManager::new(
+                path.to_str().unwrap().parse().unwrap(),
+            ))
+            .unwrap();
+
+        let connections = [pool.get().unwrap(), pool.get().unwrap()];
+        for mut conn in connections {
+            let DbConnection::Sqlite(conn) = &mut *conn else {
+                panic!("expected a SQLite connection");
+            };
+            let pragmas = diesel::sql_query(
+                "SELECT journal_mode, timeout, synchronous, foreign_keys \
+                 FROM pragma_journal_mode, pragma_busy_timeout, pragma_synchronous, \
+                 pragma_foreign_keys",
+            )
+            .get_result::<Pragmas>(conn)
+            .unwrap();
+            assert_eq!(pragmas.journal_mode, "wal");
+            assert_eq!(pragmas.timeout, 1234);
+            // NORMAL
+            assert_eq!(pragmas.synchronous, 1);
+            assert_eq!(pragmas.foreign_keys, 1);
+        }
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+
+    #[test]
+    fn test_foreign_keys_are_always_enforced() {
+        // no customizer, so nothing but the connection manager sets any pragmas
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .build(DbConnectionManager::new(":memory:".parse().unwrap()))
+            .unwrap();
+        let mut conn = pool.get().unwrap();
+        let DbConnection::Sqlite(conn) = &mut *conn else {
+            panic!("expected a SQLite connection");
+        };
+
+        let foreign_keys = diesel::sql_query("SELECT foreign_keys FROM pragma_foreign_keys")
+            .get_result::<ForeignKeys>(conn)
+            .unwrap();
+        assert_eq!(foreign_keys.foreign_keys, 1);
+    }
+
+    #[test]
+    fn test_uri_scheme_picks_backend() {
+        let parse = |s: &str| s.parse::<DatabaseUri>();
+
+        assert_eq!(
+            parse("local.db").unwrap(),
+            DatabaseUri::Sqlite("local.db".to_owned())
+        );
+        assert_eq!(
+            parse("sqlite:///var/lib/moss/local.db").unwrap(),
+            DatabaseUri::Sqlite("/var/lib/moss/local.db".to_owned())
+        );
+        assert_eq!(
+            parse(":memory:").unwrap(),
+            DatabaseUri::Sqlite(":memory:".to_owned())
+        );
+        assert_eq!(
+            parse("postgres://moss@localhost/moss").unwrap(),
+            DatabaseUri::Postgres("postgres://moss@localhost/moss".to_owned())
+        );
+        assert!(matches!(
+            parse("postgresql://localhost").unwrap(),
+            DatabaseUri::Postgres(_)
+        ));
+        assert!(parse("mysql://localhost/moss").is_err());
+        assert!(parse("").is_err());
+    }
+}
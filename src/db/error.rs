@@ -0,0 +1,93 @@
+use std::fmt;
+
+use diesel::result::{DatabaseErrorKind, Error};
+
+/// A failed database statement, with constraint violations told apart from other failures so
+/// callers can turn them into a meaningful response.
+///
+/// `DatabaseImpl` returns these inside its `anyhow::Error`s, so check for one with
+/// `err.downcast_ref::<DbError>()`.
+#[derive(Debug)]
+pub enum DbError {
+    /// The row would duplicate another in a `UNIQUE` column or set of columns.
+    UniqueViolation(String),
+    /// The row refers to a row that doesn't exist, or is still referred to by another row.
+    ForeignKeyViolation(String),
+    /// A required column was left empty.
+    NotNullViolation(String),
+    /// The row failed a `CHECK` constraint.
+    CheckViolation(String),
+    Other(Error),
+}
+
+impl DbError {
+    /// Returns true if the statement was rejected by a constraint rather than failing to run.
+    pub fn is_constraint_violation(&self) -> bool {
+        !matches!(self, DbError::Other(_))
+    }
+
+    /// Returns true if the statement failed because of another transaction, so running the whole
+    /// transaction again can succeed.
+    pub fn is_busy(&self) -> bool {
+        match self {
+            DbError::Other(e) => is_busy(e),
+            _ => false,
+        }
+    }
+}
+
+/// Returns true if SQLite reported the database locked, or PostgreSQL aborted the transaction
+/// over a deadlock or a serialization failure.
+pub(crate) fn is_busy(e: &Error) -> bool {
+    match e {
+        Error::DatabaseError(DatabaseErrorKind::SerializationFailure, _) => true,
+        Error::DatabaseError(_, info) => {
+            info.message().contains("database is locked")
+                || info.message().contains("deadlock detected")
+        }
+        _ => false,
+    }
+}
+
+impl From<Error> for DbError {
+    fn from(val: Error) -> Self {
+        match val {
+            Error::DatabaseError(kind, info) => {
+                let message = info.message().to_owned();
+                match kind {
+                    DatabaseErrorKind::UniqueViolation => DbError::UniqueViolation(message),
+                    DatabaseErrorKind::ForeignKeyViolation => DbError::ForeignKeyViolation(message),
+                    DatabaseErrorKind::NotNullViolation => DbError::NotNullViolation(message),
+                    DatabaseErrorKind::CheckViolation => DbError::CheckViolation(message),
+                    kind => DbError::Other(Error::DatabaseError(kind, info)),
+                }
+            }
+            e => DbError::Other(e),
+        }
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::UniqueViolation(message) => write!(f, "Unique constraint violated: {message}"),
+            DbError::ForeignKeyViolation(message) => {
+                write!(f, "Foreign key constraint violated: {message}")
+            }
+            DbError::NotNullViolation(message) => {
+                write!(f, "Not null constraint violated: {message}")
+            }
+            DbError::CheckViolation(message) => write!(f, "Check constraint violated: {message}"),
+            DbError::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Other(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use std::marker::PhantomData;
+
+use diesel::dsl::sql;
+use diesel::expression::BoxableExpression;
+use diesel::sql_types::{BigInt, Bool, Double, Text};
+use diesel::{BoolExpressionMethods, Column};
+
+use crate::db::connection::DbBackend;
+use crate::money::Amount;
+
+/// A boolean SQL expression that can be used to filter rows of `T`.
+pub type BoxedCondition<T> = Box<dyn BoxableExpression<T, DbBackend, SqlType = Bool>>;
+
+/// A value compared against a column. Values are always sent to the database as bind parameters,
+/// never spliced into the SQL text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterValue {
+    Text(String),
+    Integer(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+impl From<&str> for FilterValue {
+    fn from(val: &str) -> Self {
+        FilterValue::Text(val.to_owned())
+    }
+}
+
+impl From<String> for FilterValue {
+    fn from(val: String) -> Self {
+        FilterValue::Text(val)
+    }
+}
+
+impl From<&String> for FilterValue {
+    fn from(val: &String) -> Self {
+        FilterValue::Text(val.clone())
+    }
+}
+
+impl From<i32> for FilterValue {
+    fn from(val: i32) -> Self {
+        FilterValue::Integer(val.into())
+    }
+}
+
+impl From<i64> for FilterValue {
+    fn from(val: i64) -> Self {
+        FilterValue::Integer(val)
+    }
+}
+
+impl From<f64> for FilterValue {
+    fn from(val: f64) -> Self {
+        FilterValue::Double(val)
+    }
+}
+
+impl From<bool> for FilterValue {
+    fn from(val: bool) -> Self {
+        FilterValue::Bool(val)
+    }
+}
+
+impl From<Amount> for FilterValue {
+    fn from(val: Amount) -> Self {
+        FilterValue::Integer(val.minor_units())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "=",
+            Comparison::Ne => "<>",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FilterKind {
+    Compare(Comparison, FilterValue),
+    In(Vec<FilterValue>),
+    Like(String),
+}
+
+/// A condition on a single column of table `T`.
+///
+/// Filters are built from the diesel column types generated by `table!`, so a column that doesn't
+/// belong to `T` is a compile error rather than a runtime SQL error.
+///
+/// ```ignore
+/// db_manager.query_rows(users::table, vec![Filter::eq(users::email, "a@b.com")])
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter<T> {
+    column: &'static str,
+    kind: FilterKind,
+    _table: PhantomData<T>,
+}
+
+impl<T> Filter<T> {
+    fn new<C: Column<Table = T>>(_column: C, kind: FilterKind) -> Self {
+        Self {
+            column: C::NAME,
+            kind,
+            _table: PhantomData,
+        }
+    }
+
+    pub fn compare<C: Column<Table = T>>(
+        column: C,
+        comparison: Comparison,
+        value: impl Into<FilterValue>,
+    ) -> Self {
+        Self::new(column, FilterKind::Compare(comparison, value.into()))
+    }
+
+    pub fn eq<C: Column<Table = T>>(column: C, value: impl Into<FilterValue>) -> Self {
+        Self::compare(column, Comparison::Eq, value)
+    }
+
+    pub fn ne<C: Column<Table = T>>(column: C, value: impl Into<FilterValue>) -> Self {
+        Self::compare(column, Comparison::Ne, value)
+    }
+
+    pub fn lt<C: Column<Table = T>>(column: C, value: impl Into<FilterValue>) -> Self {
+        Self::compare(column, Comparison::Lt, value)
+    }
+
+    pub fn le<C: Column<Table = T>>(column: C, value: impl Into<FilterValue>) -> Self {
+        Self::compare(column, Comparison::Le, value)
+    }
+
+    pub fn gt<C: Column<Table = T>>(column: C, value: impl Into<FilterValue>) -> Self {
+        Self::compare(column, Comparison::Gt, value)
+    }
+
+    pub fn ge<C: Column<Table = T>>(column: C, value: impl Into<FilterValue>) -> Self {
+        Self::compare(column, Comparison::Ge, value)
+    }
+
+    /// Matches rows where the column equals any of `values`. An empty list matches nothing.
+    pub fn is_in<C, V>(column: C, values: impl IntoIterator<Item = V>) -> Self
+    where
+        C: Column<Table = T>,
+        V: Into<FilterValue>,
+    {
+        Self::new(
+            column,
+            FilterKind::In(values.into_iter().map(Into::into).collect()),
+        )
+    }
+
+    /// Matches rows where the column matches a SQL `LIKE` pattern, e.g. `"AA%"`.
+    pub fn like<C: Column<Table = T>>(column: C, pattern: impl Into<String>) -> Self {
+        Self::new(column, FilterKind::Like(pattern.into()))
+    }
+}
+
+impl<T> Filter<T> {
+    /// The column and condition as text, without the table. Two filters describe the same way
+    /// exactly when they match the same rows.
+    pub(crate) fn describe(&self) -> String {
+        format!("{} {:?}", self.column, self.kind)
+    }
+}
+
+impl<T: 'static> Filter<T> {
+    fn bind(sql_text: String, value: FilterValue) -> BoxedCondition<T> {
+        match value {
+            FilterValue::Text(v) => Box::new(sql::<Bool>(&sql_text).bind::<Text, _>(v)),
+            FilterValue::Integer(v) => Box::new(sql::<Bool>(&sql_text).bind::<BigInt, _>(v)),
+            FilterValue::Double(v) => Box::new(sql::<Bool>(&sql_text).bind::<Double, _>(v)),
+            FilterValue::Bool(v) => Box::new(sql::<Bool>(&sql_text).bind::<Bool, _>(v)),
+        }
+    }
+
+    pub fn into_condition(self) -> BoxedCondition<T> {
+        let column = format!("\"{}\"", self.column);
+        match self.kind {
+            FilterKind::Compare(comparison, value) => {
+                Self::bind(format!("{column} {} ", comparison.as_sql()), value)
+            }
+            FilterKind::In(values) => values
+                .into_iter()
+                .map(|value| Self::bind(format!("{column} = "), value))
+                .reduce(|acc, condition| Box::new(acc.or(condition)))
+                .unwrap_or_else(|| Box::new(sql::<Bool>("1 = 0"))),
+            FilterKind::Like(pattern) => {
+                Self::bind(format!("{column} LIKE "), FilterValue::Text(pattern))
+            }
+        }
+    }
+
+    /// Combines filters with `AND`. No filters at all matches every row.
+    pub fn all(filters: Vec<Filter<T>>) -> BoxedCondition<T> {
+        filters
+            .into_iter()
+            .map(Filter::into_condition)
+            .reduce(|acc, condition| Box::new(acc.and(condition)))
+            .unwrap_or_else(|| Box::new(sql::<Bool>("1 = 1")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::manager::DatabaseImpl;
+    use crate::db::models::stock::{schema::stock, Stock, StockBuilder};
+    use crate::db::test_utils::memory_db_manager;
+
+    async fn stocks_matching(filters: Vec<Filter<stock::table>>) -> Vec<String> {
+        let db_manager = memory_db_manager();
+        for (name, symbol) in [("Apple", "AAPL"), ("Alcoa", "AA"), ("Microsoft", "MSFT")] {
+            let stock = StockBuilder::default()
+                .id(None)
+                .name(name.to_owned())
+                .symbol(symbol.to_owned())
+                .exchange_name("NASDAQ".to_owned())
+                .build()
+                .unwrap();
+            db_manager.insert_row(stock::table, &stock).unwrap();
+        }
+
+        let stocks: Vec<Stock> = db_manager.query_rows(stock::table, filters).await.unwrap();
+        stocks.into_iter().map(|s| s.symbol).collect()
+    }
+
+    #[tokio::test]
+    async fn test_filters() {
+        assert_eq!(stocks_matching(vec![]).await.len(), 3);
+        assert_eq!(
+            stocks_matching(vec![Filter::eq(stock::symbol, "MSFT")]).await,
+            vec!["MSFT"]
+        );
+        assert_eq!(
+            stocks_matching(vec![Filter::like(stock::symbol, "AA%")]).await,
+            vec!["AAPL", "AA"]
+        );
+        assert_eq!(
+            stocks_matching(vec![
+                Filter::is_in(stock::symbol, ["AA", "MSFT"]),
+                Filter::gt(stock::id, 1),
+            ])
+            .await,
+            vec!["AA", "MSFT"]
+        );
+        assert!(
+            stocks_matching(vec![Filter::is_in(stock::symbol, Vec::<&str>::new())])
+                .await
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_values_are_not_interpreted_as_sql() {
+        let injection = "' OR '1' = '1";
+        assert!(stocks_matching(vec![Filter::eq(stock::symbol, injection)])
+            .await
+            .is_empty());
+    }
+}
@@ -1,117 +1,971 @@
 use anyhow::{anyhow, Result};
+use std::cell::RefCell;
+use std::fmt;
 use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
 
-use diesel::dsl::sql;
+use diesel::associations::HasTable;
+use diesel::connection::{AnsiTransactionManager, TransactionManager};
+use diesel::dsl::{sql, IntoBoxed};
 use diesel::expression::SqlLiteral;
-use diesel::query_builder::{InsertStatement, QueryFragment, QueryId};
-use diesel::query_dsl::methods::{FilterDsl, LoadQuery};
-use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::pg::PgConnection;
+use diesel::query_builder::{
+    AsChangeset, AsQuery, DeleteStatement, InsertStatement, IntoUpdateTarget, QueryFragment,
+    QueryId, UpdateStatement,
+};
+use diesel::query_dsl::methods::{BoxedDsl, FilterDsl, LimitDsl, LoadQuery, OffsetDsl, OrderDsl};
+use diesel::r2d2::{Pool, PooledConnection};
+use diesel::result::QueryResult;
 use diesel::sql_types::Bool;
 use diesel::sqlite::SqliteConnection;
-use diesel::{query_dsl::methods::ExecuteDsl, sqlite::Sqlite, Table};
-use diesel::{Insertable, QueryDsl, Queryable, RunQueryDsl};
+use diesel::{query_dsl::methods::ExecuteDsl, Table};
+use diesel::{Column, Connection, Insertable, QueryDsl, Queryable, RunQueryDsl};
+
+use super::connection::{DbBackend, DbConnection, DbConnectionManager};
+use super::error::{is_busy, DbError};
+use super::filter::{BoxedCondition, Filter};
+use super::page::{query_key, Order, Page, PageRequest};
 
 pub trait DatabaseImpl {
-    fn query_rows<'a, T, U>(
+    /// Runs `f` on the connection this handle issues its queries on.
+    fn with_connection<R, E, F>(&self, f: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut DbConnection) -> Result<R, E>,
+        E: From<anyhow::Error>;
+
+    /// Loads every row of `table` matching all of `filters`. An empty list returns every row.
+    fn query_rows<T, U>(
         &self,
         table: T,
-        fields: Vec<(&str, &str)>,
+        filters: Vec<Filter<T>>,
     ) -> impl Future<Output = Result<Vec<U>>>
     where
-        T: Table + QueryDsl + 'static,
-        T::Query: FilterDsl<SqlLiteral<Bool>>,
-        <T::Query as FilterDsl<SqlLiteral<Bool>>>::Output: LoadQuery<'a, SqliteConnection, U>,
-        U: Queryable<T::SqlType, Sqlite> + Send + Sync + 'static;
+        T: Table + QueryDsl + Send + 'static,
+        T::Query: FilterDsl<BoxedCondition<T>>,
+        <T::Query as FilterDsl<BoxedCondition<T>>>::Output: LoadQuery<'static, DbConnection, U>,
+        U: Queryable<T::SqlType, DbBackend> + Send + Sync + 'static,
+    {
+        async move { self.with_connection(|conn| load_rows(conn, table, filters)) }
+    }
+
+    /// Loads one page of the rows of `table` matching all of `filters`, sorted by
+    /// `page.order_by`. The returned page carries a token for the next one, which only a query
+    /// with the same filters and sort order accepts. Other tokens fail with an
+    /// [`InvalidPageToken`](super::page::InvalidPageToken).
+    fn query_page<T, U>(
+        &self,
+        table: T,
+        filters: Vec<Filter<T>>,
+        page: PageRequest<T>,
+    ) -> impl Future<Output = Result<Page<U>>>
+    where
+        T: Table + BoxedDsl<'static, DbBackend> + Send + 'static,
+        T::PrimaryKey: Column,
+        IntoBoxed<'static, T, DbBackend>: FilterDsl<BoxedCondition<T>, Output = IntoBoxed<'static, T, DbBackend>>
+            + OrderDsl<SqlLiteral<Bool>, Output = IntoBoxed<'static, T, DbBackend>>
+            + LimitDsl<Output = IntoBoxed<'static, T, DbBackend>>
+            + OffsetDsl<Output = IntoBoxed<'static, T, DbBackend>>
+            + LoadQuery<'static, DbConnection, U>,
+        U: Send + 'static,
+    {
+        async move { self.with_connection(|conn| load_page(conn, table, filters, page)) }
+    }
 
     fn insert_row<T, U>(&self, table: T, obj: &U) -> Result<usize>
     where
         T: Table + Send + 'static,
         U: Insertable<T> + Clone + Send,
-        <U as Insertable<T>>::Values: QueryFragment<Sqlite> + QueryId + Send,
-        InsertStatement<T, <U as Insertable<T>>::Values>: ExecuteDsl<SqliteConnection>;
+        <U as Insertable<T>>::Values: QueryFragment<DbBackend> + QueryId + Send,
+        InsertStatement<T, <U as Insertable<T>>::Values>: ExecuteDsl<DbConnection>,
+    {
+        self.with_connection(|conn| {
+            diesel::insert_into(table)
+                .values(obj.clone())
+                .execute(conn)
+                .map_err(db_error("Insert row"))
+        })
+    }
 
+    /// Inserts every row in `objs` with multi-row `INSERT` statements of up to
+    /// [`ROWS_PER_INSERT`] rows each, all inside one transaction.
+    ///
+    /// # Returns
+    /// The number of rows inserted, which is always `objs.len()`. If any row can't be inserted
+    /// none of them are, and the error is an [`InsertRowsError`] naming every row that failed.
     fn insert_rows<T, U>(&self, table: T, objs: Vec<&U>) -> Result<usize>
     where
         T: Table + Send + Clone + 'static,
         U: Insertable<T> + Clone + Send,
-        <U as Insertable<T>>::Values: QueryFragment<Sqlite> + QueryId + Send,
-        InsertStatement<T, <U as Insertable<T>>::Values>: ExecuteDsl<SqliteConnection>;
+        InsertStatement<T, <U as Insertable<T>>::Values>: ExecuteDsl<DbConnection>,
+        Vec<U>: Insertable<T>,
+        InsertStatement<T, <Vec<U> as Insertable<T>>::Values>:
+            ExecuteDsl<SqliteConnection> + ExecuteDsl<PgConnection>,
+    {
+        if objs.is_empty() {
+            return Ok(0);
+        }
+
+        self.with_connection(|conn| {
+            conn.transaction(|conn| {
+                let mut inserted = 0;
+                let mut failed = Vec::new();
+                for (chunk_index, chunk) in objs.chunks(ROWS_PER_INSERT).enumerate() {
+                    let rows = chunk.iter().map(|o| (*o).clone()).collect::<Vec<U>>();
+                    // every attempt gets its own savepoint, since PostgreSQL refuses to run
+                    // anything else in a transaction after a statement in it has failed
+                    if failed.is_empty() {
+                        if let Ok(count) = insert_batch(conn, table.clone(), rows) {
+                            inserted += count;
+                            continue;
+                        }
+                    }
+
+                    // Something in this chunk was rejected. Insert the rows one at a time to find
+                    // out which, everything gets rolled back afterwards anyway.
+                    for (offset, obj) in chunk.iter().enumerate() {
+                        if let Err(e) = conn.transaction(|conn| {
+                            diesel::insert_into(table.clone())
+                                .values((*obj).clone())
+                                .execute(conn)
+                        }) {
+                            failed.push((chunk_index * ROWS_PER_INSERT + offset, DbError::from(e)));
+                        }
+                    }
+                }
+
+                if failed.is_empty() {
+                    Ok(inserted)
+                } else {
+                    Err(InsertRowsError {
+                        total: objs.len(),
+                        failed,
+                    }
+                    .into())
+                }
+            })
+        })
+    }
+
+    /// Applies `changes` to every row of `table` matching all of `filters`. An empty list is
+    /// rejected rather than updating the whole table.
+    ///
+    /// # Returns
+    /// The number of rows that were updated.
+    fn update_rows<T, V>(&self, table: T, filters: Vec<Filter<T>>, changes: V) -> Result<usize>
+    where
+        T: Table + IntoUpdateTarget + HasTable<Table = T> + 'static,
+        V: AsChangeset<Target = T>,
+        UpdateStatement<T, T::WhereClause, V::Changeset>: AsQuery + FilterDsl<BoxedCondition<T>>,
+        <UpdateStatement<T, T::WhereClause, V::Changeset> as FilterDsl<BoxedCondition<T>>>::Output:
+            ExecuteDsl<DbConnection>,
+    {
+        if filters.is_empty() {
+            return Err(anyhow!(
+                "Update row error: refusing to update every row without a filter"
+            ));
+        }
+
+        self.with_connection(|conn| {
+            let query = FilterDsl::filter(diesel::update(table).set(changes), Filter::all(filters));
+            ExecuteDsl::execute(query, conn).map_err(db_error("Update row"))
+        })
+    }
+
+    /// Deletes every row of `table` matching all of `filters`. An empty list is rejected rather
+    /// than deleting the whole table.
+    ///
+    /// # Returns
+    /// The number of rows that were deleted.
+    fn delete_rows<T>(&self, table: T, filters: Vec<Filter<T>>) -> Result<usize>
+    where
+        T: Table + IntoUpdateTarget + HasTable<Table = T> + 'static,
+        DeleteStatement<T, T::WhereClause>: FilterDsl<BoxedCondition<T>>,
+        <DeleteStatement<T, T::WhereClause> as FilterDsl<BoxedCondition<T>>>::Output:
+            ExecuteDsl<DbConnection>,
+    {
+        if filters.is_empty() {
+            return Err(anyhow!(
+                "Delete row error: refusing to delete every row without a filter"
+            ));
+        }
+
+        self.with_connection(|conn| {
+            let query = FilterDsl::filter(diesel::delete(table), Filter::all(filters));
+            ExecuteDsl::execute(query, conn).map_err(db_error("Delete row"))
+        })
+    }
 }
 
+/// The most rows [`DatabaseImpl::insert_rows`] puts in one statement. SQLite accepts at most
+/// 32766 bind parameters per statement and PostgreSQL 65535, so this keeps tables of up to 32
+/// columns under both limits.
+pub const ROWS_PER_INSERT: usize = 1000;
+
+/// Returned by [`DatabaseImpl::insert_rows`] when some rows could not be inserted.
 #[derive(Debug)]
+pub struct InsertRowsError {
+    /// How many rows were passed in.
+    pub total: usize,
+    /// The index of every row that was rejected and why.
+    pub failed: Vec<(usize, DbError)>,
+}
+
+impl fmt::Display for InsertRowsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} rows could not be inserted",
+            self.failed.len(),
+            self.total
+        )?;
+        for (index, reason) in &self.failed {
+            write!(f, "; row {index}: {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InsertRowsError {}
+
+/// Wraps a failed statement in a [`DbError`], noting which operation it was.
+pub(crate) fn db_error(
+    operation: &'static str,
+) -> impl FnOnce(diesel::result::Error) -> anyhow::Error {
+    move |e| anyhow::Error::new(DbError::from(e)).context(format!("{operation} error"))
+}
+
+/// Inserts `rows` with one statement in a savepoint of its own.
+///
+/// `DbConnection` can't build multi-row inserts itself, so this hands them to whichever
+/// connection it wraps.
+fn insert_batch<T, U>(conn: &mut DbConnection, table: T, rows: Vec<U>) -> QueryResult<usize>
+where
+    T: Table,
+    Vec<U>: Insertable<T>,
+    InsertStatement<T, <Vec<U> as Insertable<T>>::Values>:
+        ExecuteDsl<SqliteConnection> + ExecuteDsl<PgConnection>,
+{
+    let query = diesel::insert_into(table).values(rows);
+    match conn {
+        DbConnection::Sqlite(conn) => conn.transaction(|conn| query.execute(conn)),
+        DbConnection::Postgres(conn) => conn.transaction(|conn| query.execute(conn)),
+    }
+}
+
+fn load_rows<T, U>(conn: &mut DbConnection, table: T, filters: Vec<Filter<T>>) -> Result<Vec<U>>
+where
+    T: Table + QueryDsl + 'static,
+    T::Query: FilterDsl<BoxedCondition<T>>,
+    <T::Query as FilterDsl<BoxedCondition<T>>>::Output: LoadQuery<'static, DbConnection, U>,
+{
+    table
+        .filter(Filter::all(filters))
+        .load::<U>(conn)
+        .map_err(db_error("Query row"))
+}
+
+fn load_page<T, U>(
+    conn: &mut DbConnection,
+    table: T,
+    filters: Vec<Filter<T>>,
+    page: PageRequest<T>,
+) -> Result<Page<U>>
+where
+    T: Table + BoxedDsl<'static, DbBackend> + Send + 'static,
+    T::PrimaryKey: Column,
+    IntoBoxed<'static, T, DbBackend>: FilterDsl<BoxedCondition<T>, Output = IntoBoxed<'static, T, DbBackend>>
+        + OrderDsl<SqlLiteral<Bool>, Output = IntoBoxed<'static, T, DbBackend>>
+        + LimitDsl<Output = IntoBoxed<'static, T, DbBackend>>
+        + OffsetDsl<Output = IntoBoxed<'static, T, DbBackend>>
+        + LoadQuery<'static, DbConnection, U>,
+    U: Send + 'static,
+{
+    let query = query_key(&page.order_by, &filters);
+    let limit = page.limit();
+    let offset = page.offset(&query)?;
+    let order_by = Order::to_sql(&page.order_by, <T::PrimaryKey as Column>::NAME);
+
+    // one row more than was asked for, to find out whether there is a next page
+    let rows = table
+        .into_boxed()
+        .filter(Filter::all(filters))
+        .order(sql::<Bool>(&order_by))
+        .limit(limit as i64 + 1)
+        .offset(offset)
+        .load::<U>(conn)
+        .map_err(db_error("Query page"))?;
+
+    Ok(Page::new(rows, offset, limit, &query))
+}
+
+/// How long to wait for a pooled connection before giving up, unless configured otherwise.
+pub const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many times a transaction is retried when the database reports it as busy.
+const BUSY_RETRIES: u32 = 5;
+
+/// How long to wait before the first retry of a busy transaction. Doubles on every retry.
+const BUSY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Hands out pooled database connections.
+///
+/// Diesel queries block, so async code should do its database work through [`DBManager::run`],
+/// which moves it onto tokio's blocking thread pool. Clones share the same pool and limits.
+#[derive(Debug, Clone)]
 pub struct DBManager {
-    pub connection_pool: Pool<ConnectionManager<SqliteConnection>>,
+    pub connection_pool: Pool<DbConnectionManager>,
+    // bounds how many blocking tasks do database work at once
+    permits: Arc<Semaphore>,
+    connection_timeout: Duration,
 }
 
 impl DBManager {
-    pub fn new(connection_pool: Pool<ConnectionManager<SqliteConnection>>) -> Self {
-        Self { connection_pool }
+    /// Creates a manager that runs as many blocking tasks at once as the pool has connections.
+    pub fn new(connection_pool: Pool<DbConnectionManager>) -> Self {
+        let max_blocking = connection_pool.max_size() as usize;
+        Self::with_limits(connection_pool, max_blocking, DEFAULT_CONNECTION_TIMEOUT)
+    }
+
+    /// Creates a manager that runs at most `max_blocking` blocking tasks at once and waits up to
+    /// `connection_timeout` for a pooled connection.
+    pub fn with_limits(
+        connection_pool: Pool<DbConnectionManager>,
+        max_blocking: usize,
+        connection_timeout: Duration,
+    ) -> Self {
+        Self {
+            connection_pool,
+            permits: Arc::new(Semaphore::new(max_blocking.max(1))),
+            connection_timeout,
+        }
+    }
+
+    /// Runs blocking database work on tokio's blocking thread pool, waiting for a free slot if
+    /// the concurrency limit has been reached.
+    ///
+    /// ```ignore
+    /// db_manager.run(move |db| db.insert_row(users::table, &user)).await?;
+    /// ```
+    pub async fn run<R, E, F>(&self, f: F) -> Result<R, E>
+    where
+        F: FnOnce(&DBManager) -> Result<R, E> + Send + 'static,
+        R: Send + 'static,
+        E: From<anyhow::Error> + Send + 'static,
+    {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| anyhow!("Database task queue closed: {e}"))?;
+        let db = self.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f(&db)
+        })
+        .await
+        .map_err(|e| anyhow!("Database task failed: {e}"))?
+    }
+
+    /// Waits up to the configured timeout for a pooled connection.
+    pub fn connection(&self) -> Result<PooledConnection<DbConnectionManager>> {
+        self.connection_pool
+            .get_timeout(self.connection_timeout)
+            .map_err(|e| {
+                anyhow!(
+                    "No database connection became available within {:?}: {e}",
+                    self.connection_timeout
+                )
+            })
+    }
+
+    /// Runs `f` inside a single transaction, committing if it returns `Ok` and rolling back
+    /// every write it made if it returns `Err`.
+    ///
+    /// On SQLite the transaction takes the write lock up front. PostgreSQL transactions run at
+    /// READ COMMITTED, so `f` has to lock rows it reads and then writes back with
+    /// `SELECT ... FOR UPDATE`. If the database reports itself busy when beginning or committing,
+    /// or `f` fails with a [`DbError`] that [is busy](DbError::is_busy), such as a PostgreSQL
+    /// deadlock, the transaction is rolled back and `f` is run again from the start, so `f` must
+    /// not have side effects outside the database.
+    ///
+    /// ```ignore
+    /// db_manager.transaction(|tx| {
+    ///     tx.update_rows(wallets::table, filters, wallets::balance.eq(balance))?;
+    ///     tx.insert_row(trades::table, &trade)
+    /// })
+    /// ```
+    pub fn transaction<R, E, F>(&self, mut f: F) -> Result<R, E>
+    where
+        F: FnMut(&Transaction<'_>) -> Result<R, E>,
+        E: TransactionError,
+    {
+        let mut conn = self.connection()?;
+
+        let mut backoff = BUSY_BACKOFF;
+        for _ in 0..BUSY_RETRIES {
+            match Transaction::run(&mut conn, &mut f)? {
+                Attempt::Done(result) => return Ok(result),
+                Attempt::Busy(_) => {
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                }
+            }
+        }
+
+        match Transaction::run(&mut conn, &mut f)? {
+            Attempt::Done(result) => Ok(result),
+            Attempt::Busy(e) => {
+                Err(anyhow!("Database stayed busy after {BUSY_RETRIES} retries: {e}").into())
+            }
+        }
     }
 }
 
 impl DatabaseImpl for DBManager {
-    async fn query_rows<'a, T, U>(&self, table: T, fields: Vec<(&str, &str)>) -> Result<Vec<U>>
+    fn with_connection<R, E, F>(&self, f: F) -> Result<R, E>
     where
-        T: Table + QueryDsl + 'static,
-        T::Query: FilterDsl<SqlLiteral<Bool>>,
-        <T::Query as FilterDsl<SqlLiteral<Bool>>>::Output: LoadQuery<'a, SqliteConnection, U>,
-        U: Queryable<T::SqlType, Sqlite> + Send + Sync + 'static,
+        F: FnOnce(&mut DbConnection) -> Result<R, E>,
+        E: From<anyhow::Error>,
     {
-        let Some(mut conn) = self.connection_pool.try_get() else {
-            return Err(anyhow!("No available connection in connection pool!"));
-        };
+        f(&mut *self.connection()?)
+    }
+
+    async fn query_rows<T, U>(&self, table: T, filters: Vec<Filter<T>>) -> Result<Vec<U>>
+    where
+        T: Table + QueryDsl + Send + 'static,
+        T::Query: FilterDsl<BoxedCondition<T>>,
+        <T::Query as FilterDsl<BoxedCondition<T>>>::Output: LoadQuery<'static, DbConnection, U>,
+        U: Queryable<T::SqlType, DbBackend> + Send + Sync + 'static,
+    {
+        self.run(move |db| db.with_connection(|conn| load_rows(conn, table, filters)))
+            .await
+    }
+
+    async fn query_page<T, U>(
+        &self,
+        table: T,
+        filters: Vec<Filter<T>>,
+        page: PageRequest<T>,
+    ) -> Result<Page<U>>
+    where
+        T: Table + BoxedDsl<'static, DbBackend> + Send + 'static,
+        T::PrimaryKey: Column,
+        IntoBoxed<'static, T, DbBackend>: FilterDsl<BoxedCondition<T>, Output = IntoBoxed<'static, T, DbBackend>>
+            + OrderDsl<SqlLiteral<Bool>, Output = IntoBoxed<'static, T, DbBackend>>
+            + LimitDsl<Output = IntoBoxed<'static, T, DbBackend>>
+            + OffsetDsl<Output = IntoBoxed<'static, T, DbBackend>>
+            + LoadQuery<'static, DbConnection, U>,
+        U: Send + 'static,
+    {
+        self.run(move |db| db.with_connection(|conn| load_page(conn, table, filters, page)))
+            .await
+    }
+}
 
-        let filter_string = fields
-            .iter()
-            .map(|(col, val)| format!(" {} = '{}' ", col, val))
-            .collect::<Vec<_>>()
-            .join("AND");
+/// A connection with an open transaction, handed to the closure passed to
+/// [`DBManager::transaction`]. Every `DatabaseImpl` operation run through it is part of the
+/// transaction.
+pub struct Transaction<'c> {
+    conn: RefCell<&'c mut DbConnection>,
+}
 
-        // Create the SQL filter expression
-        let query = table.filter(sql::<Bool>(&filter_string));
+type DbTransactionManager = <DbConnection as Connection>::TransactionManager;
 
-        let results = query // bind the first parameter as a Text type
-            .load::<U>(&mut *conn)
-            .map_err(|e| anyhow!("Query row error: {e:#?}"))?;
+/// An error the closure passed to [`DBManager::transaction`] can fail with.
+pub trait TransactionError: From<anyhow::Error> {
+    /// The failed statement this error came from, if any.
+    fn db_error(&self) -> Option<&DbError>;
+}
 
-        Ok(results)
+impl TransactionError for anyhow::Error {
+    fn db_error(&self) -> Option<&DbError> {
+        self.downcast_ref()
     }
+}
 
-    fn insert_row<T, U>(&self, table: T, obj: &U) -> Result<usize>
+enum Attempt<R> {
+    Done(R),
+    Busy(String),
+}
+
+impl Transaction<'_> {
+    fn run<R, E, F>(conn: &mut DbConnection, f: &mut F) -> Result<Attempt<R>, E>
     where
-        T: Table + Send + 'static,
-        U: Insertable<T> + Clone + Send,
-        <U as Insertable<T>>::Values: QueryFragment<Sqlite> + QueryId + Send,
-        InsertStatement<T, <U as Insertable<T>>::Values>: ExecuteDsl<SqliteConnection>,
+        F: FnMut(&Transaction<'_>) -> Result<R, E>,
+        E: TransactionError,
     {
-        let Some(mut conn) = self.connection_pool.try_get() else {
-            return Err(anyhow!("No available connection in connection pool!"));
+        // SQLite takes the write lock up front so two writers can't deadlock upgrading their
+        // read locks. PostgreSQL locks per row, callers lock what they read with FOR UPDATE.
+        let begin = match &mut *conn {
+            DbConnection::Sqlite(conn) => {
+                AnsiTransactionManager::begin_transaction_sql(conn, "BEGIN IMMEDIATE")
+            }
+            DbConnection::Postgres(conn) => AnsiTransactionManager::begin_transaction(conn),
         };
+        match begin {
+            Ok(()) => {}
+            Err(e) if is_busy(&e) => return Ok(Attempt::Busy(e.to_string())),
+            Err(e) => return Err(anyhow!("Begin transaction error: {e:#?}").into()),
+        }
 
-        diesel::insert_into(table)
-            .values(obj.clone())
-            .execute(&mut *conn) // Convert to &mut SqliteConnection
-            .map_err(|e| anyhow!("Insert row error: {e:#?}"))
+        let result = f(&Transaction {
+            conn: RefCell::new(&mut *conn),
+        });
+
+        match result {
+            // a failed commit has already been rolled back
+            Ok(value) => match DbTransactionManager::commit_transaction(conn) {
+                Ok(()) => Ok(Attempt::Done(value)),
+                Err(e) if is_busy(&e) => Ok(Attempt::Busy(e.to_string())),
+                Err(e) => Err(anyhow!("Commit transaction error: {e:#?}").into()),
+            },
+            Err(e) => {
+                DbTransactionManager::rollback_transaction(conn)
+                    .map_err(|e| anyhow!("Rollback transaction error: {e:#?}"))?;
+                match e.db_error() {
+                    Some(db_error) if db_error.is_busy() => Ok(Attempt::Busy(db_error.to_string())),
+                    _ => Err(e),
+                }
+            }
+        }
     }
+}
 
-    fn insert_rows<T, U>(&self, table: T, objs: Vec<&U>) -> Result<usize>
+impl DatabaseImpl for Transaction<'_> {
+    fn with_connection<R, E, F>(&self, f: F) -> Result<R, E>
     where
-        T: Table + Send + Clone + 'static,
-        U: Insertable<T> + Clone + Send,
-        <U as Insertable<T>>::Values: QueryFragment<Sqlite> + QueryId + Send,
-        InsertStatement<T, <U as Insertable<T>>::Values>: ExecuteDsl<SqliteConnection>,
+        F: FnOnce(&mut DbConnection) -> Result<R, E>,
+        E: From<anyhow::Error>,
     {
-        // A janky way of inserting bulk rows iteratively calling insert_row
-        // Ideally this would be it's own function which can do a bulk insert but rust
-        // is hard.
-        // TODO: when someone with skill can, make this bulk insert objs with one query
-        Ok(objs
-            .iter()
-            .map(|o| {
-                let table = table.clone();
-                self.insert_row(table, *o).ok()
+        let Ok(mut conn) = self.conn.try_borrow_mut() else {
+            return Err(anyhow!("Transaction connection is already in use!").into());
+        };
+
+        f(&mut conn)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::connection::SqlitePragmas;
+    use crate::db::migrations::run_migrations;
+    use crate::db::models::stock::{schema::stock, Stock, StockBuilder};
+    use crate::db::page::InvalidPageToken;
+    use crate::db::test_utils::{memory_db_manager, postgres_db_manager};
+    use diesel::connection::SimpleConnection;
+    use diesel::ExpressionMethods;
+
+    fn stock(symbol: &str) -> Stock {
+        StockBuilder::default()
+            .id(None)
+            .name(format!("{symbol} Inc"))
+            .symbol(symbol.to_owned())
+            .exchange_name("NASDAQ".to_owned())
+            .build()
+            .unwrap()
+    }
+
+    async fn symbols(db: &impl DatabaseImpl) -> Vec<String> {
+        let stocks: Vec<Stock> = db.query_rows(stock::table, vec![]).await.unwrap();
+        stocks.into_iter().map(|s| s.symbol).collect()
+    }
+
+    #[tokio::test]
+    async fn test_update_and_delete_rows() {
+        let db_manager = memory_db_manager();
+        for symbol in ["AAPL", "MSFT"] {
+            db_manager.insert_row(stock::table, &stock(symbol)).unwrap();
+        }
+
+        let updated = db_manager
+            .update_rows(
+                stock::table,
+                vec![Filter::eq(stock::symbol, "AAPL")],
+                stock::exchange_name.eq("NYSE"),
+            )
+            .unwrap();
+        assert_eq!(updated, 1);
+
+        let stocks: Vec<Stock> = db_manager
+            .query_rows(stock::table, vec![Filter::eq(stock::exchange_name, "NYSE")])
+            .await
+            .unwrap();
+        assert_eq!(stocks.len(), 1);
+        assert_eq!(stocks[0].symbol, "AAPL");
+
+        let err = db_manager.delete_rows(stock::table, vec![]).unwrap_err();
+        assert!(err.to_string().contains("without a filter"));
+        let err = db_manager
+            .update_rows(stock::table, vec![], stock::exchange_name.eq("NYSE"))
+            .unwrap_err();
+        assert!(err.to_string().contains("without a filter"));
+
+        let deleted = db_manager
+            .delete_rows(stock::table, vec![Filter::ne(stock::symbol, "GOOG")])
+            .unwrap();
+        assert_eq!(deleted, 2);
+    }
+
+    #[tokio::test]
+    async fn test_insert_rows() {
+        let db_manager = memory_db_manager();
+
+        // enough rows to need more than one statement
+        let stocks = (0..10_000)
+            .map(|i| stock(&format!("S{i}")))
+            .collect::<Vec<_>>();
+        let inserted = db_manager
+            .insert_rows(stock::table, stocks.iter().collect())
+            .unwrap();
+        assert_eq!(inserted, 10_000);
+        assert_eq!(symbols(&db_manager).await.len(), 10_000);
+
+        // stock names are unique, so these two clash with rows that are already there
+        let stocks = ["NEW", "S1", "S2"].map(stock);
+        let err = db_manager
+            .insert_rows(stock::table, stocks.iter().collect())
+            .unwrap_err();
+        let err = err.downcast_ref::<InsertRowsError>().unwrap();
+        assert_eq!(err.total, 3);
+        assert_eq!(
+            err.failed.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(symbols(&db_manager).await.len(), 10_000);
+    }
+
+    #[tokio::test]
+    async fn test_constraint_violations_are_typed() {
+        use crate::db::models::wallet::{schema::wallets, Wallet};
+        use crate::money::Amount;
+
+        let db_manager = memory_db_manager();
+        db_manager.insert_row(stock::table, &stock("AAPL")).unwrap();
+
+        let mut duplicate_symbol = stock("AAPL");
+        duplicate_symbol.name = "Apple Computer".to_owned();
+        let err = db_manager
+            .insert_row(stock::table, &duplicate_symbol)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::UniqueViolation(_))
+        ));
+
+        // there is no user 7
+        let wallet = Wallet {
+            id: None,
+            stock_id: 1,
+            user_id: 7,
+            balance: Amount::ZERO,
+            reserved: Amount::ZERO,
+        };
+        let err = db_manager.insert_row(wallets::table, &wallet).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::ForeignKeyViolation(_))
+        ));
+        assert!(format!("{err:#}").starts_with("Insert row error: Foreign key"));
+    }
+
+    #[tokio::test]
+    async fn test_query_page() {
+        let db_manager = memory_db_manager();
+        let stocks = ["AAPL", "MSFT", "GOOG", "AMZN", "TSLA"].map(stock);
+        db_manager
+            .insert_rows(stock::table, stocks.iter().collect())
+            .unwrap();
+
+        let mut pages = Vec::new();
+        let mut request = PageRequest::new(vec![Order::desc(stock::symbol)]).page_size(2);
+        loop {
+            let page: Page<Stock> = db_manager
+                .query_page(
+                    stock::table,
+                    vec![Filter::ne(stock::symbol, "GOOG")],
+                    request.clone(),
+                )
+                .await
+                .unwrap();
+            pages.push(page.rows.into_iter().map(|s| s.symbol).collect::<Vec<_>>());
+            match page.next_page_token {
+                Some(token) => request = request.page_token(token),
+                None => break,
+            }
+        }
+        assert_eq!(pages, vec![vec!["TSLA", "MSFT"], vec!["AMZN", "AAPL"]]);
+
+        let err = db_manager
+            .query_page::<_, Stock>(
+                stock::table,
+                vec![],
+                PageRequest::new(vec![]).page_token("nonsense"),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<InvalidPageToken>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_waiting_for_a_connection_times_out() {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(DbConnectionManager::new(":memory:".parse().unwrap()))
+            .unwrap();
+        let db_manager = DBManager::with_limits(pool, 4, Duration::from_millis(50));
+        run_migrations(&mut db_manager.connection().unwrap()).unwrap();
+
+        let held = db_manager.connection().unwrap();
+        let err = db_manager
+            .query_rows::<_, Stock>(stock::table, vec![])
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("No database connection became available"));
+
+        drop(held);
+        let inserted = db_manager
+            .run(|db| db.insert_row(stock::table, &stock("AAPL")))
+            .await
+            .unwrap();
+        assert_eq!(inserted, 1);
+    }
+
+    #[tokio::test]
+    async fn test_transaction_rolls_back_on_error() {
+        let db_manager = memory_db_manager();
+        db_manager.insert_row(stock::table, &stock("AAPL")).unwrap();
+
+        let result: Result<()> = db_manager.transaction(|tx| {
+            tx.insert_row(stock::table, &stock("MSFT"))?;
+            tx.delete_rows(stock::table, vec![Filter::eq(stock::symbol, "AAPL")])?;
+            Err(anyhow!("Settlement failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(symbols(&db_manager).await, vec!["AAPL"]);
+
+        let inserted = db_manager
+            .transaction(|tx| tx.insert_row(stock::table, &stock("MSFT")))
+            .unwrap();
+        assert_eq!(inserted, 1);
+        assert_eq!(symbols(&db_manager).await, vec!["AAPL", "MSFT"]);
+    }
+
+    /// A file database whose connections give up on a lock at once instead of waiting for it.
+    fn impatient_db_manager(name: &str) -> (DBManager, String) {
+        let path = std::env::temp_dir().join(format!("{name}-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap().to_owned();
+        let pool = Pool::builder()
+            .max_size(1)
+            .connection_customizer(Box::new(SqlitePragmas {
+                busy_timeout: Duration::ZERO,
+                ..Default::default()
+            }))
+            .build(DbConnectionManager::new(path.parse().unwrap()))
+            .unwrap();
+        let db_manager = DBManager::new(pool);
+        run_migrations(&mut db_manager.connection().unwrap()).unwrap();
+        (db_manager, path)
+    }
+
+    /// Takes the write lock on the database at `path` from a connection of its own.
+    fn lock(path: &str) -> DbConnection {
+        let mut conn = DbConnection::establish(path).unwrap();
+        conn.batch_execute("BEGIN IMMEDIATE").unwrap();
+        conn
+    }
+
+    #[tokio::test]
+    async fn test_transaction_retries_while_busy() {
+        let (db_manager, path) = impatient_db_manager("busy");
+
+        // another connection holds the write lock through the first few attempts
+        let mut other = lock(&path);
+        let holder = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            other.batch_execute("COMMIT").unwrap();
+        });
+
+        let start = std::time::Instant::now();
+        let mut attempts = 0;
+        db_manager
+            .transaction(|tx| {
+                attempts += 1;
+                tx.insert_row(stock::table, &stock("AAPL"))
+            })
+            .unwrap();
+        holder.join().unwrap();
+
+        // without a busy timeout every attempt fails at once, so only retrying can outlast the
+        // lock. BEGIN IMMEDIATE fails before the closure runs, so just the last attempt reaches it.
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(attempts, 1);
+        assert_eq!(symbols(&db_manager).await, vec!["AAPL"]);
+
+        let other = lock(&path);
+        let mut attempts = 0;
+        let err = db_manager
+            .transaction(|tx| {
+                attempts += 1;
+                tx.insert_row(stock::table, &stock("MSFT"))
+            })
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Database stayed busy after"));
+        assert_eq!(attempts, 0);
+
+        drop(other);
+        assert_eq!(symbols(&db_manager).await, vec!["AAPL"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transaction_retries_busy_statements() {
+        use diesel::result::{DatabaseErrorKind, Error};
+
+        let db_manager = memory_db_manager();
+        let serialization_failure = || {
+            let info = Box::new("could not serialize access".to_owned());
+            anyhow::Error::new(DbError::from(Error::DatabaseError(
+                DatabaseErrorKind::SerializationFailure,
+                info,
+            )))
+            .context("Update row error")
+        };
+
+        let mut attempts = 0;
+        db_manager
+            .transaction(|tx| {
+                attempts += 1;
+                tx.insert_row(stock::table, &stock(&format!("S{attempts}")))?;
+                if attempts < 3 {
+                    return Err(serialization_failure());
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(attempts, 3);
+        // the failed attempts were rolled back
+        assert_eq!(symbols(&db_manager).await, vec!["S3"]);
+
+        let mut attempts = 0;
+        let err = db_manager
+            .transaction(|tx| -> Result<()> {
+                attempts += 1;
+                tx.insert_row(stock::table, &stock("NEVER"))?;
+                Err(serialization_failure())
+            })
+            .unwrap_err();
+        assert_eq!(attempts, BUSY_RETRIES + 1);
+        assert!(err.to_string().starts_with("Database stayed busy after"));
+
+        // other failures are handed back without running the closure again
+        let mut attempts = 0;
+        let err = db_manager
+            .transaction(|tx| {
+                attempts += 1;
+                tx.insert_row(stock::table, &stock("S3"))
+            })
+            .unwrap_err();
+        assert_eq!(attempts, 1);
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::UniqueViolation(_))
+        ));
+        assert_eq!(symbols(&db_manager).await, vec!["S3"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database in POSTGRES_TEST_URL"]
+    async fn test_postgres_backend() {
+        let db_manager = postgres_db_manager("manager_test");
+
+        let stocks = ["AAPL", "MSFT", "GOOG"].map(stock);
+        let inserted = db_manager
+            .insert_rows(stock::table, stocks.iter().collect())
+            .unwrap();
+        assert_eq!(inserted, 3);
+
+        let err = db_manager
+            .insert_rows(stock::table, [stock("NEW"), stock("AAPL")].iter().collect())
+            .unwrap_err();
+        let err = err.downcast_ref::<InsertRowsError>().unwrap();
+        assert_eq!(err.failed.len(), 1);
+        assert_eq!(err.failed[0].0, 1);
+
+        let result: Result<()> = db_manager.transaction(|tx| {
+            tx.delete_rows(stock::table, vec![Filter::eq(stock::symbol, "AAPL")])?;
+            Err(anyhow!("Settlement failed"))
+        });
+        assert!(result.is_err());
+
+        let updated = db_manager
+            .update_rows(
+                stock::table,
+                vec![Filter::is_in(stock::symbol, ["MSFT", "GOOG"])],
+                stock::exchange_name.eq("NYSE"),
+            )
+            .unwrap();
+        assert_eq!(updated, 2);
+
+        let page: Page<Stock> = db_manager
+            .query_page(
+                stock::table,
+                vec![],
+                PageRequest::new(vec![Order::asc(stock::symbol)]).page_size(2),
+            )
+            .await
+            .unwrap();
+        let page_symbols = page.rows.into_iter().map(|s| s.symbol).collect::<Vec<_>>();
+        assert_eq!(page_symbols, vec!["AAPL", "GOOG"]);
+        assert!(page.next_page_token.is_some());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database in POSTGRES_TEST_URL"]
+    async fn test_postgres_deadlocks_are_retried() {
+        let db_manager = postgres_db_manager("deadlock_test");
+        let stocks = ["AAPL", "MSFT"].map(stock);
+        db_manager
+            .insert_rows(stock::table, stocks.iter().collect())
+            .unwrap();
+
+        // each transaction locks one stock and then waits for the other's, so PostgreSQL has to
+        // abort one of them
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let handles = [("AAPL", "MSFT"), ("MSFT", "AAPL")].map(|(first, second)| {
+            let db_manager = db_manager.clone();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                let mut attempts = 0;
+                db_manager
+                    .transaction(|tx| {
+                        attempts += 1;
+                        let rename = |symbol| {
+                            tx.update_rows(
+                                stock::table,
+                                vec![Filter::eq(stock::symbol, symbol)],
+                                stock::exchange_name.eq("NYSE"),
+                            )
+                        };
+                        rename(first)?;
+                        if attempts == 1 {
+                            barrier.wait();
+                        }
+                        rename(second)
+                    })
+                    .unwrap();
+                attempts
             })
-            .map(|r| r.unwrap_or(0))
-            .sum())
+        });
+
+        let attempts = handles.map(|handle| handle.join().unwrap());
+        assert_eq!(attempts.iter().sum::<i32>(), 3);
     }
 }
@@ -0,0 +1,574 @@
+//! Versioned schema migrations.
+//!
+//! Every change to the schema is a new entry at the end of [`MIGRATIONS`]. Migrations that have
+//! been released are never edited, since databases that already applied them won't run them
+//! again. The `schema_version` table records which versions a database has applied.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use diesel::connection::SimpleConnection;
+use diesel::dsl::max;
+use diesel::prelude::*;
+
+use super::connection::DbConnection;
+
+pub(crate) mod schema {
+    diesel::table! {
+        schema_version (version) {
+            version -> Integer,
+            name -> Text,
+            applied_at -> Timestamp,
+        }
+    }
+}
+
+use schema::schema_version;
+
+/// A schema change, written once for each backend. The SQL for a backend the change doesn't apply
+/// to is empty, the version is still recorded as applied.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sqlite: &'static str,
+    pub postgres: &'static str,
+}
+
+impl Migration {
+    fn sql(&self, conn: &DbConnection) -> &'static str {
+        match conn {
+            DbConnection::Sqlite(_) => self.sqlite,
+            DbConnection::Postgres(_) => self.postgres,
+        }
+    }
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    // Databases created before migrations existed already have these tables, so this one only
+    // creates what is missing.
+    Migration {
+        version: 1,
+        name: "create users, stock and wallets",
+        sqlite: r#"
+        CREATE TABLE IF NOT EXISTS users (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            email TEXT NOT NULL UNIQUE,
+            password TEXT NOT NULL,
+            first_name TEXT NOT NULL,
+            last_name TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS stock (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE,
+            symbol TEXT NOT NULL,
+            exchange_name TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS wallets (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            stock_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            balance DOUBLE NOT NULL
+        );
+        "#,
+        postgres: "",
+    },
+    // Balances move from floating point to a whole number of 10^-8 units, see `money::Amount`.
+    Migration {
+        version: 2,
+        name: "store wallet balances as fixed-point and add reserved funds",
+        sqlite: r#"
+        CREATE TABLE wallets_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            stock_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            balance INTEGER NOT NULL,
+            reserved INTEGER NOT NULL DEFAULT 0
+        );
+        INSERT INTO wallets_new (id, stock_id, user_id, balance)
+            SELECT id, stock_id, user_id, CAST(ROUND(balance * 100000000) AS INTEGER) FROM wallets;
+        DROP TABLE wallets;
+        ALTER TABLE wallets_new RENAME TO wallets;
+        "#,
+        postgres: "",
+    },
+    Migration {
+        version: 3,
+        name: "create orders and trades",
+        sqlite: r#"
+        CREATE TABLE orders (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            base_symbol TEXT NOT NULL,
+            quote_symbol TEXT NOT NULL,
+            side TEXT NOT NULL,
+            price INTEGER NOT NULL,
+            quantity INTEGER NOT NULL,
+            remaining INTEGER NOT NULL,
+            status TEXT NOT NULL,
+            created_at TIMESTAMP NOT NULL
+        );
+        CREATE TABLE trades (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            maker_order_id INTEGER NOT NULL,
+            taker_order_id INTEGER NOT NULL,
+            buyer_id INTEGER NOT NULL,
+            seller_id INTEGER NOT NULL,
+            base_symbol TEXT NOT NULL,
+            quote_symbol TEXT NOT NULL,
+            price INTEGER NOT NULL,
+            quantity INTEGER NOT NULL,
+            executed_at TIMESTAMP NOT NULL
+        );
+        "#,
+        postgres: "",
+    },
+    // Existing rows have to satisfy the new constraints first. Stocks listed more than once under
+    // the same symbol are merged into the oldest of them, and wallets for a stock or user that
+    // doesn't exist can't be used by anyone, so they are dropped. Then each user's wallets for
+    // the same stock are merged into the oldest one.
+    Migration {
+        version: 4,
+        name: "add foreign keys, unique constraints and indexes to wallets and stock",
+        sqlite: r#"
+        UPDATE wallets SET
+            stock_id = (SELECT MIN(s.id) FROM stock s
+                WHERE s.symbol = (SELECT symbol FROM stock WHERE id = wallets.stock_id))
+            WHERE stock_id IN (SELECT id FROM stock);
+        DELETE FROM stock
+            WHERE id NOT IN (SELECT MIN(id) FROM stock GROUP BY symbol);
+        DELETE FROM wallets
+            WHERE stock_id NOT IN (SELECT id FROM stock) OR user_id NOT IN (SELECT id FROM users);
+
+        UPDATE wallets SET
+            balance = (SELECT SUM(w.balance) FROM wallets w
+                WHERE w.user_id = wallets.user_id AND w.stock_id = wallets.stock_id),
+            reserved = (SELECT SUM(w.reserved) FROM wallets w
+                WHERE w.user_id = wallets.user_id AND w.stock_id = wallets.stock_id)
+            WHERE id IN (SELECT MIN(id) FROM wallets GROUP BY user_id, stock_id HAVING COUNT(*) > 1);
+        DELETE FROM wallets
+            WHERE id NOT IN (SELECT MIN(id) FROM wallets GROUP BY user_id, stock_id);
+
+        CREATE TABLE wallets_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            stock_id INTEGER NOT NULL REFERENCES stock (id),
+            user_id INTEGER NOT NULL REFERENCES users (id),
+            balance INTEGER NOT NULL,
+            reserved INTEGER NOT NULL DEFAULT 0,
+            UNIQUE (user_id, stock_id)
+        );
+        INSERT INTO wallets_new (id, stock_id, user_id, balance, reserved)
+            SELECT id, stock_id, user_id, balance, reserved FROM wallets;
+        DROP TABLE wallets;
+        ALTER TABLE wallets_new RENAME TO wallets;
+
+        CREATE INDEX wallets_stock_id ON wallets (stock_id);
+        CREATE UNIQUE INDEX stock_symbol ON stock (symbol);
+        "#,
+        postgres: "",
+    },
+    // Delisted stocks keep their row, wallets and trades still refer to it.
+    Migration {
+        version: 5,
+        name: "add disabled to users and delisted to stock",
+        sqlite: r#"
+        ALTER TABLE users ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT 0;
+        ALTER TABLE stock ADD COLUMN delisted BOOLEAN NOT NULL DEFAULT 0;
+        "#,
+        postgres: "",
+    },
+    // Tokens are stored as hex encoded SHA-256 hashes, never as the tokens themselves.
+    Migration {
+        version: 6,
+        name: "create sessions and used_refresh_tokens",
+        sqlite: r#"
+        CREATE TABLE sessions (
+            token_hash TEXT PRIMARY KEY,
+            refresh_token_hash TEXT NOT NULL UNIQUE,
+            user_id INTEGER NOT NULL REFERENCES users (id),
+            family INTEGER NOT NULL,
+            user_agent TEXT,
+            address TEXT,
+            create_time TIMESTAMP NOT NULL,
+            expire_time TIMESTAMP NOT NULL,
+            refresh_expire_time TIMESTAMP NOT NULL
+        );
+        CREATE INDEX sessions_user_id ON sessions (user_id);
+        CREATE INDEX sessions_family ON sessions (family);
+        CREATE TABLE used_refresh_tokens (
+            token_hash TEXT PRIMARY KEY,
+            family INTEGER NOT NULL,
+            expire_time TIMESTAMP NOT NULL
+        );
+        "#,
+        postgres: "",
+    },
+    // PostgreSQL support came after the versions above, so on PostgreSQL they do nothing and this
+    // creates the schema as they left it in one go. Everything is created only if it is missing,
+    // so databases that already have the schema are left as they are.
+    //
+    // Rows are inserted with a NULL id so the database assigns one. SQLite does that for any
+    // INTEGER PRIMARY KEY, PostgreSQL needs a trigger to take the next value of the sequence.
+    Migration {
+        version: 7,
+        name: "create the schema on PostgreSQL",
+        sqlite: "",
+        postgres: r#"
+        CREATE OR REPLACE FUNCTION assign_serial_id() RETURNS trigger AS $$
+        BEGIN
+            IF NEW.id IS NULL THEN
+                NEW.id := nextval(pg_get_serial_sequence(TG_TABLE_SCHEMA || '.' || TG_TABLE_NAME, 'id'));
+            END IF;
+            RETURN NEW;
+        END;
+        $$ LANGUAGE plpgsql;
+
+        CREATE TABLE IF NOT EXISTS users (
+            id SERIAL PRIMARY KEY,
+            email TEXT NOT NULL UNIQUE,
+            password TEXT NOT NULL,
+            first_name TEXT NOT NULL,
+            last_name TEXT NOT NULL,
+            disabled BOOLEAN NOT NULL DEFAULT FALSE
+        );
+        CREATE TABLE IF NOT EXISTS stock (
+            id SERIAL PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            symbol TEXT NOT NULL,
+            exchange_name TEXT NOT NULL,
+            delisted BOOLEAN NOT NULL DEFAULT FALSE
+        );
+        CREATE UNIQUE INDEX IF NOT EXISTS stock_symbol ON stock (symbol);
+        CREATE TABLE IF NOT EXISTS wallets (
+            id SERIAL PRIMARY KEY,
+            stock_id INTEGER NOT NULL REFERENCES stock (id),
+            user_id INTEGER NOT NULL REFERENCES users (id),
+            balance BIGINT NOT NULL,
+            reserved BIGINT NOT NULL DEFAULT 0,
+            UNIQUE (user_id, stock_id)
+        );
+        CREATE INDEX IF NOT EXISTS wallets_stock_id ON wallets (stock_id);
+        CREATE TABLE IF NOT EXISTS orders (
+            id SERIAL PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            base_symbol TEXT NOT NULL,
+            quote_symbol TEXT NOT NULL,
+            side TEXT NOT NULL,
+            price BIGINT NOT NULL,
+            quantity BIGINT NOT NULL,
+            remaining BIGINT NOT NULL,
+            status TEXT NOT NULL,
+            created_at TIMESTAMP NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS trades (
+            id SERIAL PRIMARY KEY,
+            maker_order_id INTEGER NOT NULL,
+            taker_order_id INTEGER NOT NULL,
+            buyer_id INTEGER NOT NULL,
+            seller_id INTEGER NOT NULL,
+            base_symbol TEXT NOT NULL,
+            quote_symbol TEXT NOT NULL,
+            price BIGINT NOT NULL,
+            quantity BIGINT NOT NULL,
+            executed_at TIMESTAMP NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS sessions (
+            token_hash TEXT PRIMARY KEY,
+            refresh_token_hash TEXT NOT NULL UNIQUE,
+            user_id INTEGER NOT NULL REFERENCES users (id),
+            family BIGINT NOT NULL,
+            user_agent TEXT,
+            address TEXT,
+            create_time TIMESTAMP NOT NULL,
+            expire_time TIMESTAMP NOT NULL,
+            refresh_expire_time TIMESTAMP NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS sessions_user_id ON sessions (user_id);
+        CREATE INDEX IF NOT EXISTS sessions_family ON sessions (family);
+        CREATE TABLE IF NOT EXISTS used_refresh_tokens (
+            token_hash TEXT PRIMARY KEY,
+            family BIGINT NOT NULL,
+            expire_time TIMESTAMP NOT NULL
+        );
+
+        DROP TRIGGER IF EXISTS users_id ON users;
+        CREATE TRIGGER users_id BEFORE INSERT ON users
+            FOR EACH ROW EXECUTE FUNCTION assign_serial_id();
+        DROP TRIGGER IF EXISTS stock_id ON stock;
+        CREATE TRIGGER stock_id BEFORE INSERT ON stock
+            FOR EACH ROW EXECUTE FUNCTION assign_serial_id();
+        DROP TRIGGER IF EXISTS wallets_id ON wallets;
+        CREATE TRIGGER wallets_id BEFORE INSERT ON wallets
+            FOR EACH ROW EXECUTE FUNCTION assign_serial_id();
+        DROP TRIGGER IF EXISTS orders_id ON orders;
+        CREATE TRIGGER orders_id BEFORE INSERT ON orders
+            FOR EACH ROW EXECUTE FUNCTION assign_serial_id();
+        DROP TRIGGER IF EXISTS trades_id ON trades;
+        CREATE TRIGGER trades_id BEFORE INSERT ON trades
+            FOR EACH ROW EXECUTE FUNCTION assign_serial_id();
+        "#,
+    },
+    // Resting orders get what they would hold if they were placed now, everything else holds
+    // nothing.
+    Migration {
+        version: 8,
+        name: "track the funds each order holds",
+        sqlite: r#"
+        ALTER TABLE orders ADD COLUMN held INTEGER NOT NULL DEFAULT 0;
+        UPDATE orders SET held = CASE side
+                WHEN 'buy' THEN CAST(ROUND(price * remaining / 100000000.0) AS INTEGER)
+                ELSE remaining
+            END
+            WHERE status IN ('open', 'partially_filled');
+        "#,
+        postgres: r#"
+        ALTER TABLE orders ADD COLUMN held BIGINT NOT NULL DEFAULT 0;
+        UPDATE orders SET held = CASE side
+                WHEN 'buy' THEN ROUND(price::NUMERIC * remaining / 100000000)::BIGINT
+                ELSE remaining
+            END
+            WHERE status IN ('open', 'partially_filled');
+        "#,
+    },
+];
+
+/// Returns the latest migration version applied to the database, or 0 for an empty database.
+pub fn current_version(conn: &mut DbConnection) -> Result<i32> {
+    create_version_table(conn)?;
+    let version = schema_version::table
+        .select(max(schema_version::version))
+        .first::<Option<i32>>(conn)
+        .map_err(|e| anyhow!("Failed to read schema version: {e:#?}"))?;
+    Ok(version.unwrap_or(0))
+}
+
+/// Applies every migration the database hasn't applied yet, in order, each in its own
+/// transaction. Stops at the first migration that fails.
+///
+/// # Returns
+/// The migrations that were applied.
+pub fn run_migrations(conn: &mut DbConnection) -> Result<Vec<&'static Migration>> {
+    apply(conn, MIGRATIONS)
+}
+
+fn create_version_table(conn: &mut DbConnection) -> Result<()> {
+    conn.batch_execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TIMESTAMP NOT NULL
+        );
+        "#,
+    )
+    .map_err(|e| anyhow!("Failed to create schema_version table: {e:#?}"))
+}
+
+fn apply<'m>(conn: &mut DbConnection, migrations: &'m [Migration]) -> Result<Vec<&'m Migration>> {
+    let current = current_version(conn)?;
+    let latest = migrations.last().map(|m| m.version).unwrap_or(0);
+    if current > latest {
+        return Err(anyhow!(
+            "Database is at schema version {current}, but the newest migration this build knows \
+             about is {latest}"
+        ));
+    }
+
+    let mut applied = Vec::new();
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        conn.transaction(|conn| {
+            let sql = migration.sql(conn);
+            if !sql.is_empty() {
+                conn.batch_execute(sql)?;
+            }
+            diesel::insert_into(schema_version::table)
+                .values((
+                    schema_version::version.eq(migration.version),
+                    schema_version::name.eq(migration.name),
+                    schema_version::applied_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(conn)?;
+            QueryResult::Ok(())
+        })
+        .with_context(|| {
+            format!(
+                "Migration {} ({}) failed",
+                migration.version, migration.name
+            )
+        })?;
+        applied.push(migration);
+    }
+
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::connection::{DbConnectionManager, SqlitePragmas};
+    use crate::db::models::wallet::{schema::wallets, Wallet};
+    use crate::money::Amount;
+    use diesel::r2d2::{CustomizeConnection, ManageConnection};
+
+    fn connection() -> DbConnection {
+        let mut conn = DbConnectionManager::new(":memory:".parse().unwrap())
+            .connect()
+            .unwrap();
+        SqlitePragmas::default().on_acquire(&mut conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_versions_are_ordered() {
+        assert!(MIGRATIONS.windows(2).all(|w| w[0].version < w[1].version));
+        assert_eq!(MIGRATIONS[0].version, 1);
+    }
+
+    #[test]
+    fn test_migrations_apply_once() {
+        let mut conn = connection();
+
+        let applied = run_migrations(&mut conn).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(
+            current_version(&mut conn).unwrap(),
+            MIGRATIONS.last().unwrap().version
+        );
+
+        assert!(run_migrations(&mut conn).unwrap().is_empty());
+    }
+
+    /// A database created before migrations existed, with floating point balances.
+    fn legacy_connection() -> DbConnection {
+        let mut conn = connection();
+        conn.batch_execute(
+            r#"
+            CREATE TABLE users (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                email TEXT NOT NULL UNIQUE,
+                password TEXT NOT NULL,
+                first_name TEXT NOT NULL,
+                last_name TEXT NOT NULL
+            );
+            CREATE TABLE stock (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE,
+                symbol TEXT NOT NULL,
+                exchange_name TEXT NOT NULL
+            );
+            CREATE TABLE wallets (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                stock_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                balance DOUBLE NOT NULL
+            );
+            INSERT INTO users (email, password, first_name, last_name)
+                VALUES ('a@b.com', 'hash', 'A', 'B');
+            INSERT INTO stock (name, symbol, exchange_name) VALUES ('Apple', 'AAPL', 'NASDAQ');
+            "#,
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_existing_database_is_upgraded() {
+        // the same wallet opened twice
+        let mut conn = legacy_connection();
+        conn.batch_execute(
+            "INSERT INTO wallets (stock_id, user_id, balance) VALUES (1, 1, 12.5), (1, 1, 2.5);",
+        )
+        .unwrap();
+
+        run_migrations(&mut conn).unwrap();
+
+        let wallets = wallets::table.load::<Wallet>(&mut conn).unwrap();
+        assert_eq!(wallets.len(), 1);
+        assert_eq!(wallets[0].id, Some(1));
+        assert_eq!(wallets[0].balance, "15".parse::<Amount>().unwrap());
+        assert_eq!(wallets[0].reserved, Amount::ZERO);
+    }
+
+    #[test]
+    fn test_duplicate_symbols_are_merged() {
+        use crate::db::models::stock::{schema::stock, Stock};
+
+        // the same stock listed twice, with a wallet for each listing
+        let mut conn = legacy_connection();
+        conn.batch_execute(
+            r#"
+            INSERT INTO stock (name, symbol, exchange_name) VALUES ('Apple Inc', 'AAPL', 'NYSE');
+            INSERT INTO wallets (stock_id, user_id, balance) VALUES (2, 1, 1.5), (1, 1, 2);
+            "#,
+        )
+        .unwrap();
+
+        run_migrations(&mut conn).unwrap();
+
+        let stocks = stock::table.load::<Stock>(&mut conn).unwrap();
+        assert_eq!(stocks.len(), 1);
+        assert_eq!(stocks[0].name, "Apple");
+        let wallets = wallets::table.load::<Wallet>(&mut conn).unwrap();
+        assert_eq!(wallets.len(), 1);
+        assert_eq!(wallets[0].stock_id, 1);
+        assert_eq!(wallets[0].balance, "3.5".parse::<Amount>().unwrap());
+    }
+
+    #[test]
+    fn test_orphan_wallets_are_dropped() {
+        // wallets for a stock and for a user that were deleted
+        let mut conn = legacy_connection();
+        conn.batch_execute(
+            "INSERT INTO wallets (stock_id, user_id, balance) VALUES (1, 1, 1), (7, 1, 2), (1, 7, 3);",
+        )
+        .unwrap();
+
+        run_migrations(&mut conn).unwrap();
+
+        let wallets = wallets::table.load::<Wallet>(&mut conn).unwrap();
+        assert_eq!(wallets.len(), 1);
+        assert_eq!(wallets[0].id, Some(1));
+    }
+
+    #[test]
+    #[ignore = "needs a PostgreSQL database in POSTGRES_TEST_URL"]
+    fn test_postgres_schema_is_only_created_where_missing() {
+        let db_manager = crate::db::test_utils::postgres_db_manager("migrations_test");
+        let mut conn = db_manager.connection().unwrap();
+        assert_eq!(
+            current_version(&mut conn).unwrap(),
+            MIGRATIONS.last().unwrap().version
+        );
+
+        // databases that had the schema before it moved into one migration run it again
+        let schema = MIGRATIONS.iter().find(|m| m.version == 7).unwrap();
+        conn.batch_execute(schema.postgres).unwrap();
+    }
+
+    #[test]
+    fn test_failed_migration_is_rolled_back() {
+        let mut conn = connection();
+        let migrations = [
+            Migration {
+                version: 1,
+                name: "create a",
+                sqlite: "CREATE TABLE a (id INTEGER PRIMARY KEY);",
+                postgres: "",
+            },
+            Migration {
+                version: 2,
+                name: "broken",
+                sqlite: "CREATE TABLE b (id INTEGER PRIMARY KEY); INSERT INTO missing VALUES (1);",
+                postgres: "",
+            },
+        ];
+
+        let err = apply(&mut conn, &migrations).unwrap_err();
+        assert!(format!("{err:#}").contains("Migration 2 (broken) failed"));
+        assert_eq!(current_version(&mut conn).unwrap(), 1);
+        assert!(conn.batch_execute("SELECT * FROM b").is_err());
+
+        // a database from a newer build is refused rather than silently used
+        let err = apply(&mut conn, &migrations[..0]).unwrap_err();
+        assert!(err.to_string().contains("schema version 1"));
+    }
+}
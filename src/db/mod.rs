@@ -1,2 +1,12 @@
+pub mod backup;
+pub mod connection;
+pub mod error;
+pub mod filter;
 pub mod manager;
+pub mod migrations;
 pub mod models;
+pub mod page;
+pub mod repository;
+
+#[cfg(test)]
+pub(crate) mod test_utils;
@@ -1,3 +1,6 @@
+pub mod order;
+pub mod session;
 pub mod stock;
+pub mod trade;
 pub mod user;
 pub mod wallet;
@@ -0,0 +1,47 @@
+use chrono::NaiveDateTime;
+use derive_builder::Builder;
+use diesel::prelude::{Insertable, Queryable, Selectable};
+
+use crate::money::Amount;
+
+pub(crate) mod schema {
+    diesel::table! {
+        orders (id) {
+            id -> Nullable<Integer>,
+            user_id -> Integer,
+            base_symbol -> Text,
+            quote_symbol -> Text,
+            side -> Text,
+            price -> BigInt,
+            quantity -> BigInt,
+            remaining -> BigInt,
+            held -> BigInt,
+            status -> Text,
+            created_at -> Timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Builder, Clone, Queryable, Selectable, Insertable, PartialEq)]
+#[diesel(table_name = schema::orders)]
+#[diesel(treat_none_as_default_value = false)]
+pub struct Order {
+    // id is assigned by the database when the order is placed, before it is matched.
+    pub id: Option<i32>,
+    // the owner id of the person who placed this order
+    pub user_id: i32,
+    pub base_symbol: String,
+    pub quote_symbol: String,
+    // either "buy" or "sell"
+    pub side: String,
+    pub price: Amount,
+    // the quantity the order was placed with
+    pub quantity: Amount,
+    // the quantity that has not been filled yet
+    pub remaining: Amount,
+    // the funds the order still holds in the wallet it draws from
+    pub held: Amount,
+    // one of "open", "partially_filled", "filled" or "cancelled"
+    pub status: String,
+    pub created_at: NaiveDateTime,
+}
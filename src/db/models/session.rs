@@ -0,0 +1,52 @@
+use chrono::NaiveDateTime;
+use derive_builder::Builder;
+use diesel::prelude::{Insertable, Queryable, Selectable};
+
+pub(crate) mod schema {
+    diesel::table! {
+        sessions (token_hash) {
+            token_hash -> Text,
+            refresh_token_hash -> Text,
+            user_id -> Integer,
+            family -> BigInt,
+            user_agent -> Nullable<Text>,
+            address -> Nullable<Text>,
+            create_time -> Timestamp,
+            expire_time -> Timestamp,
+            refresh_expire_time -> Timestamp,
+        }
+    }
+
+    diesel::table! {
+        used_refresh_tokens (token_hash) {
+            token_hash -> Text,
+            family -> BigInt,
+            expire_time -> Timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Builder, Clone, Queryable, Selectable, Insertable, PartialEq, Eq)]
+#[diesel(table_name = schema::sessions)]
+pub struct StoredSession {
+    // hex encoded SHA-256 of the access token
+    pub token_hash: String,
+    pub refresh_token_hash: String,
+    pub user_id: i32,
+    // shared by every session refreshed from the same login
+    pub family: i64,
+    pub user_agent: Option<String>,
+    pub address: Option<String>,
+    pub create_time: NaiveDateTime,
+    pub expire_time: NaiveDateTime,
+    pub refresh_expire_time: NaiveDateTime,
+}
+
+/// A refresh token that has been traded in, kept so that using it again can be detected.
+#[derive(Debug, Builder, Clone, Queryable, Selectable, Insertable, PartialEq, Eq)]
+#[diesel(table_name = schema::used_refresh_tokens)]
+pub struct UsedRefreshToken {
+    pub token_hash: String,
+    pub family: i64,
+    pub expire_time: NaiveDateTime,
+}
@@ -1,8 +1,5 @@
-use anyhow::{anyhow, Result};
 use derive_builder::Builder;
-use diesel::prelude::*;
 use diesel::prelude::{Insertable, Queryable, Selectable};
-use diesel::sqlite::SqliteConnection;
 
 pub(crate) mod schema {
     diesel::table! {
@@ -11,12 +8,15 @@ pub(crate) mod schema {
             name -> Text,
             symbol -> Text,
             exchange_name -> Text,
+            delisted -> Bool,
         }
     }
 }
 
 #[derive(Debug, Builder, Clone, Queryable, Selectable, Insertable)]
 #[diesel(table_name = schema::stock)]
+// a `None` id is bound as NULL, which sqlite turns into a fresh rowid, so rows can be bulk inserted
+#[diesel(treat_none_as_default_value = false)]
 pub struct Stock {
     // id is optinal because when we create a new item in the db, we don't actually set the id, we
     // let sqlite do that. We only set this field when we read from the db.
@@ -24,23 +24,7 @@ pub struct Stock {
     pub name: String,
     pub symbol: String,
     pub exchange_name: String,
-}
-
-impl Stock {
-    pub fn initialize_database(conn: &mut SqliteConnection) -> Result<()> {
-        diesel::sql_query(
-            r#"
-            CREATE TABLE IF NOT EXISTS stock (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            name TEXT NOT NULL UNIQUE,
-            symbol TEXT NOT NULL,
-            exchange_name TEXT NOT NULL
-        );
-        "#,
-        )
-        .execute(conn)
-        .map_err(|e| anyhow!("Failed to create table: {e:#?}"))?;
-
-        Ok(())
-    }
+    // delisted stocks have no market, but existing wallets still hold them
+    #[builder(default)]
+    pub delisted: bool,
 }
@@ -0,0 +1,43 @@
+use chrono::NaiveDateTime;
+use derive_builder::Builder;
+use diesel::prelude::{Insertable, Queryable, Selectable};
+
+use crate::money::Amount;
+
+pub(crate) mod schema {
+    diesel::table! {
+        trades (id) {
+            id -> Nullable<Integer>,
+            maker_order_id -> Integer,
+            taker_order_id -> Integer,
+            buyer_id -> Integer,
+            seller_id -> Integer,
+            base_symbol -> Text,
+            quote_symbol -> Text,
+            price -> BigInt,
+            quantity -> BigInt,
+            executed_at -> Timestamp,
+        }
+    }
+}
+
+/// A single fill between two orders.
+#[derive(Debug, Builder, Clone, Queryable, Selectable, Insertable, PartialEq)]
+#[diesel(table_name = schema::trades)]
+#[diesel(treat_none_as_default_value = false)]
+pub struct Trade {
+    // id is optinal because when we create a new item in the db, we don't actually set the id, we
+    // let sqlite do that. We only set this field when we read from the db.
+    pub id: Option<i32>,
+    // the order that was resting in the book
+    pub maker_order_id: i32,
+    // the order that matched against the resting order
+    pub taker_order_id: i32,
+    pub buyer_id: i32,
+    pub seller_id: i32,
+    pub base_symbol: String,
+    pub quote_symbol: String,
+    pub price: Amount,
+    pub quantity: Amount,
+    pub executed_at: NaiveDateTime,
+}
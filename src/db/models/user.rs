@@ -1,6 +1,5 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use derive_builder::Builder;
-use diesel::{sqlite::SqliteConnection, RunQueryDsl};
 use prost_types::Timestamp;
 
 use crate::passwords::Password;
@@ -14,12 +13,14 @@ pub(crate) mod schema {
             password -> Text,
             first_name -> Text,
             last_name -> Text,
+            disabled -> Bool,
         }
     }
 }
 
 #[derive(Debug, Builder, Clone, Queryable, Selectable, Insertable, PartialEq, Eq)]
 #[diesel(table_name = schema::users)]
+#[diesel(treat_none_as_default_value = false)]
 pub struct User {
     // id is optinal because when we create a new item in the db, we don't actually set the id, we
     // let sqlite do that. We only set this field when we read from the db.
@@ -28,30 +29,15 @@ pub struct User {
     pub password: String,
     pub first_name: String,
     pub last_name: String,
+    // disabled users can't log in
+    #[builder(default)]
+    pub disabled: bool,
 }
 
 impl User {
     pub fn verify_password(&self, plaintext: &str) -> Result<bool, bcrypt::BcryptError> {
         Password::from_hash(&self.password).verify(plaintext)
     }
-
-    pub fn initialize_database(conn: &mut SqliteConnection) -> Result<()> {
-        diesel::sql_query(
-            r#"
-        CREATE TABLE IF NOT EXISTS users (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            email TEXT NOT NULL UNIQUE,
-            password TEXT NOT NULL,
-            first_name TEXT NOT NULL,
-            last_name TEXT NOT NULL
-        );
-        "#,
-        )
-        .execute(conn)
-        .map_err(|e| anyhow!("Failed to create table: {e:#?}"))?;
-
-        Ok(())
-    }
 }
 
 impl From<User> for rust_models::common::User {
@@ -1,10 +1,7 @@
-use anyhow::{anyhow, Result};
-
 use derive_builder::Builder;
-use diesel::{
-    prelude::{Insertable, Queryable, Selectable},
-    RunQueryDsl, SqliteConnection,
-};
+use diesel::prelude::{Insertable, Queryable, Selectable};
+
+use crate::money::Amount;
 
 pub(crate) mod schema {
     diesel::table! {
@@ -12,38 +9,35 @@ pub(crate) mod schema {
             id -> Nullable<Integer>,
             stock_id -> Integer,
             user_id -> Integer,
-            balance -> Double,
+            balance -> BigInt,
+            reserved -> BigInt,
         }
     }
 }
 
 #[derive(Debug, Builder, Clone, Queryable, Selectable, Insertable, PartialEq)]
 #[diesel(table_name = schema::wallets)]
+#[diesel(treat_none_as_default_value = false)]
 pub struct Wallet {
-    id: Option<i32>,
+    pub id: Option<i32>,
     // the id of the stock that this wallet is set for
-    stock_id: i32,
+    pub stock_id: i32,
     // the owner id of the person this wallet belongs to
-    user_id: i32,
+    pub user_id: i32,
     // amount of money in the wallet
-    balance: f64,
+    pub balance: Amount,
+    // part of the balance that is held by open orders and can't be spent elsewhere
+    pub reserved: Amount,
 }
 
 impl Wallet {
-    pub fn initialize_database(conn: &mut SqliteConnection) -> Result<()> {
-        diesel::sql_query(
-            r#"
-        CREATE TABLE IF NOT EXISTS wallets (
-        id INTEGER PRIMARY KEY AUTOINCREMENT,
-        stock_id INTEGER NOT NULL,
-        user_id INTEGER NOT NULL,
-        balance DOUBLE NOT NULL
-        );
-        "#,
+    /// The part of the balance that isn't held by open orders. Settlement never holds more than
+    /// the balance, so this is only negative if the row was written some other way.
+    pub fn available(&self) -> Amount {
+        Amount::from_minor_units(
+            self.balance
+                .minor_units()
+                .saturating_sub(self.reserved.minor_units()),
         )
-        .execute(conn)
-        .map_err(|e| anyhow!("Failed to create table: {e:#?}"))?;
-
-        Ok(())
     }
 }
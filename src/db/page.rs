@@ -0,0 +1,294 @@
+use std::fmt;
+use std::marker::PhantomData;
+
+use anyhow::Result;
+use diesel::Column;
+use sha2::{Digest, Sha256};
+
+use super::filter::Filter;
+
+/// The number of rows returned when a request doesn't say how many it wants.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// The most rows a single page can hold. Larger requests are clamped to this.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+impl Direction {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Direction::Asc => "ASC",
+            Direction::Desc => "DESC",
+        }
+    }
+}
+
+/// Sorts rows of table `T` by one of its columns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order<T> {
+    column: &'static str,
+    direction: Direction,
+    _table: PhantomData<T>,
+}
+
+impl<T> Order<T> {
+    pub fn asc<C: Column<Table = T>>(_column: C) -> Self {
+        Self::new(C::NAME, Direction::Asc)
+    }
+
+    pub fn desc<C: Column<Table = T>>(_column: C) -> Self {
+        Self::new(C::NAME, Direction::Desc)
+    }
+
+    fn new(column: &'static str, direction: Direction) -> Self {
+        Self {
+            column,
+            direction,
+            _table: PhantomData,
+        }
+    }
+
+    /// Renders `orders` as the body of an `ORDER BY` clause.
+    ///
+    /// Rows are always sorted by `primary_key` last so that rows which tie on every other column
+    /// still come back in the same order on every page.
+    pub(crate) fn to_sql(orders: &[Order<T>], primary_key: &'static str) -> String {
+        let mut columns = orders
+            .iter()
+            .map(|o| format!("\"{}\" {}", o.column, o.direction.as_sql()))
+            .collect::<Vec<_>>();
+        if !orders.iter().any(|o| o.column == primary_key) {
+            columns.push(format!("\"{primary_key}\" ASC"));
+        }
+        columns.join(", ")
+    }
+}
+
+/// Which page of rows to load and how to sort them.
+///
+/// ```ignore
+/// let page = db_manager
+///     .query_page(
+///         trades::table,
+///         vec![Filter::eq(trades::buyer_id, user_id)],
+///         PageRequest::new(vec![Order::desc(trades::executed_at)]).page_token(request.page_token),
+///     )
+///     .await?;
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageRequest<T> {
+    pub order_by: Vec<Order<T>>,
+    pub page_size: usize,
+    /// The token from the previous page, or `None` for the first page.
+    pub page_token: Option<String>,
+}
+
+impl<T> PageRequest<T> {
+    /// Requests the first page of [`DEFAULT_PAGE_SIZE`] rows.
+    pub fn new(order_by: Vec<Order<T>>) -> Self {
+        Self {
+            order_by,
+            page_size: DEFAULT_PAGE_SIZE,
+            page_token: None,
+        }
+    }
+
+    /// Sets how many rows to return. Zero picks the default, as gRPC clients send zero when they
+    /// leave the field unset.
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Continues from a token returned with an earlier page. An empty token means the first page.
+    pub fn page_token(mut self, page_token: impl Into<String>) -> Self {
+        let page_token = page_token.into();
+        self.page_token = (!page_token.is_empty()).then_some(page_token);
+        self
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        match self.page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            size => size.min(MAX_PAGE_SIZE),
+        }
+    }
+
+    /// The offset the page token points at. `query` is the [`query_key`] of the query being
+    /// paged through, which the token must have been issued for.
+    pub(crate) fn offset(&self, query: &str) -> Result<i64> {
+        match &self.page_token {
+            None => Ok(0),
+            Some(token) => {
+                Ok(decode_token(token, query).ok_or_else(|| InvalidPageToken(token.clone()))?)
+            }
+        }
+    }
+}
+
+/// Identifies a query by its filters and sort order, so that a page token issued for one query
+/// is refused by any other.
+pub(crate) fn query_key<T>(order_by: &[Order<T>], filters: &[Filter<T>]) -> String {
+    let mut hasher = Sha256::new();
+    for order in order_by {
+        hasher.update(format!("{} {:?};", order.column, order.direction));
+    }
+    hasher.update("|");
+    for filter in filters {
+        hasher.update(format!("{};", filter.describe()));
+    }
+    hasher.finalize()[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Returned by [`DatabaseImpl::query_page`](super::manager::DatabaseImpl::query_page) when the
+/// page token can't be decoded, points past the last offset a page can start at, or was issued
+/// for a different query. Services should answer it with `INVALID_ARGUMENT`.
+#[derive(Debug)]
+pub struct InvalidPageToken(pub String);
+
+impl fmt::Display for InvalidPageToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid page token {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPageToken {}
+
+/// One page of rows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<U> {
+    pub rows: Vec<U>,
+    /// Pass this back in a [`PageRequest`] to get the next page. `None` on the last page.
+    pub next_page_token: Option<String>,
+}
+
+impl<U> Page<U> {
+    /// Builds a page from up to `limit + 1` rows of `query` loaded at `offset`. The extra row only
+    /// signals that there is another page and is dropped.
+    pub(crate) fn new(mut rows: Vec<U>, offset: i64, limit: usize, query: &str) -> Self {
+        let next_offset = i64::try_from(limit)
+            .ok()
+            .and_then(|limit| offset.checked_add(limit))
+            .filter(|offset| *offset <= MAX_OFFSET);
+        let next_page_token = next_offset
+            .filter(|_| rows.len() > limit)
+            .map(|offset| encode_token(offset, query));
+        rows.truncate(limit);
+        Self {
+            rows,
+            next_page_token,
+        }
+    }
+}
+
+// Tokens are opaque to clients. They currently hold the offset of the next page and the key of
+// the query it belongs to.
+const TOKEN_PREFIX: &str = "o";
+
+/// The largest offset a page can start at, so that adding a page to it can't overflow.
+const MAX_OFFSET: i64 = i64::MAX - MAX_PAGE_SIZE as i64;
+
+fn encode_token(offset: i64, query: &str) -> String {
+    format!("{TOKEN_PREFIX}{offset:x}.{query}")
+}
+
+fn decode_token(token: &str, query: &str) -> Option<i64> {
+    let (offset, token_query) = token.strip_prefix(TOKEN_PREFIX)?.split_once('.')?;
+    if token_query != query || offset.starts_with('+') {
+        return None;
+    }
+    u64::from_str_radix(offset, 16)
+        .ok()
+        .and_then(|offset| i64::try_from(offset).ok())
+        .filter(|offset| *offset <= MAX_OFFSET)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::stock::schema::stock;
+
+    #[test]
+    fn test_tokens_belong_to_one_query() {
+        let by_symbol = [Order::asc(stock::symbol)];
+        let nyse = [Filter::eq(stock::exchange_name, "NYSE")];
+        let query = query_key(&by_symbol, &nyse);
+        let token = encode_token(2, &query);
+
+        assert_eq!(decode_token(&token, &query), Some(2));
+        assert_eq!(decode_token(&token, &query_key(&by_symbol, &[])), None);
+        assert_eq!(
+            decode_token(&token, &query_key(&[Order::desc(stock::symbol)], &nyse)),
+            None
+        );
+        assert_eq!(
+            decode_token(
+                &token,
+                &query_key(&by_symbol, &[Filter::eq(stock::exchange_name, "LSE")])
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_order_by_ends_with_primary_key() {
+        let orders = vec![Order::desc(stock::exchange_name), Order::asc(stock::symbol)];
+        assert_eq!(
+            Order::to_sql(&orders, "id"),
+            r#""exchange_name" DESC, "symbol" ASC, "id" ASC"#
+        );
+        assert_eq!(
+            Order::to_sql(&[Order::desc(stock::id)], "id"),
+            r#""id" DESC"#
+        );
+    }
+
+    #[test]
+    fn test_page_tokens() {
+        let query = query_key::<stock::table>(&[], &[]);
+        let request = PageRequest::<stock::table>::new(vec![]).page_size(2);
+        assert_eq!(request.offset(&query).unwrap(), 0);
+
+        let page = Page::new(vec![1, 2, 3], 0, request.limit(), &query);
+        assert_eq!(page.rows, vec![1, 2]);
+        let request = request.page_token(page.next_page_token.unwrap());
+        assert_eq!(request.offset(&query).unwrap(), 2);
+
+        let page = Page::new(vec![3], 2, request.limit(), &query);
+        assert_eq!(page.next_page_token, None);
+
+        for token in [
+            "garbage".to_owned(),
+            format!("o{:x}.{query}", i64::MAX),
+            format!("o{:x}.{query}", u64::MAX),
+            format!("o-1.{query}"),
+        ] {
+            let err = PageRequest::<stock::table>::new(vec![])
+                .page_token(token)
+                .offset(&query)
+                .unwrap_err();
+            assert!(err.downcast_ref::<InvalidPageToken>().is_some());
+        }
+        assert_eq!(
+            PageRequest::<stock::table>::new(vec![])
+                .page_token(encode_token(MAX_OFFSET, &query))
+                .offset(&query)
+                .unwrap(),
+            MAX_OFFSET
+        );
+        assert_eq!(
+            PageRequest::<stock::table>::new(vec![])
+                .page_size(0)
+                .limit(),
+            DEFAULT_PAGE_SIZE
+        );
+    }
+}
@@ -0,0 +1,10 @@
+//! Typed lookups for each model, so services don't build table queries themselves.
+//!
+//! Every repository is a trait with a database implementation built on [`DBManager`] and an
+//! in-memory one for unit tests that shouldn't need a database.
+//!
+//! [`DBManager`]: crate::db::manager::DBManager
+
+pub mod stock;
+pub mod user;
+pub mod wallet;
@@ -0,0 +1,147 @@
+use std::fmt::Debug;
+use std::sync::RwLock;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use diesel::ExpressionMethods;
+
+use crate::db::error::DbError;
+use crate::db::filter::Filter;
+use crate::db::manager::{DBManager, DatabaseImpl};
+use crate::db::models::stock::{schema::stock, Stock};
+
+#[async_trait]
+pub trait StockRepositoryImpl: Debug + Send + Sync {
+    async fn by_symbol(&self, symbol: &str) -> Result<Option<Stock>>;
+    /// Every stock, delisted ones included.
+    async fn list(&self) -> Result<Vec<Stock>>;
+    /// Saves a new stock and returns it with its id. Fails with [`DbError::UniqueViolation`] if
+    /// the symbol or name is taken.
+    async fn create(&self, stock: Stock) -> Result<Stock>;
+    /// Returns false if no stock has that symbol.
+    async fn set_delisted(&self, symbol: &str, delisted: bool) -> Result<bool>;
+}
+
+#[derive(Debug, Clone)]
+pub struct StockRepository {
+    db_manager: DBManager,
+}
+
+impl StockRepository {
+    pub fn new(db_manager: DBManager) -> Self {
+        Self { db_manager }
+    }
+}
+
+#[async_trait]
+impl StockRepositoryImpl for StockRepository {
+    async fn by_symbol(&self, symbol: &str) -> Result<Option<Stock>> {
+        let stocks: Vec<Stock> = self
+            .db_manager
+            .query_rows(stock::table, vec![Filter::eq(stock::symbol, symbol)])
+            .await?;
+        Ok(stocks.into_iter().next())
+    }
+
+    async fn list(&self) -> Result<Vec<Stock>> {
+        self.db_manager.query_rows(stock::table, vec![]).await
+    }
+
+    async fn create(&self, stock: Stock) -> Result<Stock> {
+        let symbol = stock.symbol.clone();
+        self.db_manager
+            .run(move |db| db.insert_row(stock::table, &stock))
+            .await?;
+        self.by_symbol(&symbol)
+            .await?
+            .ok_or_else(|| anyhow!("Stock {symbol} was not saved"))
+    }
+
+    async fn set_delisted(&self, symbol: &str, delisted: bool) -> Result<bool> {
+        let filters = vec![Filter::eq(stock::symbol, symbol)];
+        let updated = self
+            .db_manager
+            .run(move |db| db.update_rows(stock::table, filters, stock::delisted.eq(delisted)))
+            .await?;
+        Ok(updated > 0)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryStockRepository {
+    stocks: RwLock<Vec<Stock>>,
+}
+
+#[async_trait]
+impl StockRepositoryImpl for InMemoryStockRepository {
+    async fn by_symbol(&self, symbol: &str) -> Result<Option<Stock>> {
+        let stocks = self.stocks.read().unwrap();
+        Ok(stocks.iter().find(|s| s.symbol == symbol).cloned())
+    }
+
+    async fn list(&self) -> Result<Vec<Stock>> {
+        Ok(self.stocks.read().unwrap().clone())
+    }
+
+    async fn create(&self, mut stock: Stock) -> Result<Stock> {
+        let mut stocks = self.stocks.write().unwrap();
+        if stocks
+            .iter()
+            .any(|s| s.symbol == stock.symbol || s.name == stock.name)
+        {
+            return Err(DbError::UniqueViolation("stock.symbol".to_owned()).into());
+        }
+        stock.id = Some(stocks.len() as i32 + 1);
+        stocks.push(stock.clone());
+        Ok(stock)
+    }
+
+    async fn set_delisted(&self, symbol: &str, delisted: bool) -> Result<bool> {
+        let mut stocks = self.stocks.write().unwrap();
+        let stock = stocks.iter_mut().find(|s| s.symbol == symbol);
+        Ok(stock.map(|s| s.delisted = delisted).is_some())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::stock::StockBuilder;
+    use crate::db::test_utils::memory_db_manager;
+
+    fn stock(symbol: &str) -> Stock {
+        StockBuilder::default()
+            .id(None)
+            .name(format!("{symbol} Inc"))
+            .symbol(symbol.to_owned())
+            .exchange_name("NASDAQ".to_owned())
+            .build()
+            .unwrap()
+    }
+
+    async fn check(repository: &dyn StockRepositoryImpl) {
+        let aapl = repository.create(stock("AAPL")).await.unwrap();
+        assert!(aapl.id.is_some());
+        assert!(repository.create(stock("AAPL")).await.is_err());
+
+        assert_eq!(
+            repository.by_symbol("AAPL").await.unwrap().unwrap().id,
+            aapl.id
+        );
+        assert!(repository.by_symbol("MSFT").await.unwrap().is_none());
+
+        assert!(repository.set_delisted("AAPL", true).await.unwrap());
+        assert!(!repository.set_delisted("MSFT", true).await.unwrap());
+        let stocks = repository.list().await.unwrap();
+        assert_eq!(stocks.len(), 1);
+        assert!(stocks[0].delisted);
+    }
+
+    #[tokio::test]
+    async fn test_repositories_agree() {
+        let db_manager = memory_db_manager();
+
+        check(&StockRepository::new(db_manager)).await;
+        check(&InMemoryStockRepository::default()).await;
+    }
+}
@@ -0,0 +1,176 @@
+use std::fmt::Debug;
+use std::sync::RwLock;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use diesel::ExpressionMethods;
+
+use crate::db::error::DbError;
+use crate::db::filter::Filter;
+use crate::db::manager::{DBManager, DatabaseImpl};
+use crate::db::models::user::{schema::users, User};
+
+#[async_trait]
+pub trait UserRepositoryImpl: Debug + Send + Sync {
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>>;
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>>;
+    async fn list(&self) -> Result<Vec<User>>;
+    /// Saves a new user and returns it with its id. Fails with [`DbError::UniqueViolation`] if
+    /// the email is taken.
+    async fn create(&self, user: User) -> Result<User>;
+    /// Returns false if no user has that email.
+    async fn set_disabled(&self, email: &str, disabled: bool) -> Result<bool>;
+}
+
+#[derive(Debug, Clone)]
+pub struct UserRepository {
+    db_manager: DBManager,
+}
+
+impl UserRepository {
+    pub fn new(db_manager: DBManager) -> Self {
+        Self { db_manager }
+    }
+}
+
+#[async_trait]
+impl UserRepositoryImpl for UserRepository {
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>> {
+        let users: Vec<User> = self
+            .db_manager
+            .query_rows(users::table, vec![Filter::eq(users::id, id)])
+            .await?;
+        Ok(users.into_iter().next())
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>> {
+        let users: Vec<User> = self
+            .db_manager
+            .query_rows(users::table, vec![Filter::eq(users::email, email)])
+            .await?;
+        Ok(users.into_iter().next())
+    }
+
+    async fn list(&self) -> Result<Vec<User>> {
+        self.db_manager.query_rows(users::table, vec![]).await
+    }
+
+    async fn create(&self, user: User) -> Result<User> {
+        let email = user.email.clone();
+        self.db_manager
+            .run(move |db| db.insert_row(users::table, &user))
+            .await?;
+        self.find_by_email(&email)
+            .await?
+            .ok_or_else(|| anyhow!("User {email} was not saved"))
+    }
+
+    async fn set_disabled(&self, email: &str, disabled: bool) -> Result<bool> {
+        let filters = vec![Filter::eq(users::email, email)];
+        let updated = self
+            .db_manager
+            .run(move |db| db.update_rows(users::table, filters, users::disabled.eq(disabled)))
+            .await?;
+        Ok(updated > 0)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryUserRepository {
+    users: RwLock<Vec<User>>,
+}
+
+#[async_trait]
+impl UserRepositoryImpl for InMemoryUserRepository {
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>> {
+        let users = self.users.read().unwrap();
+        Ok(users.iter().find(|u| u.id == Some(id)).cloned())
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>> {
+        let users = self.users.read().unwrap();
+        Ok(users.iter().find(|u| u.email == email).cloned())
+    }
+
+    async fn list(&self) -> Result<Vec<User>> {
+        Ok(self.users.read().unwrap().clone())
+    }
+
+    async fn create(&self, mut user: User) -> Result<User> {
+        let mut users = self.users.write().unwrap();
+        if users.iter().any(|u| u.email == user.email) {
+            return Err(DbError::UniqueViolation("users.email".to_owned()).into());
+        }
+        user.id = Some(users.len() as i32 + 1);
+        users.push(user.clone());
+        Ok(user)
+    }
+
+    async fn set_disabled(&self, email: &str, disabled: bool) -> Result<bool> {
+        let mut users = self.users.write().unwrap();
+        let user = users.iter_mut().find(|u| u.email == email);
+        Ok(user.map(|u| u.disabled = disabled).is_some())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::user::UserBuilder;
+    use crate::db::test_utils::memory_db_manager;
+
+    fn user(email: &str) -> User {
+        UserBuilder::default()
+            .id(None)
+            .email(email.to_owned())
+            .password("hash".to_owned())
+            .first_name("Ada".to_owned())
+            .last_name("Lovelace".to_owned())
+            .build()
+            .unwrap()
+    }
+
+    async fn check(repository: &dyn UserRepositoryImpl) {
+        let ada = repository.create(user("ada@example.com")).await.unwrap();
+        let id = ada.id.unwrap();
+        repository.create(user("bob@example.com")).await.unwrap();
+
+        let err = repository
+            .create(user("ada@example.com"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::UniqueViolation(_))
+        ));
+
+        assert_eq!(repository.find_by_id(id).await.unwrap(), Some(ada.clone()));
+        assert_eq!(
+            repository.find_by_email("ada@example.com").await.unwrap(),
+            Some(ada)
+        );
+        assert_eq!(
+            repository.find_by_email("eve@example.com").await.unwrap(),
+            None
+        );
+        assert_eq!(repository.list().await.unwrap().len(), 2);
+
+        assert!(repository
+            .set_disabled("ada@example.com", true)
+            .await
+            .unwrap());
+        assert!(!repository
+            .set_disabled("eve@example.com", true)
+            .await
+            .unwrap());
+        assert!(repository.find_by_id(id).await.unwrap().unwrap().disabled);
+    }
+
+    #[tokio::test]
+    async fn test_repositories_agree() {
+        let db_manager = memory_db_manager();
+
+        check(&UserRepository::new(db_manager)).await;
+        check(&InMemoryUserRepository::default()).await;
+    }
+}
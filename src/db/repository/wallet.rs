@@ -0,0 +1,147 @@
+use std::fmt::Debug;
+use std::sync::RwLock;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::db::filter::Filter;
+use crate::db::manager::{DBManager, DatabaseImpl};
+use crate::db::models::wallet::{schema::wallets, Wallet};
+
+/// Read access to wallets. Balances only change through settlement, which works inside the
+/// transaction of the order that moves them.
+#[async_trait]
+pub trait WalletRepositoryImpl: Debug + Send + Sync {
+    /// Every wallet the user holds.
+    async fn for_user(&self, user_id: i32) -> Result<Vec<Wallet>>;
+    async fn find(&self, user_id: i32, stock_id: i32) -> Result<Option<Wallet>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct WalletRepository {
+    db_manager: DBManager,
+}
+
+impl WalletRepository {
+    pub fn new(db_manager: DBManager) -> Self {
+        Self { db_manager }
+    }
+}
+
+#[async_trait]
+impl WalletRepositoryImpl for WalletRepository {
+    async fn for_user(&self, user_id: i32) -> Result<Vec<Wallet>> {
+        self.db_manager
+            .query_rows(wallets::table, vec![Filter::eq(wallets::user_id, user_id)])
+            .await
+    }
+
+    async fn find(&self, user_id: i32, stock_id: i32) -> Result<Option<Wallet>> {
+        let wallets: Vec<Wallet> = self
+            .db_manager
+            .query_rows(
+                wallets::table,
+                vec![
+                    Filter::eq(wallets::user_id, user_id),
+                    Filter::eq(wallets::stock_id, stock_id),
+                ],
+            )
+            .await?;
+        Ok(wallets.into_iter().next())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryWalletRepository {
+    wallets: RwLock<Vec<Wallet>>,
+}
+
+impl InMemoryWalletRepository {
+    pub fn new(wallets: Vec<Wallet>) -> Self {
+        Self {
+            wallets: RwLock::new(wallets),
+        }
+    }
+}
+
+#[async_trait]
+impl WalletRepositoryImpl for InMemoryWalletRepository {
+    async fn for_user(&self, user_id: i32) -> Result<Vec<Wallet>> {
+        let wallets = self.wallets.read().unwrap();
+        Ok(wallets
+            .iter()
+            .filter(|w| w.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn find(&self, user_id: i32, stock_id: i32) -> Result<Option<Wallet>> {
+        let wallets = self.wallets.read().unwrap();
+        Ok(wallets
+            .iter()
+            .find(|w| w.user_id == user_id && w.stock_id == stock_id)
+            .cloned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::{stock, user, wallet::WalletBuilder};
+    use crate::db::test_utils::memory_db_manager;
+    use crate::money::Amount;
+
+    fn wallet(user_id: i32, stock_id: i32) -> Wallet {
+        WalletBuilder::default()
+            .id(None)
+            .stock_id(stock_id)
+            .user_id(user_id)
+            .balance(Amount::from_minor_units(100))
+            .reserved(Amount::ZERO)
+            .build()
+            .unwrap()
+    }
+
+    async fn check(repository: &dyn WalletRepositoryImpl) {
+        assert_eq!(repository.for_user(1).await.unwrap().len(), 2);
+        assert_eq!(repository.for_user(3).await.unwrap(), vec![]);
+        let found = repository.find(2, 1).await.unwrap().unwrap();
+        assert_eq!((found.user_id, found.stock_id), (2, 1));
+        assert_eq!(repository.find(2, 2).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_repositories_agree() {
+        let db_manager = memory_db_manager();
+        for i in 1..=2 {
+            let user = user::UserBuilder::default()
+                .id(None)
+                .email(format!("trader{i}@example.com"))
+                .password("hash".to_owned())
+                .first_name("Trader".to_owned())
+                .last_name(i.to_string())
+                .build()
+                .unwrap();
+            db_manager
+                .insert_row(user::schema::users::table, &user)
+                .unwrap();
+            let stock = stock::StockBuilder::default()
+                .id(None)
+                .name(format!("Stock {i}"))
+                .symbol(format!("S{i}"))
+                .exchange_name("NASDAQ".to_owned())
+                .build()
+                .unwrap();
+            db_manager
+                .insert_row(stock::schema::stock::table, &stock)
+                .unwrap();
+        }
+        let wallets = vec![wallet(1, 1), wallet(1, 2), wallet(2, 1)];
+        db_manager
+            .insert_rows(wallets::table, wallets.iter().collect())
+            .unwrap();
+
+        check(&WalletRepository::new(db_manager)).await;
+        check(&InMemoryWalletRepository::new(wallets)).await;
+    }
+}
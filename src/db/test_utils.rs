@@ -0,0 +1,64 @@
+//! Databases for tests.
+
+use diesel::connection::SimpleConnection;
+use diesel::r2d2::{self, CustomizeConnection, Pool};
+
+use super::connection::{DbConnection, DbConnectionManager, SqlitePragmas};
+use super::manager::DBManager;
+use super::migrations::run_migrations;
+
+/// A migrated in-memory SQLite database.
+pub fn memory_db_manager() -> DBManager {
+    sqlite_db_manager(":memory:")
+}
+
+/// A migrated SQLite database at `uri`, a file path or `:memory:`.
+pub fn sqlite_db_manager(uri: &str) -> DBManager {
+    // in memory databases are per connection, so their pool must only ever hold one
+    let max_size = if uri == ":memory:" { 1 } else { 2 };
+    let pool = Pool::builder()
+        .max_size(max_size)
+        .connection_customizer(Box::new(SqlitePragmas::default()))
+        .build(DbConnectionManager::new(uri.parse().unwrap()))
+        .unwrap();
+    let db_manager = DBManager::new(pool);
+    run_migrations(&mut db_manager.connection().unwrap()).unwrap();
+    db_manager
+}
+
+/// Puts every connection the pool opens in one schema.
+#[derive(Debug)]
+struct SearchPath(String);
+
+impl CustomizeConnection<DbConnection, r2d2::Error> for SearchPath {
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), r2d2::Error> {
+        conn.batch_execute(&format!("SET search_path TO {}", self.0))
+            .map_err(r2d2::Error::QueryError)
+    }
+}
+
+/// Connects to the PostgreSQL database named by `POSTGRES_TEST_URL` and migrates a fresh schema
+/// of its own in it, so tests never touch anything else in that database. The schema is dropped
+/// first if an earlier run left it behind.
+///
+/// Tests using this are `#[ignore]`d, run them with `cargo test -- --ignored`.
+pub fn postgres_db_manager(schema: &str) -> DBManager {
+    let url = std::env::var("POSTGRES_TEST_URL")
+        .expect("POSTGRES_TEST_URL must name a PostgreSQL database to run this test against");
+    let manager = DbConnectionManager::new(url.parse().unwrap());
+
+    let mut conn = r2d2::ManageConnection::connect(&manager).unwrap();
+    conn.batch_execute(&format!(
+        "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema};"
+    ))
+    .unwrap();
+
+    let pool = Pool::builder()
+        .max_size(4)
+        .connection_customizer(Box::new(SearchPath(schema.to_owned())))
+        .build(manager)
+        .unwrap();
+    let db_manager = DBManager::new(pool);
+    run_migrations(&mut db_manager.connection().unwrap()).unwrap();
+    db_manager
+}
@@ -1,19 +1,49 @@
 use std::sync::Arc;
 
 use crate::db::manager::DBManager;
+use crate::db::repository::{
+    stock::{StockRepository, StockRepositoryImpl},
+    user::{UserRepository, UserRepositoryImpl},
+    wallet::{WalletRepository, WalletRepositoryImpl},
+};
 use crate::session::manager::SessionManager;
 
+/// The repositories the services go through, so tests can hand them the in-memory ones.
+#[derive(Debug, Clone)]
+pub struct Repositories {
+    pub users: Arc<dyn UserRepositoryImpl>,
+    pub stocks: Arc<dyn StockRepositoryImpl>,
+    pub wallets: Arc<dyn WalletRepositoryImpl>,
+}
+
+impl Repositories {
+    /// Repositories that read and write the tables of `db_manager`.
+    pub fn database(db_manager: &DBManager) -> Self {
+        Self {
+            users: Arc::new(UserRepository::new(db_manager.clone())),
+            stocks: Arc::new(StockRepository::new(db_manager.clone())),
+            wallets: Arc::new(WalletRepository::new(db_manager.clone())),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ServerDependencies {
     pub db_manager: Arc<DBManager>,
     pub session_manager: Arc<SessionManager>,
+    pub repositories: Repositories,
 }
 
 impl ServerDependencies {
-    pub fn new(db_manager: Arc<DBManager>, session_manager: Arc<SessionManager>) -> Self {
+    pub fn new(
+        db_manager: Arc<DBManager>,
+        session_manager: Arc<SessionManager>,
+        repositories: Repositories,
+    ) -> Self {
         Self {
             db_manager,
             session_manager,
+            repositories,
         }
     }
 }
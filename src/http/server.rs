@@ -2,13 +2,23 @@ use rust_models::common;
 use std::sync::Arc;
 
 use common::authorization_service_server::AuthorizationServiceServer;
+use common::session_service_server::SessionServiceServer;
 use common::trade_service_server::TradeServiceServer;
 use std::net::SocketAddr;
-use tonic::{Request, Status};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Context, Poll, Service};
+use tonic::server::NamedService;
+use tonic::Status;
 
 use crate::{
-    services::{auth::AuthService, trading::TradeServiceImpl},
-    session::manager::{SessionManager, SessionManagerImpl, SessionToken},
+    db::repository::user::UserRepositoryImpl,
+    services::{
+        auth::AuthService, session::SessionServiceImpl, session_store_unavailable,
+        trading::TradeServiceImpl,
+    },
+    session::manager::{
+        Session, SessionManager, SessionManagerImpl, SessionToken, SESSION_CLEANUP_INTERVAL,
+    },
 };
 
 use super::dependencies::ServerDependencies;
@@ -22,7 +32,10 @@ pub struct Server {
 impl Server {
     pub async fn new(addr: SocketAddr, dependencies: ServerDependencies) -> Self {
         let auth_service = AuthService::new(dependencies.clone());
-        let trade_service = TradeServiceImpl::new(dependencies.clone());
+        let session_service = SessionServiceImpl::new(dependencies.clone());
+        let trade_service = TradeServiceImpl::new(dependencies.clone())
+            .await
+            .expect("Failed to load markets");
 
         let service = tonic_reflection::server::Builder::configure()
             .register_encoded_file_descriptor_set(common::FILE_DESCRIPTOR_SET)
@@ -30,10 +43,24 @@ impl Server {
             .expect("Failed to create tonic reflecion");
 
         let session_manager = dependencies.session_manager;
-        let auth_interceptor =
-            { move |request: Request<()>| verify_auth(request, session_manager.clone()) };
+        let users = dependencies.repositories.users;
+        tokio::spawn(
+            session_manager
+                .clone()
+                .watch_expired(SESSION_CLEANUP_INTERVAL),
+        );
+
         let auth_server = AuthorizationServiceServer::new(auth_service);
-        let trade_server = TradeServiceServer::with_interceptor(trade_service, auth_interceptor);
+        let trade_server = Authenticated::new(
+            TradeServiceServer::new(trade_service),
+            session_manager.clone(),
+            users.clone(),
+        );
+        let session_server = Authenticated::new(
+            SessionServiceServer::new(session_service),
+            session_manager,
+            users,
+        );
 
         let handle = tokio::task::spawn({
             async move {
@@ -41,6 +68,7 @@ impl Server {
                     .add_service(service)
                     .add_service(auth_server)
                     .add_service(trade_server)
+                    .add_service(session_server)
                     .serve(addr)
                     .await
                     .expect("Failed to create server!");
@@ -53,20 +81,258 @@ impl Server {
     }
 }
 
-fn verify_auth(
-    mut req: Request<()>,
+/// Passes a request on to `inner` only if it carries the access token of a valid session of a
+/// user who isn't disabled, and attaches that session to the request for the service to read.
+///
+/// This does what a tonic interceptor would, but interceptors are synchronous and looking the
+/// session up may have to wait for the database.
+#[derive(Debug, Clone)]
+struct Authenticated<S> {
+    inner: S,
     session_manager: Arc<SessionManager>,
-) -> Result<Request<()>, Status> {
-    let token = req
-        .metadata()
-        .get("Auth")
+    users: Arc<dyn UserRepositoryImpl>,
+}
+
+impl<S> Authenticated<S> {
+    fn new(
+        inner: S,
+        session_manager: Arc<SessionManager>,
+        users: Arc<dyn UserRepositoryImpl>,
+    ) -> Self {
+        Self {
+            inner,
+            session_manager,
+            users,
+        }
+    }
+}
+
+impl<S, B> Service<http::Request<B>> for Authenticated<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        // only the service that was polled is known to be ready, so that one handles the request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let session_manager = self.session_manager.clone();
+        let users = self.users.clone();
+
+        Box::pin(async move {
+            match verify_auth(request.headers(), &session_manager, users.as_ref()).await {
+                Ok(session) => {
+                    request.extensions_mut().insert(session);
+                    inner.call(request).await
+                }
+                Err(status) => Ok(status.into_http()),
+            }
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for Authenticated<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+/// The session whose access token is in the `auth` header, as long as it hasn't expired.
+///
+/// The user is read again on every request, since a session carries the user as they were at
+/// login. The session of a user who has been disabled or deleted since is ended.
+async fn verify_auth(
+    headers: &http::HeaderMap,
+    session_manager: &SessionManager,
+    users: &dyn UserRepositoryImpl,
+) -> Result<Session, Status> {
+    let token = headers
+        .get("auth")
         .and_then(|md| md.to_str().ok())
-        .ok_or_else(|| tonic::Status::unknown("Header is missing `Auth` field with token"))?;
+        .ok_or_else(|| Status::unauthenticated("Header is missing `auth` field with token"))?;
 
     let session = session_manager
         .get_session(SessionToken::from(token.to_owned()))
-        .ok_or_else(|| tonic::Status::not_found("Invalid token"))?;
+        .await
+        .map_err(|e| session_store_unavailable(&e))?
+        .ok_or_else(|| Status::unauthenticated("Invalid token"))?;
+    let user = session_manager
+        .validate_session(session.clone())
+        .await
+        .map_err(|e| session_store_unavailable(&e))?;
+    let Some(user) = user else {
+        return Err(Status::unauthenticated("Session has expired"));
+    };
+
+    let active = match user.id {
+        Some(id) => users
+            .find_by_id(id)
+            .await
+            .map_err(|e| Status::unavailable(format!("Failed to look up the user: {e:#}")))?
+            .is_some_and(|user| !user.disabled),
+        None => false,
+    };
+    if !active {
+        session_manager
+            .end_session(&session.token_hash)
+            .await
+            .map_err(|e| session_store_unavailable(&e))?;
+        return Err(Status::unauthenticated("Account is disabled"));
+    }
+
+    Ok(session)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::user::User;
+    use crate::db::repository::user::InMemoryUserRepository;
+    use crate::session::manager::{ClientInfo, IssuedSession, SessionConfig};
+
+    const EMAIL: &str = "ada@example.com";
+
+    /// A repository holding the one user tests log in as.
+    async fn users() -> Arc<InMemoryUserRepository> {
+        let users = Arc::new(InMemoryUserRepository::default());
+        let user = User {
+            id: None,
+            email: EMAIL.to_owned(),
+            password: "hash".to_owned(),
+            first_name: "Ada".to_owned(),
+            last_name: "Lovelace".to_owned(),
+            disabled: false,
+        };
+        users.create(user).await.unwrap();
+        users
+    }
+
+    async fn login(session_manager: &SessionManager, users: &InMemoryUserRepository) -> String {
+        let user = users.find_by_email(EMAIL).await.unwrap().unwrap();
+        let session: IssuedSession = session_manager
+            .new_session(user, ClientInfo::default())
+            .await
+            .unwrap();
+        common::Token::from(session).token
+    }
+
+    fn auth_headers(token: &str) -> http::HeaderMap {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("auth", token.parse().unwrap());
+        headers
+    }
+
+    /// Answers with the id of the user whose session was attached to the request.
+    #[derive(Clone)]
+    struct Whoami;
+
+    impl Service<http::Request<()>> for Whoami {
+        type Response = http::Response<BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<()>) -> Self::Future {
+            let user_id = request.extensions().get::<Session>().unwrap().user.id;
+            let mut response = http::Response::new(tonic::codegen::empty_body());
+            response
+                .headers_mut()
+                .insert("user-id", user_id.unwrap().into());
+            Box::pin(async move { Ok(response) })
+        }
+    }
 
-    req.extensions_mut().insert(session);
-    Ok(req)
+    #[tokio::test]
+    async fn test_verify_auth_rejects_unauthenticated_requests() {
+        let users = users().await;
+        let session_manager = SessionManager::default();
+        let token = login(&session_manager, &users).await;
+        assert!(
+            verify_auth(&auth_headers(&token), &session_manager, users.as_ref())
+                .await
+                .is_ok()
+        );
+
+        let status = verify_auth(&http::HeaderMap::new(), &session_manager, users.as_ref())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let status = verify_auth(
+            &auth_headers("not-a-token"),
+            &session_manager,
+            users.as_ref(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let session_manager = SessionManager::new(SessionConfig {
+            token_timeout: chrono::Duration::zero(),
+            ..Default::default()
+        });
+        let token = login(&session_manager, &users).await;
+        let status = verify_auth(&auth_headers(&token), &session_manager, users.as_ref())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_users_are_logged_out() {
+        let users = users().await;
+        let session_manager = SessionManager::default();
+        let token = login(&session_manager, &users).await;
+        assert!(
+            verify_auth(&auth_headers(&token), &session_manager, users.as_ref())
+                .await
+                .is_ok()
+        );
+
+        users.set_disabled(EMAIL, true).await.unwrap();
+        let status = verify_auth(&auth_headers(&token), &session_manager, users.as_ref())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert!(session_manager
+            .get_session(SessionToken::from(token.clone()))
+            .await
+            .unwrap()
+            .is_none());
+
+        // the session stays ended once the user is enabled again
+        users.set_disabled(EMAIL, false).await.unwrap();
+        let status = verify_auth(&auth_headers(&token), &session_manager, users.as_ref())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn test_only_authenticated_requests_reach_the_service() {
+        let users = users().await;
+        let session_manager = Arc::new(SessionManager::default());
+        let token = login(&session_manager, &users).await;
+        let mut service = Authenticated::new(Whoami, session_manager, users);
+
+        let mut request = http::Request::new(());
+        *request.headers_mut() = auth_headers(&token);
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.headers()["user-id"], "1");
+
+        let response = service.call(http::Request::new(())).await.unwrap();
+        assert!(!response.headers().contains_key("user-id"));
+        let status = Status::from_header_map(response.headers()).unwrap();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
 }
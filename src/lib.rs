@@ -1,9 +1,9 @@
+pub mod admin;
 pub mod db;
 pub mod http;
+pub mod money;
 pub mod session;
 
 pub(crate) mod passwords;
 pub(crate) mod services;
-//TODO: DELETE THIS AFTER TRADING IS IMPLEMENTED!
-#[allow(unused)]
 pub(crate) mod trading;
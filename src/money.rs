@@ -0,0 +1,223 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::BigInt;
+
+/// Number of decimal places an `Amount` keeps.
+pub const DECIMAL_PLACES: u32 = 8;
+
+const MINOR_UNITS_PER_UNIT: i64 = 10i64.pow(DECIMAL_PLACES);
+
+/// A fixed-point decimal used for money, prices and quantities.
+///
+/// Amounts are stored as a whole number of minor units (10^-8 of a unit) so that adding up
+/// balances never accumulates floating point error. They are written to the database as a plain
+/// integer. All arithmetic is checked and returns `None` on overflow.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = BigInt)]
+pub struct Amount(i64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_minor_units(minor_units: i64) -> Self {
+        Self(minor_units)
+    }
+
+    pub const fn minor_units(&self) -> i64 {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn checked_add(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_add(rhs.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_sub(rhs.0).map(Amount)
+    }
+
+    pub fn checked_neg(self) -> Option<Amount> {
+        self.0.checked_neg().map(Amount)
+    }
+
+    /// Multiplies two amounts, e.g. a price by a quantity, rounding half away from zero to the
+    /// nearest minor unit.
+    pub fn checked_mul(self, rhs: Amount) -> Option<Amount> {
+        let product = (self.0 as i128).checked_mul(rhs.0 as i128)?;
+        let scale = MINOR_UNITS_PER_UNIT as i128;
+        let half = scale / 2;
+        let rounded = if product >= 0 {
+            (product + half) / scale
+        } else {
+            (product - half) / scale
+        };
+        i64::try_from(rounded).ok().map(Amount)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let units = self.0.unsigned_abs() / MINOR_UNITS_PER_UNIT as u64;
+        let fraction = self.0.unsigned_abs() % MINOR_UNITS_PER_UNIT as u64;
+        if fraction == 0 {
+            return write!(f, "{sign}{units}");
+        }
+
+        let fraction = format!("{fraction:0width$}", width = DECIMAL_PLACES as usize);
+        write!(f, "{sign}{units}.{}", fraction.trim_end_matches('0'))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("{s:?} is not a valid amount");
+
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let (units, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if units.is_empty() && fraction.is_empty() {
+            return Err(invalid());
+        }
+        if !units
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        if fraction.len() > DECIMAL_PLACES as usize {
+            return Err(anyhow!(
+                "{s:?} has more than {DECIMAL_PLACES} decimal places"
+            ));
+        }
+
+        let units = if units.is_empty() {
+            0
+        } else {
+            units.parse::<i64>().map_err(|_| invalid())?
+        };
+        let fraction = format!("{fraction:0<width$}", width = DECIMAL_PLACES as usize)
+            .parse::<i64>()
+            .map_err(|_| invalid())?;
+
+        let minor_units = units
+            .checked_mul(MINOR_UNITS_PER_UNIT)
+            .and_then(|u| u.checked_add(fraction))
+            .ok_or_else(|| anyhow!("{s:?} is too large"))?;
+
+        Ok(Amount(if negative { -minor_units } else { minor_units }))
+    }
+}
+
+/// Converts a floating point amount from the proto models, rounding to the nearest minor unit.
+impl TryFrom<f64> for Amount {
+    type Error = anyhow::Error;
+
+    fn try_from(val: f64) -> Result<Self> {
+        let minor_units = (val * MINOR_UNITS_PER_UNIT as f64).round();
+        if !minor_units.is_finite() || minor_units.abs() >= i64::MAX as f64 {
+            return Err(anyhow!("{val} is not a representable amount"));
+        }
+        Ok(Amount(minor_units as i64))
+    }
+}
+
+impl From<Amount> for f64 {
+    fn from(val: Amount) -> Self {
+        val.0 as f64 / MINOR_UNITS_PER_UNIT as f64
+    }
+}
+
+impl<DB> ToSql<BigInt, DB> for Amount
+where
+    DB: Backend,
+    i64: ToSql<BigInt, DB>,
+{
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, DB>) -> serialize::Result {
+        <i64 as ToSql<BigInt, DB>>::to_sql(&self.0, out)
+    }
+}
+
+impl<DB> FromSql<BigInt, DB> for Amount
+where
+    DB: Backend,
+    i64: FromSql<BigInt, DB>,
+{
+    fn from_sql(bytes: DB::RawValue<'_>) -> deserialize::Result<Self> {
+        <i64 as FromSql<BigInt, DB>>::from_sql(bytes).map(Amount)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn amount(s: &str) -> Amount {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(amount("12.5").minor_units(), 1_250_000_000);
+        assert_eq!(amount("-0.00000001").minor_units(), -1);
+        assert_eq!(amount(".5"), amount("0.5"));
+        assert_eq!(amount("12.50").to_string(), "12.5");
+        assert_eq!(amount("7").to_string(), "7");
+        assert_eq!(amount("-0.25").to_string(), "-0.25");
+
+        assert!("1.000000001".parse::<Amount>().is_err());
+        assert!("abc".parse::<Amount>().is_err());
+        assert!("".parse::<Amount>().is_err());
+        assert!("1.2.3".parse::<Amount>().is_err());
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        assert_eq!(
+            amount("0.1").checked_add(amount("0.2")),
+            Some(amount("0.3"))
+        );
+        assert_eq!(amount("1").checked_sub(amount("1.5")), Some(amount("-0.5")));
+        assert_eq!(
+            amount("10.25").checked_mul(amount("3")),
+            Some(amount("30.75"))
+        );
+        // 0.00000001 * 0.5 rounds up to the smallest representable amount
+        assert_eq!(
+            amount("0.00000001").checked_mul(amount("0.5")),
+            Some(amount("0.00000001"))
+        );
+
+        let max = Amount::from_minor_units(i64::MAX);
+        assert_eq!(max.checked_add(amount("0.00000001")), None);
+        assert_eq!(max.checked_mul(amount("2")), None);
+    }
+
+    #[test]
+    fn test_f64_conversion() {
+        assert_eq!(Amount::try_from(0.1).unwrap(), amount("0.1"));
+        assert_eq!(f64::from(amount("1234.5678")), 1234.5678);
+        assert!(Amount::try_from(f64::NAN).is_err());
+        assert!(Amount::try_from(f64::INFINITY).is_err());
+    }
+}
@@ -1,28 +1,32 @@
+use std::sync::Arc;
+
 use rust_models::common::{
     authorization_service_server::AuthorizationService, CreateUserRequest, CreateUserResponse,
-    LoginUserRequest, LoginUserResponse,
+    LoginUserRequest, LoginUserResponse, RefreshTokenRequest, RefreshTokenResponse,
 };
 
 use tonic::Request;
 
 use crate::{
-    db::{
-        manager::DatabaseImpl,
-        models::user::{self, UserBuilder},
-    },
+    db::{error::DbError, models::user::UserBuilder, repository::user::UserRepositoryImpl},
     http::dependencies::ServerDependencies,
     passwords::Password,
-    session::manager::SessionManagerImpl,
+    services::session_store_unavailable,
+    session::manager::{ClientInfo, RefreshError, SessionManager, SessionManagerImpl},
 };
 
 #[derive(Debug)]
 pub struct AuthService {
-    server_deps: ServerDependencies,
+    users: Arc<dyn UserRepositoryImpl>,
+    session_manager: Arc<SessionManager>,
 }
 
 impl AuthService {
     pub fn new(server_deps: ServerDependencies) -> Self {
-        Self { server_deps }
+        Self {
+            users: server_deps.repositories.users,
+            session_manager: server_deps.session_manager,
+        }
     }
 }
 
@@ -48,14 +52,17 @@ impl AuthorizationService for AuthService {
             .build()
         {
             Ok(user) => {
-                let user_write_result = self
-                    .server_deps
-                    .db_manager
-                    .insert_row(user::schema::users::table, &user)
-                    .map_err(|e| tonic::Status::internal(format!("Server Error: {e}")))?;
+                let user = self.users.create(user).await.map_err(|e| {
+                    match e.downcast_ref::<DbError>() {
+                        Some(DbError::UniqueViolation(_)) => tonic::Status::already_exists(
+                            "A user with that email already exists".to_owned(),
+                        ),
+                        _ => tonic::Status::internal(format!("Server Error: {e:#}")),
+                    }
+                })?;
                 Ok(tonic::Response::new(CreateUserResponse {
                     status: 1,
-                    message: format!("{:#?}", user_write_result),
+                    message: format!("Created user {}", user.email),
                 }))
             }
             Err(e) => Ok(tonic::Response::new(CreateUserResponse {
@@ -69,16 +76,16 @@ impl AuthorizationService for AuthService {
         &self,
         request: Request<LoginUserRequest>,
     ) -> Result<tonic::Response<LoginUserResponse>, tonic::Status> {
+        let client = ClientInfo::from(&request);
         let request = request.get_ref();
 
-        let user: Vec<crate::db::models::user::User> = self
-            .server_deps
-            .db_manager
-            .query_rows(user::schema::users::table, vec![("email", &request.email)])
+        let user = self
+            .users
+            .find_by_email(&request.email)
             .await
             .map_err(|e| tonic::Status::internal(format!("Server Error: {e:#}")))?;
 
-        if let Some(user) = user.first() {
+        if let Some(user) = user {
             if !user.verify_password(&request.password).map_err(|e| {
                 tonic::Status::invalid_argument(format!("Interal Error occured {e}"))
             })? {
@@ -86,16 +93,19 @@ impl AuthorizationService for AuthService {
                     "Invalid Password".to_owned(),
                 ));
             }
+            if user.disabled {
+                return Err(tonic::Status::permission_denied(
+                    "This account has been disabled".to_owned(),
+                ));
+            }
 
             let mut proto_user = rust_models::common::User::from(user.clone());
 
             proto_user.token = Some(rust_models::common::Token::from(
-                self.server_deps
-                    .session_manager
-                    .new_session(user.clone())
-                    .ok_or_else(|| {
-                        tonic::Status::not_found("Invalid token during generation".to_string())
-                    })?,
+                self.session_manager
+                    .new_session(user, client)
+                    .await
+                    .map_err(|e| session_store_unavailable(&e))?,
             ));
 
             Ok(tonic::Response::new(LoginUserResponse {
@@ -106,4 +116,155 @@ impl AuthorizationService for AuthService {
             Err(tonic::Status::internal("No user found".to_string()))
         }
     }
+
+    async fn refresh_token(
+        &self,
+        request: Request<RefreshTokenRequest>,
+    ) -> Result<tonic::Response<RefreshTokenResponse>, tonic::Status> {
+        let issued = self
+            .session_manager
+            .refresh_session(request.into_inner().refresh_token)
+            .await
+            .map_err(|e| match e {
+                RefreshError::Internal(_) => tonic::Status::unavailable(e.to_string()),
+                _ => tonic::Status::unauthenticated(e.to_string()),
+            })?;
+
+        // the account may have been disabled since the user logged in
+        let user_id = issued.session.user.id.unwrap_or_default();
+        let user = self
+            .users
+            .find_by_id(user_id)
+            .await
+            .map_err(|e| tonic::Status::internal(format!("Server Error: {e:#}")))?;
+        if user.is_none_or(|user| user.disabled) {
+            self.session_manager
+                .end_session(&issued.session.token_hash)
+                .await
+                .map_err(|e| session_store_unavailable(&e))?;
+            return Err(tonic::Status::permission_denied(
+                "This account has been disabled".to_owned(),
+            ));
+        }
+
+        Ok(tonic::Response::new(RefreshTokenResponse {
+            token: Some(rust_models::common::Token::from(issued)),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::user::InMemoryUserRepository;
+
+    fn auth_service() -> AuthService {
+        AuthService {
+            users: Arc::new(InMemoryUserRepository::default()),
+            session_manager: Arc::new(SessionManager::default()),
+        }
+    }
+
+    fn create_request(email: &str) -> Request<CreateUserRequest> {
+        Request::new(CreateUserRequest {
+            email: email.to_owned(),
+            password: "hunter2".to_owned(),
+            first_name: "Ada".to_owned(),
+            last_name: "Lovelace".to_owned(),
+        })
+    }
+
+    fn login_request(email: &str, password: &str) -> Request<LoginUserRequest> {
+        Request::new(LoginUserRequest {
+            email: email.to_owned(),
+            password: password.to_owned(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_create_user_and_login() {
+        let service = auth_service();
+        service
+            .create_user(create_request("ada@example.com"))
+            .await
+            .unwrap();
+        let status = service
+            .create_user(create_request("ada@example.com"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+
+        let response = service
+            .login_user(login_request("ada@example.com", "hunter2"))
+            .await
+            .unwrap()
+            .into_inner();
+        let token = response.user.unwrap().token.unwrap().token;
+        assert!(service
+            .session_manager
+            .get_session(token)
+            .await
+            .unwrap()
+            .is_some());
+
+        let status = service
+            .login_user(login_request("ada@example.com", "wrong"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token() {
+        let service = auth_service();
+        service
+            .create_user(create_request("ada@example.com"))
+            .await
+            .unwrap();
+        let token = service
+            .login_user(login_request("ada@example.com", "hunter2"))
+            .await
+            .unwrap()
+            .into_inner()
+            .user
+            .unwrap()
+            .token
+            .unwrap();
+
+        let refresh = |refresh_token: String| {
+            service.refresh_token(Request::new(RefreshTokenRequest { refresh_token }))
+        };
+        let refreshed = refresh(token.refresh_token.clone())
+            .await
+            .unwrap()
+            .into_inner()
+            .token
+            .unwrap();
+        assert_ne!(refreshed.token, token.token);
+
+        let status = refresh(token.refresh_token).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let status = refresh(refreshed.refresh_token).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_user_cannot_log_in() {
+        let service = auth_service();
+        service
+            .create_user(create_request("ada@example.com"))
+            .await
+            .unwrap();
+        service
+            .users
+            .set_disabled("ada@example.com", true)
+            .await
+            .unwrap();
+
+        let status = service
+            .login_user(login_request("ada@example.com", "hunter2"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
 }
@@ -1,2 +1,10 @@
 pub(crate) mod auth;
+pub(crate) mod session;
 pub(crate) mod trading;
+
+use tonic::Status;
+
+/// Reports a session store that couldn't be reached, which the client can retry later.
+pub(crate) fn session_store_unavailable(e: &anyhow::Error) -> Status {
+    Status::unavailable(format!("Session store error: {e:#}"))
+}
@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use rust_models::common::{
+    session_service_server::SessionService, ListSessionsRequest, ListSessionsResponse,
+    LogoutRequest, LogoutResponse, RevokeOtherSessionsRequest, RevokeOtherSessionsResponse,
+};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    http::dependencies::ServerDependencies,
+    services::session_store_unavailable,
+    session::manager::{Session, SessionManager, SessionManagerImpl},
+};
+
+/// Lets a logged in user see and end their own sessions.
+#[derive(Debug)]
+pub struct SessionServiceImpl {
+    session_manager: Arc<SessionManager>,
+}
+
+impl SessionServiceImpl {
+    pub fn new(dependencies: ServerDependencies) -> Self {
+        Self {
+            session_manager: dependencies.session_manager,
+        }
+    }
+}
+
+/// The session the auth interceptor attached to the request.
+#[allow(clippy::result_large_err)]
+fn current_session<T>(request: &Request<T>) -> Result<Session, Status> {
+    request
+        .extensions()
+        .get::<Session>()
+        .cloned()
+        .ok_or_else(|| Status::unauthenticated("Session not found"))
+}
+
+#[allow(clippy::result_large_err)]
+fn session_user_id(session: &Session) -> Result<i32, Status> {
+    session
+        .user
+        .id
+        .ok_or_else(|| Status::unauthenticated("Session not found"))
+}
+
+#[tonic::async_trait]
+impl SessionService for SessionServiceImpl {
+    async fn logout(
+        &self,
+        request: Request<LogoutRequest>,
+    ) -> Result<Response<LogoutResponse>, Status> {
+        let session = current_session(&request)?;
+        self.session_manager
+            .end_session(&session.token_hash)
+            .await
+            .map_err(|e| session_store_unavailable(&e))?;
+        Ok(Response::new(LogoutResponse {}))
+    }
+
+    async fn list_sessions(
+        &self,
+        request: Request<ListSessionsRequest>,
+    ) -> Result<Response<ListSessionsResponse>, Status> {
+        let session = current_session(&request)?;
+        let sessions = self
+            .session_manager
+            .user_sessions(session_user_id(&session)?)
+            .await
+            .map_err(|e| session_store_unavailable(&e))?
+            .iter()
+            .map(|s| s.info(&session.token_hash))
+            .collect();
+        Ok(Response::new(ListSessionsResponse { sessions }))
+    }
+
+    async fn revoke_other_sessions(
+        &self,
+        request: Request<RevokeOtherSessionsRequest>,
+    ) -> Result<Response<RevokeOtherSessionsResponse>, Status> {
+        let session = current_session(&request)?;
+        let revoked = self
+            .session_manager
+            .end_other_sessions(session_user_id(&session)?, &session.token_hash)
+            .await
+            .map_err(|e| session_store_unavailable(&e))?;
+        Ok(Response::new(RevokeOtherSessionsResponse {
+            revoked: revoked as i32,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::user::User;
+    use crate::session::manager::ClientInfo;
+
+    fn authed<T>(message: T, session: &Session) -> Request<T> {
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(session.clone());
+        request
+    }
+
+    #[tokio::test]
+    async fn test_list_revoke_and_logout() {
+        let service = SessionServiceImpl {
+            session_manager: Arc::new(SessionManager::default()),
+        };
+        let user = User {
+            id: Some(1),
+            email: "ada@example.com".to_owned(),
+            password: "hash".to_owned(),
+            first_name: "Ada".to_owned(),
+            last_name: "Lovelace".to_owned(),
+            disabled: false,
+        };
+        let manager = &service.session_manager;
+        let session = manager
+            .new_session(user.clone(), ClientInfo::default())
+            .await
+            .unwrap();
+        let phone = manager
+            .new_session(user.clone(), ClientInfo::default())
+            .await
+            .unwrap();
+
+        let sessions = service
+            .list_sessions(authed(ListSessionsRequest {}, &session.session))
+            .await
+            .unwrap()
+            .into_inner()
+            .sessions;
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions.iter().filter(|s| s.current).count(), 1);
+
+        let revoked = service
+            .revoke_other_sessions(authed(RevokeOtherSessionsRequest {}, &session.session))
+            .await
+            .unwrap()
+            .into_inner()
+            .revoked;
+        assert_eq!(revoked, 1);
+        assert!(manager.get_session(phone.token).await.unwrap().is_none());
+
+        service
+            .logout(authed(LogoutRequest {}, &session.session))
+            .await
+            .unwrap();
+        assert!(manager.get_session(session.token).await.unwrap().is_none());
+
+        let status = service
+            .logout(Request::new(LogoutRequest {}))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+}
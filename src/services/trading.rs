@@ -1,47 +1,132 @@
 use rust_models::common::{
     create_trade_response::CreateTradeStatus, trade_service_server::*, CreateTradeRequest,
     CreateTradeResponse, DeleteTradeRequest, DeleteTradeResponse, GetTradeRequest,
-    GetTradeResponse, TradeId,
+    GetTradeResponse, TradeFill, TradeId, TradeStatus,
 };
+use std::sync::Arc;
+
+use anyhow::Result;
 use tonic::Status;
 
 use crate::{
-    http::dependencies::ServerDependencies, session::manager::Session,
-    trading::backend::TradeBackend,
+    db::error::DbError,
+    http::dependencies::ServerDependencies,
+    session::manager::Session,
+    trading::{
+        backend::{TradeBackend, MARKET_SYNC_INTERVAL},
+        error::TradeError,
+        order::{Order, OrderRequest},
+    },
 };
 
 #[derive(Debug)]
 pub struct TradeServiceImpl {
     _dependencies: ServerDependencies,
-    _trade_backend: TradeBackend,
+    trade_backend: Arc<TradeBackend>,
 }
 
 impl TradeServiceImpl {
-    pub fn new(dependencies: ServerDependencies) -> Self {
-        let trade_backend = TradeBackend::new(&dependencies.db_manager);
-        Self {
+    pub async fn new(dependencies: ServerDependencies) -> Result<Self> {
+        let trade_backend = Arc::new(
+            TradeBackend::new(
+                dependencies.db_manager.clone(),
+                dependencies.repositories.stocks.clone(),
+            )
+            .await?,
+        );
+
+        tokio::spawn(trade_backend.clone().watch_markets(MARKET_SYNC_INTERVAL));
+
+        Ok(Self {
             _dependencies: dependencies,
-            _trade_backend: trade_backend,
+            trade_backend,
+        })
+    }
+
+    /// Looks up an order by the id in the request, refusing to return orders that belong to
+    /// anyone other than the caller.
+    async fn owned_order(
+        &self,
+        user_id: i32,
+        trade_id: Option<TradeId>,
+    ) -> Result<Order, tonic::Status> {
+        let trade_id = trade_id
+            .ok_or_else(|| Status::invalid_argument("Missing trade id"))?
+            .trade_id;
+
+        let order = self
+            .trade_backend
+            .order(trade_id)
+            .await
+            .map_err(|e| server_error(&e))?
+            .ok_or_else(|| Status::not_found(format!("No trade found with id {trade_id}")))?;
+
+        if order.user_id != user_id {
+            return Err(Status::permission_denied(
+                "Trade belongs to a different user",
+            ));
         }
+
+        Ok(order)
     }
 }
 
+/// Reports a failure the backend ran into, telling a request the database's constraints refused
+/// apart from the server failing.
+fn server_error(e: &anyhow::Error) -> Status {
+    match e.downcast_ref::<DbError>() {
+        Some(DbError::ForeignKeyViolation(_)) | Some(DbError::CheckViolation(_)) => {
+            Status::failed_precondition(format!("{e:#}"))
+        }
+        Some(DbError::UniqueViolation(_)) => Status::already_exists(format!("{e:#}")),
+        _ => Status::internal(format!("Server Error: {e:#}")),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn session_user_id<T>(request: &tonic::Request<T>) -> Result<i32, tonic::Status> {
+    request
+        .extensions()
+        .get::<Session>()
+        .and_then(|session| session.user.id)
+        .ok_or_else(|| Status::unauthenticated("Session not found"))
+}
+
 #[tonic::async_trait]
 impl TradeService for TradeServiceImpl {
     async fn create_trade(
         &self,
         request: tonic::Request<CreateTradeRequest>,
     ) -> Result<tonic::Response<CreateTradeResponse>, tonic::Status> {
-        let _session = request
-            .extensions()
-            .get::<Session>()
-            .ok_or_else(|| Status::unauthenticated("Session not found"))?;
+        let user_id = session_user_id(&request)?;
+
+        let trade_request = request
+            .into_inner()
+            .trade_request
+            .ok_or_else(|| Status::invalid_argument("Missing trade request"))?;
+
+        let order_request = OrderRequest::try_from(trade_request.clone())
+            .map_err(|e| Status::invalid_argument(format!("{e:#}")))?;
+
+        let execution = self
+            .trade_backend
+            .place_order(user_id, order_request)
+            .await
+            .map_err(|e| match e {
+                TradeError::UnknownMarket(_) | TradeError::UnknownStock(_) => {
+                    Status::not_found(e.to_string())
+                }
+                TradeError::InsufficientFunds { .. } => Status::failed_precondition(e.to_string()),
+                TradeError::Overdrawn { .. } => Status::internal(e.to_string()),
+                TradeError::Internal(e) => server_error(&e),
+            })?;
 
-        let create_trade_request = request.into_inner().trade_request;
         let response = CreateTradeResponse {
             status: CreateTradeStatus::Ok.into(),
-            trade_id: Some(TradeId { trade_id: 1 }),
-            trade_request: create_trade_request,
+            trade_id: Some(TradeId {
+                trade_id: execution.order.id,
+            }),
+            trade_request: Some(trade_request),
         };
 
         Ok(tonic::Response::new(response))
@@ -49,15 +134,60 @@ impl TradeService for TradeServiceImpl {
 
     async fn get_trade(
         &self,
-        _request: tonic::Request<GetTradeRequest>,
+        request: tonic::Request<GetTradeRequest>,
     ) -> Result<tonic::Response<GetTradeResponse>, tonic::Status> {
-        unimplemented!("Not yet implemeneted")
+        let user_id = session_user_id(&request)?;
+        let order = self
+            .owned_order(user_id, request.into_inner().trade_id)
+            .await?;
+
+        let fills = self
+            .trade_backend
+            .fills(order.id)
+            .await
+            .map_err(|e| server_error(&e))?;
+
+        let response = GetTradeResponse {
+            trade_id: Some(TradeId { trade_id: order.id }),
+            trade_request: Some((&order).into()),
+            status: TradeStatus::from(order.status).into(),
+            filled_quantity: order.filled().into(),
+            remaining_quantity: order.remaining.into(),
+            fills: fills.iter().map(TradeFill::from).collect(),
+        };
+
+        Ok(tonic::Response::new(response))
     }
 
     async fn delete_trade(
         &self,
-        _request: tonic::Request<DeleteTradeRequest>,
+        request: tonic::Request<DeleteTradeRequest>,
     ) -> Result<tonic::Response<DeleteTradeResponse>, tonic::Status> {
-        unimplemented!("Not yet implemeneted")
+        let user_id = session_user_id(&request)?;
+        let order = self
+            .owned_order(user_id, request.into_inner().trade_id)
+            .await?;
+
+        let cancelled = self
+            .trade_backend
+            .cancel_order(&order.swap_pair, order.id)
+            .await
+            .map_err(|e| server_error(&e))?
+            .ok_or_else(|| {
+                Status::failed_precondition(format!(
+                    "Trade {} is {} and can no longer be cancelled",
+                    order.id,
+                    order.status.as_str()
+                ))
+            })?;
+
+        let response = DeleteTradeResponse {
+            trade_id: Some(TradeId {
+                trade_id: cancelled.id,
+            }),
+            status: TradeStatus::from(cancelled.status).into(),
+        };
+
+        Ok(tonic::Response::new(response))
     }
 }
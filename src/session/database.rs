@@ -0,0 +1,583 @@
+//! Sessions kept in the database, so they survive restarts and are shared by every server that
+//! uses the same database.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+
+use super::manager::{
+    get_time, ClientInfo, IssuedSession, RefreshError, RefreshToken, Session, SessionConfig,
+    SessionManagerImpl, SessionToken, TokenHash,
+};
+use crate::db::filter::Filter;
+use crate::db::manager::{db_error, DBManager, DatabaseImpl, Transaction};
+use crate::db::models::session::{
+    schema::{sessions, used_refresh_tokens},
+    StoredSession, UsedRefreshToken,
+};
+use crate::db::models::user::{schema::users, User};
+
+/// How long a cached session is trusted before it is read from the database again. A session
+/// ended by another server keeps working on this one for at most this long.
+const CACHE_TTL: Duration = Duration::seconds(5);
+
+#[derive(Debug, Clone)]
+struct CachedSession {
+    session: Session,
+    cached_at: DateTime<Utc>,
+}
+
+/// What a refresh did inside its transaction.
+enum Refreshed {
+    Issued {
+        old: TokenHash,
+        issued: Box<IssuedSession>,
+    },
+    Unknown,
+    Expired(TokenHash),
+    Reused(u64),
+}
+
+/// Keeps sessions in the `sessions` table with a write-through cache in front of it.
+///
+/// Queries run on the database's blocking pool through [`DBManager::run`]. Most lookups are
+/// answered from the cache.
+#[derive(Debug)]
+pub struct DbSessionManager {
+    config: SessionConfig,
+    db_manager: DBManager,
+    cache: RwLock<HashMap<TokenHash, CachedSession>>,
+}
+
+impl DbSessionManager {
+    pub fn new(config: SessionConfig, db_manager: DBManager) -> Self {
+        Self {
+            config,
+            db_manager,
+            cache: Default::default(),
+        }
+    }
+
+    /// Runs `f` against the database without blocking the calling task.
+    async fn run<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&DBManager) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        // the mock clock is per thread, so the test's time has to go along to the one f runs on
+        #[cfg(test)]
+        let f = {
+            let now = super::manager::test_utils::get_mock_time();
+            move |db: &DBManager| {
+                super::manager::test_utils::set_mock_time(now);
+                f(db)
+            }
+        };
+        self.db_manager.run(f).await
+    }
+
+    fn cache(&self, session: &Session) {
+        self.cache.write().unwrap().insert(
+            session.token_hash,
+            CachedSession {
+                session: session.clone(),
+                cached_at: get_time(),
+            },
+        );
+    }
+
+    fn cached(&self, token_hash: &TokenHash) -> Option<Session> {
+        let cache = self.cache.read().unwrap();
+        let cached = cache.get(token_hash)?;
+        (get_time() < cached.cached_at + CACHE_TTL).then(|| cached.session.clone())
+    }
+
+    /// Reads a session from the table and caches it.
+    async fn load(&self, token_hash: TokenHash) -> Result<Option<Session>> {
+        let session = self
+            .run(move |db| {
+                let row = db.with_connection(|conn| {
+                    sessions::table
+                        .find(token_hash.to_hex())
+                        .first::<StoredSession>(conn)
+                        .optional()
+                        .map_err(|e| anyhow!("Failed to load session: {e:#?}"))
+                })?;
+                let Some(row) = row else {
+                    return Ok(None);
+                };
+                match load_user(db, row.user_id)? {
+                    Some(user) => from_row(row, user).map(Some),
+                    None => Ok(None),
+                }
+            })
+            .await?;
+
+        match &session {
+            Some(session) => self.cache(session),
+            None => {
+                self.cache.write().unwrap().remove(&token_hash);
+            }
+        }
+        Ok(session)
+    }
+}
+
+#[async_trait]
+impl SessionManagerImpl for DbSessionManager {
+    async fn new_session(&self, user: User, client: ClientInfo) -> Result<IssuedSession> {
+        if user.id.is_none() {
+            return Err(anyhow!("Can't start a session for a user without an id"));
+        }
+        let issued = Session::issue(user, client, &self.config, new_family()?)?;
+        let row = to_row(&issued.session)?;
+        self.run(move |db| db.insert_row(sessions::table, &row))
+            .await?;
+        self.cache(&issued.session);
+        Ok(issued)
+    }
+
+    async fn get_session(&self, token: impl Into<SessionToken> + Send) -> Result<Option<Session>> {
+        let token_hash = token.into().hash();
+        let mut session = match self.cached(&token_hash) {
+            Some(session) => session,
+            None => match self.load(token_hash).await? {
+                Some(session) => session,
+                None => return Ok(None),
+            },
+        };
+
+        if self.config.sliding_expiry && session.is_valid() {
+            let expire_time = session.slid_expire_time(self.config.token_timeout);
+            if expire_time > session.expire_time {
+                let updated = self
+                    .run(move |db| {
+                        db.update_rows(
+                            sessions::table,
+                            vec![Filter::eq(sessions::token_hash, token_hash.to_hex())],
+                            sessions::expire_time.eq(expire_time.naive_utc()),
+                        )
+                    })
+                    .await?;
+                // ended on another server since it was cached
+                if updated == 0 {
+                    self.cache.write().unwrap().remove(&token_hash);
+                    return Ok(None);
+                }
+                session.expire_time = expire_time;
+                self.cache(&session);
+            }
+        }
+        Ok(Some(session))
+    }
+
+    async fn validate_session(&self, session: Session) -> Result<Option<User>> {
+        if session.is_valid() {
+            return Ok(Some(session.user));
+        }
+        // an expired session is kept for as long as it can still be refreshed
+        if !session.can_refresh() {
+            self.end_session(&session.token_hash).await?;
+        }
+        Ok(None)
+    }
+
+    async fn refresh_session(
+        &self,
+        refresh_token: impl Into<RefreshToken> + Send,
+    ) -> Result<IssuedSession, RefreshError> {
+        let refresh_token_hash = refresh_token.into().hash().to_hex();
+        let config = self.config;
+        let refreshed = self
+            .run(move |db| db.transaction(|tx| refresh(tx, &config, &refresh_token_hash)))
+            .await
+            .map_err(|e| RefreshError::Internal(format!("{e:#}")))?;
+
+        let mut cache = self.cache.write().unwrap();
+        match refreshed {
+            Refreshed::Issued { old, issued } => {
+                cache.remove(&old);
+                drop(cache);
+                self.cache(&issued.session);
+                Ok(*issued)
+            }
+            Refreshed::Unknown => Err(RefreshError::Unknown),
+            Refreshed::Expired(old) => {
+                cache.remove(&old);
+                Err(RefreshError::Expired)
+            }
+            Refreshed::Reused(family) => {
+                cache.retain(|_, cached| cached.session.family != family);
+                Err(RefreshError::Reused)
+            }
+        }
+    }
+
+    async fn end_session(&self, token_hash: &TokenHash) -> Result<Option<Session>> {
+        let session = match self.cached(token_hash) {
+            Some(session) => Some(session),
+            None => self.load(*token_hash).await?,
+        };
+        let hex = token_hash.to_hex();
+        let deleted = self
+            .run(move |db| {
+                db.delete_rows(sessions::table, vec![Filter::eq(sessions::token_hash, hex)])
+            })
+            .await;
+        self.cache.write().unwrap().remove(token_hash);
+        Ok(match deleted? {
+            0 => None,
+            _ => session,
+        })
+    }
+
+    async fn user_sessions(&self, user_id: i32) -> Result<Vec<Session>> {
+        let sessions = self
+            .run(move |db| {
+                let rows = db.with_connection(|conn| {
+                    sessions::table
+                        .filter(sessions::user_id.eq(user_id))
+                        .order(sessions::create_time.asc())
+                        .load::<StoredSession>(conn)
+                        .map_err(|e| anyhow!("Failed to load sessions: {e:#?}"))
+                })?;
+                let Some(user) = load_user(db, user_id)? else {
+                    return Ok(vec![]);
+                };
+                rows.into_iter()
+                    .map(|row| from_row(row, user.clone()))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .await?;
+
+        Ok(sessions
+            .into_iter()
+            .filter(|session| session.is_valid() || session.can_refresh())
+            .collect())
+    }
+
+    async fn end_other_sessions(&self, user_id: i32, keep: &TokenHash) -> Result<usize> {
+        let keep_hex = keep.to_hex();
+        let deleted = self
+            .run(move |db| {
+                db.delete_rows(
+                    sessions::table,
+                    vec![
+                        Filter::eq(sessions::user_id, user_id),
+                        Filter::ne(sessions::token_hash, keep_hex),
+                    ],
+                )
+            })
+            .await;
+        self.cache.write().unwrap().retain(|token_hash, cached| {
+            cached.session.user.id != Some(user_id) || token_hash == keep
+        });
+        deleted
+    }
+
+    async fn cleanup(&self) -> Result<()> {
+        let now = get_time();
+        self.cache
+            .write()
+            .unwrap()
+            .retain(|_, cached| now < cached.cached_at + CACHE_TTL);
+
+        let now = now.naive_utc();
+        self.run(move |db| {
+            db.with_connection(|conn| {
+                diesel::delete(
+                    sessions::table.filter(
+                        sessions::expire_time
+                            .le(now)
+                            .and(sessions::refresh_expire_time.le(now)),
+                    ),
+                )
+                .execute(conn)
+                .and_then(|_| {
+                    diesel::delete(
+                        used_refresh_tokens::table.filter(used_refresh_tokens::expire_time.le(now)),
+                    )
+                    .execute(conn)
+                })
+                .map_err(|e| anyhow!("Failed to delete expired sessions: {e:#?}"))
+            })
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+/// Trades the refresh token with the given hash for a new session. The old session is only
+/// ended once the new one has been issued.
+fn refresh(
+    tx: &Transaction,
+    config: &SessionConfig,
+    refresh_token_hash: &str,
+) -> Result<Refreshed> {
+    let used = tx.with_connection(|conn| {
+        used_refresh_tokens::table
+            .find(refresh_token_hash)
+            .first::<UsedRefreshToken>(conn)
+            .optional()
+            .map_err(db_error("Load used refresh token"))
+    })?;
+    if let Some(used) = used {
+        return end_family(tx, used.family);
+    }
+
+    let row = tx.with_connection(|conn| {
+        sessions::table
+            .filter(sessions::refresh_token_hash.eq(refresh_token_hash))
+            .first::<StoredSession>(conn)
+            .optional()
+            .map_err(db_error("Load session"))
+    })?;
+    let Some(row) = row else {
+        return Ok(Refreshed::Unknown);
+    };
+    let Some(user) = load_user(tx, row.user_id)? else {
+        return Ok(Refreshed::Unknown);
+    };
+    let old = from_row(row, user)?;
+    let old_filter = || vec![Filter::eq(sessions::token_hash, old.token_hash.to_hex())];
+    if !old.can_refresh() {
+        tx.delete_rows(sessions::table, old_filter())?;
+        return Ok(Refreshed::Expired(old.token_hash));
+    }
+
+    let issued = Session::issue(old.user.clone(), old.client.clone(), config, old.family)?;
+    // another server traded the same token in at the same time
+    if tx.delete_rows(sessions::table, old_filter())? == 0 {
+        return end_family(tx, old.family as i64);
+    }
+    tx.insert_row(
+        used_refresh_tokens::table,
+        &UsedRefreshToken {
+            token_hash: refresh_token_hash.to_owned(),
+            family: old.family as i64,
+            expire_time: old.refresh_expire_time.naive_utc(),
+        },
+    )?;
+    tx.insert_row(sessions::table, &to_row(&issued.session)?)?;
+    Ok(Refreshed::Issued {
+        old: old.token_hash,
+        issued: Box::new(issued),
+    })
+}
+
+fn end_family(tx: &Transaction, family: i64) -> Result<Refreshed> {
+    tx.delete_rows(sessions::table, vec![Filter::eq(sessions::family, family)])?;
+    Ok(Refreshed::Reused(family as u64))
+}
+
+fn load_user(db: &impl DatabaseImpl, user_id: i32) -> Result<Option<User>> {
+    db.with_connection(|conn| {
+        users::table
+            .filter(users::id.eq(user_id))
+            .first::<User>(conn)
+            .optional()
+            .map_err(db_error("Load user"))
+    })
+}
+
+/// Families only have to be unique, and random ones need no coordination between servers.
+fn new_family() -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("Failed to generate a family: {e}"))?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn to_row(session: &Session) -> Result<StoredSession> {
+    Ok(StoredSession {
+        token_hash: session.token_hash.to_hex(),
+        refresh_token_hash: session.refresh_token_hash.to_hex(),
+        user_id: session
+            .user
+            .id
+            .ok_or_else(|| anyhow!("Session belongs to a user without an id"))?,
+        family: session.family as i64,
+        user_agent: session.client.user_agent.clone(),
+        address: session.client.address.clone(),
+        create_time: session.create_time.naive_utc(),
+        expire_time: session.expire_time.naive_utc(),
+        refresh_expire_time: session.refresh_expire_time.naive_utc(),
+    })
+}
+
+fn from_row(row: StoredSession, user: User) -> Result<Session> {
+    let hash = |hex: &str| {
+        TokenHash::from_hex(hex).ok_or_else(|| anyhow!("Stored session has a malformed hash"))
+    };
+    Ok(Session {
+        token_hash: hash(&row.token_hash)?,
+        expire_time: row.expire_time.and_utc(),
+        create_time: row.create_time.and_utc(),
+        user,
+        refresh_token_hash: hash(&row.refresh_token_hash)?,
+        refresh_expire_time: row.refresh_expire_time.and_utc(),
+        client: ClientInfo {
+            user_agent: row.user_agent,
+            address: row.address,
+        },
+        family: row.family as u64,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::user::UserBuilder;
+    use crate::db::test_utils::memory_db_manager;
+    use crate::session::manager::test_utils;
+
+    fn db_manager() -> (DBManager, User) {
+        let db_manager = memory_db_manager();
+        let user = UserBuilder::default()
+            .id(None)
+            .email("ada@example.com".to_owned())
+            .password("hash".to_owned())
+            .first_name("Ada".to_owned())
+            .last_name("Lovelace".to_owned())
+            .build()
+            .unwrap();
+        db_manager.insert_row(users::table, &user).unwrap();
+        let user = load_user(&db_manager, 1).unwrap().unwrap();
+        (db_manager, user)
+    }
+
+    fn advance(seconds: i64) {
+        test_utils::set_mock_time(test_utils::get_mock_time() + seconds);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_shared_between_servers() {
+        let (db_manager, user) = db_manager();
+        let one = DbSessionManager::new(SessionConfig::default(), db_manager.clone());
+        let two = DbSessionManager::new(SessionConfig::default(), db_manager);
+
+        let client = ClientInfo {
+            user_agent: Some("laptop".to_owned()),
+            address: Some("127.0.0.1".to_owned()),
+        };
+        let issued = one.new_session(user.clone(), client.clone()).await.unwrap();
+        let session = two
+            .get_session(issued.token.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.user, user);
+        assert_eq!(session.client, client);
+        assert_eq!(session.token_hash, issued.session.token_hash);
+
+        two.end_session(&session.token_hash).await.unwrap().unwrap();
+        // the first server trusts its cache for a little while
+        assert!(one
+            .get_session(issued.token.clone())
+            .await
+            .unwrap()
+            .is_some());
+        advance(CACHE_TTL.num_seconds());
+        assert!(one.get_session(issued.token).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_reuse_is_caught_by_another_server() {
+        let (db_manager, user) = db_manager();
+        let one = DbSessionManager::new(SessionConfig::default(), db_manager.clone());
+        let two = DbSessionManager::new(SessionConfig::default(), db_manager);
+
+        let issued = one.new_session(user, ClientInfo::default()).await.unwrap();
+        let refreshed = one
+            .refresh_session(issued.refresh_token.clone())
+            .await
+            .unwrap();
+        assert!(two
+            .get_session(refreshed.token.clone())
+            .await
+            .unwrap()
+            .is_some());
+        assert!(two.get_session(issued.token).await.unwrap().is_none());
+
+        assert_eq!(
+            two.refresh_session(issued.refresh_token).await.unwrap_err(),
+            RefreshError::Reused
+        );
+        assert!(two.get_session(refreshed.token).await.unwrap().is_none());
+        assert_eq!(
+            one.refresh_session(refreshed.refresh_token)
+                .await
+                .unwrap_err(),
+            RefreshError::Unknown
+        );
+    }
+
+    #[tokio::test]
+    async fn test_user_sessions_and_cleanup() {
+        let (db_manager, user) = db_manager();
+        let manager = DbSessionManager::new(SessionConfig::default(), db_manager);
+
+        let current = manager
+            .new_session(user.clone(), ClientInfo::default())
+            .await
+            .unwrap();
+        advance(1);
+        manager
+            .new_session(user.clone(), ClientInfo::default())
+            .await
+            .unwrap();
+        let sessions = manager.user_sessions(1).await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].token_hash, current.session.token_hash);
+
+        assert_eq!(
+            manager
+                .end_other_sessions(1, &current.session.token_hash)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(manager.user_sessions(1).await.unwrap().len(), 1);
+
+        advance(SessionConfig::default().refresh_token_timeout.num_seconds());
+        manager.cleanup().await.unwrap();
+        assert!(manager.user_sessions(1).await.unwrap().is_empty());
+        let rows = manager
+            .db_manager
+            .with_connection(|conn| {
+                sessions::table
+                    .count()
+                    .get_result::<i64>(conn)
+                    .map_err(|e| anyhow!("{e}"))
+            })
+            .unwrap();
+        assert_eq!(rows, 0);
+    }
+
+    #[tokio::test]
+    async fn test_store_errors_are_returned() {
+        let (db_manager, user) = db_manager();
+        let manager = DbSessionManager::new(SessionConfig::default(), db_manager.clone());
+        let issued = manager
+            .new_session(user, ClientInfo::default())
+            .await
+            .unwrap();
+        db_manager
+            .with_connection(|conn| {
+                diesel::sql_query("DROP TABLE sessions")
+                    .execute(conn)
+                    .map_err(|e| anyhow!("{e}"))
+            })
+            .unwrap();
+
+        // answered from the cache until it goes stale
+        assert!(manager.get_session(issued.token.clone()).await.is_ok());
+        advance(CACHE_TTL.num_seconds());
+        assert!(manager.get_session(issued.token).await.is_err());
+        assert!(manager.user_sessions(1).await.is_err());
+        assert!(manager.cleanup().await.is_err());
+    }
+}
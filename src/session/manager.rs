@@ -1,84 +1,235 @@
-use bcrypt::hash;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use chrono::{DateTime, Duration, Utc};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::sync::{Arc, RwLock};
+use subtle::ConstantTimeEq;
 
 use prost_types::Timestamp;
 
+use super::database::DbSessionManager;
+use crate::db::manager::DBManager;
 use crate::db::models::user::User;
 
 const DEFAULT_TOKEN_TIMEOUT_DURATION: Duration = Duration::seconds(300);
+const DEFAULT_REFRESH_TOKEN_TIMEOUT_DURATION: Duration = Duration::days(1);
+/// How often sessions that can no longer be used or refreshed are dropped.
+pub const SESSION_CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+/// Random bytes in every token.
+const TOKEN_BYTES: usize = 32;
+
+/// How long sessions last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionConfig {
+    /// How long an access token is accepted for.
+    pub token_timeout: Duration,
+    /// How long a refresh token can be traded for a new session.
+    pub refresh_token_timeout: Duration,
+    /// Pushes a session's expiry back to `token_timeout` from now every time it is used, but
+    /// never past the expiry of its refresh token.
+    pub sliding_expiry: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            token_timeout: DEFAULT_TOKEN_TIMEOUT_DURATION,
+            refresh_token_timeout: DEFAULT_REFRESH_TOKEN_TIMEOUT_DURATION,
+            sliding_expiry: false,
+        }
+    }
+}
+
+/// Where a session was started from, so users can tell their sessions apart.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub address: Option<String>,
+}
 
+impl<T> From<&tonic::Request<T>> for ClientInfo {
+    fn from(request: &tonic::Request<T>) -> Self {
+        Self {
+            user_agent: request
+                .metadata()
+                .get("user-agent")
+                .and_then(|agent| agent.to_str().ok())
+                .map(str::to_owned),
+            address: request.remote_addr().map(|addr| addr.ip().to_string()),
+        }
+    }
+}
+
+/// A session as the server stores it. Only the hashes of its tokens are kept, the tokens
+/// themselves are handed to the client once in an [`IssuedSession`].
 #[derive(Debug, Clone)]
 #[allow(unused)]
 pub struct Session {
-    pub token: SessionToken,
+    pub token_hash: TokenHash,
     pub expire_time: DateTime<Utc>,
     pub create_time: DateTime<Utc>,
     pub user: User,
+    pub refresh_token_hash: TokenHash,
+    pub refresh_expire_time: DateTime<Utc>,
+    pub client: ClientInfo,
+    // every session refreshed from the same login shares a family, so reusing one of their
+    // refresh tokens can end all of them
+    pub(super) family: u64,
+}
+
+/// A newly created session along with the tokens for the client.
+#[derive(Debug, Clone)]
+pub struct IssuedSession {
+    pub session: Session,
+    pub token: SessionToken,
+    pub refresh_token: RefreshToken,
 }
 
 impl Session {
-    fn new(user: User, user_id: i32, expire_duration: Duration) -> Self {
-        let token = SessionToken::new(user_id);
+    pub(super) fn issue(
+        user: User,
+        client: ClientInfo,
+        config: &SessionConfig,
+        family: u64,
+    ) -> Result<IssuedSession> {
+        let token = SessionToken(generate_token()?);
+        let refresh_token = RefreshToken(generate_token()?);
         let create_time = get_time();
-        let expire_time = create_time + expire_duration;
-        Self {
-            token,
-            user: user.clone(),
+        let expire_time = create_time + config.token_timeout;
+        let refresh_expire_time = create_time + config.refresh_token_timeout;
+        let session = Self {
+            token_hash: token.hash(),
+            user,
             expire_time,
             create_time,
-        }
+            refresh_token_hash: refresh_token.hash(),
+            refresh_expire_time,
+            client,
+            family,
+        };
+        Ok(IssuedSession {
+            session,
+            token,
+            refresh_token,
+        })
     }
 
-    fn is_valid(&self) -> bool {
+    pub(super) fn is_valid(&self) -> bool {
         let now = get_time();
         now < self.expire_time
     }
+
+    pub(super) fn can_refresh(&self) -> bool {
+        get_time() < self.refresh_expire_time
+    }
+
+    /// The expiry a use of the session slides it to, capped so that a session in constant use
+    /// still ends when its refresh token does.
+    pub(super) fn slid_expire_time(&self, token_timeout: Duration) -> DateTime<Utc> {
+        let slid = (get_time() + token_timeout).min(self.refresh_expire_time);
+        self.expire_time.max(slid)
+    }
 }
 
-impl From<Session> for rust_models::common::Token {
-    fn from(val: Session) -> Self {
-        let expire_ts: Option<Timestamp> = Some(Timestamp {
-            seconds: val.expire_time.timestamp(),
-            nanos: val.expire_time.timestamp_subsec_nanos() as i32,
-        });
-        let create_ts: Option<Timestamp> = Some(Timestamp {
-            seconds: val.create_time.timestamp(),
-            nanos: val.create_time.timestamp_subsec_nanos() as i32,
-        });
+fn timestamp(time: DateTime<Utc>) -> Option<Timestamp> {
+    Some(Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.timestamp_subsec_nanos() as i32,
+    })
+}
+
+impl Session {
+    /// Describes the session for its owner. `current` marks the session making the request.
+    pub fn info(&self, current: &TokenHash) -> rust_models::common::SessionInfo {
+        rust_models::common::SessionInfo {
+            create_ts: timestamp(self.create_time),
+            expire_ts: timestamp(self.expire_time),
+            refresh_expire_ts: timestamp(self.refresh_expire_time),
+            user_agent: self.client.user_agent.clone().unwrap_or_default(),
+            address: self.client.address.clone().unwrap_or_default(),
+            current: &self.token_hash == current,
+        }
+    }
+}
+
+impl From<IssuedSession> for rust_models::common::Token {
+    fn from(val: IssuedSession) -> Self {
         rust_models::common::Token {
-            create_ts,
-            expire_ts,
+            create_ts: timestamp(val.session.create_time),
+            expire_ts: timestamp(val.session.expire_time),
             token: val.token.0,
+            refresh_token: val.refresh_token.0,
+            refresh_expire_ts: timestamp(val.session.refresh_expire_time),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Hex encodes bytes from the operating system's CSPRNG.
+fn generate_token() -> Result<String> {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("Failed to generate a token: {e}"))?;
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// SHA-256 of a token, which is all the server keeps of it. Comparisons run in constant time.
+#[derive(Clone, Copy)]
+pub struct TokenHash([u8; 32]);
+
+impl TokenHash {
+    fn of(token: &str) -> Self {
+        Self(Sha256::digest(token.as_bytes()).into())
+    }
+
+    pub(super) fn to_hex(self) -> String {
+        self.0.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    pub(super) fn from_hex(hex: &str) -> Option<Self> {
+        let mut bytes = [0u8; 32];
+        if hex.len() != bytes.len() * 2 {
+            return None;
+        }
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+        Some(Self(bytes))
+    }
+}
+
+impl PartialEq for TokenHash {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Eq for TokenHash {}
+
+impl Hash for TokenHash {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl fmt::Debug for TokenHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TokenHash(")?;
+        self.0[..4]
+            .iter()
+            .try_for_each(|byte| write!(f, "{byte:02x}"))?;
+        write!(f, "..)")
+    }
+}
+
+/// An access token as presented by a client.
+#[derive(Clone)]
 pub struct SessionToken(String);
 
 impl SessionToken {
-    fn new(user_id: i32) -> Self {
-        // Get the current timestamp
-        let now = get_time();
-        let timestamp = now.to_rfc3339();
-
-        // Concatenate the user ID and timestamp
-        let input = format!("{}:{}", user_id, timestamp);
-
-        // Hash the input using bcrypt
-        // 4 is the lowest cost of the hash function making this faster
-        // Since we're generating a sesion token it's ok for this to be
-        // not as fast as a password hash. Changing this value to default cost
-        // can take up to 600ms instead of 5ms.
-        match hash(input, 4) {
-            Ok(hashed) => Self(hashed),
-            Err(e) => {
-                eprintln!("Failed to generate bcrypt hash: {}", e);
-                Self(String::new()) // Return an empty string on failure
-            }
-        }
+    pub fn hash(&self) -> TokenHash {
+        TokenHash::of(&self.0)
     }
 }
 
@@ -88,47 +239,401 @@ impl From<String> for SessionToken {
     }
 }
 
+impl fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SessionToken(..)")
+    }
+}
+
+/// Traded in for a new session once the access token has expired. Every refresh token can only
+/// be used once.
+#[derive(Clone)]
+pub struct RefreshToken(String);
+
+impl RefreshToken {
+    pub fn hash(&self) -> TokenHash {
+        TokenHash::of(&self.0)
+    }
+}
+
+impl From<String> for RefreshToken {
+    fn from(value: String) -> Self {
+        RefreshToken(value)
+    }
+}
+
+impl fmt::Debug for RefreshToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RefreshToken(..)")
+    }
+}
+
+/// Reasons a refresh token can't be traded for a new session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefreshError {
+    Unknown,
+    Expired,
+    /// The token was already used. Every session from the same login has been ended, since
+    /// someone else may hold a copy of it.
+    Reused,
+    /// The new tokens couldn't be generated or stored. The refresh token can be tried again.
+    Internal(String),
+}
+
+impl fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefreshError::Unknown => write!(f, "Invalid refresh token"),
+            RefreshError::Expired => write!(f, "Refresh token has expired"),
+            RefreshError::Reused => {
+                write!(f, "Refresh token was already used, please log in again")
+            }
+            RefreshError::Internal(cause) => write!(f, "Failed to issue a new token: {cause}"),
+        }
+    }
+}
+
+impl std::error::Error for RefreshError {}
+
+/// A store of sessions. Every method fails only when the store itself can't be reached.
+#[async_trait]
 pub trait SessionManagerImpl: Send + Sync {
-    fn new_session(&self, user: User) -> Option<Session>;
-    fn get_session(&self, token: impl Into<SessionToken>) -> Option<Session>;
-    fn validate_session(&self, session: Session) -> Option<User>;
-    fn cleanup(&self);
+    /// Fails if the user has no id or the tokens couldn't be generated.
+    async fn new_session(&self, user: User, client: ClientInfo) -> Result<IssuedSession>;
+    async fn get_session(&self, token: impl Into<SessionToken> + Send) -> Result<Option<Session>>;
+    async fn validate_session(&self, session: Session) -> Result<Option<User>>;
+    /// Trades a refresh token for a new session, ending the session it was issued with.
+    async fn refresh_session(
+        &self,
+        refresh_token: impl Into<RefreshToken> + Send,
+    ) -> Result<IssuedSession, RefreshError>;
+    async fn end_session(&self, token_hash: &TokenHash) -> Result<Option<Session>>;
+    /// Every session of the user that is still valid or can be refreshed, oldest first.
+    async fn user_sessions(&self, user_id: i32) -> Result<Vec<Session>>;
+    /// Ends every session of the user except `keep`, returning how many were ended.
+    async fn end_other_sessions(&self, user_id: i32, keep: &TokenHash) -> Result<usize>;
+    async fn cleanup(&self) -> Result<()>;
+}
+
+#[derive(Debug)]
+struct UsedRefreshToken {
+    family: u64,
+    expire_time: DateTime<Utc>,
 }
 
 #[derive(Debug, Default)]
-pub struct SessionManager {
-    sessions: Arc<RwLock<HashMap<SessionToken, Session>>>,
+struct Sessions {
+    by_token: HashMap<TokenHash, Session>,
+    by_refresh_token: HashMap<TokenHash, TokenHash>,
+    by_user: HashMap<i32, HashSet<TokenHash>>,
+    // kept until they would have expired anyway, so a second use can be told apart from a
+    // made up token
+    used_refresh_tokens: HashMap<TokenHash, UsedRefreshToken>,
+    next_family: u64,
 }
 
-impl SessionManagerImpl for SessionManager {
-    fn get_session(&self, token: impl Into<SessionToken>) -> Option<Session> {
-        self.sessions.read().unwrap().get(&token.into()).cloned()
+impl Sessions {
+    fn insert(&mut self, session: Session) {
+        self.by_refresh_token
+            .insert(session.refresh_token_hash, session.token_hash);
+        if let Some(user_id) = session.user.id {
+            self.by_user
+                .entry(user_id)
+                .or_default()
+                .insert(session.token_hash);
+        }
+        self.by_token.insert(session.token_hash, session);
     }
 
-    fn validate_session(&self, session: Session) -> Option<User> {
-        match session.is_valid().then_some(session.user) {
-            Some(user) => Some(user),
-            None => {
-                let mut guard = self.sessions.write().unwrap();
-                guard.remove(&session.token);
-                None
+    fn remove(&mut self, token_hash: &TokenHash) -> Option<Session> {
+        let session = self.by_token.remove(token_hash)?;
+        self.by_refresh_token.remove(&session.refresh_token_hash);
+        if let Some(user_id) = session.user.id {
+            if let Some(tokens) = self.by_user.get_mut(&user_id) {
+                tokens.remove(token_hash);
+                if tokens.is_empty() {
+                    self.by_user.remove(&user_id);
+                }
             }
         }
+        Some(session)
+    }
+
+    fn user_tokens(&self, user_id: i32) -> Vec<TokenHash> {
+        self.by_user
+            .get(&user_id)
+            .map(|tokens| tokens.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn remove_family(&mut self, family: u64) {
+        let tokens = self
+            .by_token
+            .values()
+            .filter(|session| session.family == family)
+            .map(|session| session.token_hash)
+            .collect::<Vec<_>>();
+        for token in tokens {
+            self.remove(&token);
+        }
+    }
+}
+
+/// Where sessions are kept, picked when the server starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SessionStore {
+    /// In this process only. Every restart logs everyone out.
+    Memory,
+    /// In the `sessions` table, shared by every server using the same database.
+    Database,
+}
+
+/// The session store the server runs with.
+#[derive(Debug)]
+pub enum SessionManager {
+    InMemory(InMemorySessionManager),
+    Database(DbSessionManager),
+}
+
+impl Default for SessionManager {
+    fn default() -> Self {
+        SessionManager::InMemory(InMemorySessionManager::default())
     }
+}
 
-    fn new_session(&self, user: User) -> Option<Session> {
-        let session = Session::new(user.clone(), user.id?, DEFAULT_TOKEN_TIMEOUT_DURATION);
+impl SessionManager {
+    /// Keeps sessions in memory.
+    pub fn new(config: SessionConfig) -> Self {
+        SessionManager::InMemory(InMemorySessionManager::new(config))
+    }
+
+    /// Keeps sessions in the database behind `db_manager`.
+    pub fn database(config: SessionConfig, db_manager: DBManager) -> Self {
+        SessionManager::Database(DbSessionManager::new(config, db_manager))
+    }
+
+    /// Calls [`SessionManagerImpl::cleanup`] every `period`, forever.
+    pub async fn watch_expired(self: Arc<Self>, period: std::time::Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.cleanup().await {
+                eprintln!("Failed to clean up sessions: {e:#}");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SessionManagerImpl for SessionManager {
+    async fn new_session(&self, user: User, client: ClientInfo) -> Result<IssuedSession> {
+        match self {
+            SessionManager::InMemory(manager) => manager.new_session(user, client).await,
+            SessionManager::Database(manager) => manager.new_session(user, client).await,
+        }
+    }
+
+    async fn get_session(&self, token: impl Into<SessionToken> + Send) -> Result<Option<Session>> {
+        match self {
+            SessionManager::InMemory(manager) => manager.get_session(token).await,
+            SessionManager::Database(manager) => manager.get_session(token).await,
+        }
+    }
+
+    async fn validate_session(&self, session: Session) -> Result<Option<User>> {
+        match self {
+            SessionManager::InMemory(manager) => manager.validate_session(session).await,
+            SessionManager::Database(manager) => manager.validate_session(session).await,
+        }
+    }
+
+    async fn refresh_session(
+        &self,
+        refresh_token: impl Into<RefreshToken> + Send,
+    ) -> Result<IssuedSession, RefreshError> {
+        match self {
+            SessionManager::InMemory(manager) => manager.refresh_session(refresh_token).await,
+            SessionManager::Database(manager) => manager.refresh_session(refresh_token).await,
+        }
+    }
+
+    async fn end_session(&self, token_hash: &TokenHash) -> Result<Option<Session>> {
+        match self {
+            SessionManager::InMemory(manager) => manager.end_session(token_hash).await,
+            SessionManager::Database(manager) => manager.end_session(token_hash).await,
+        }
+    }
+
+    async fn user_sessions(&self, user_id: i32) -> Result<Vec<Session>> {
+        match self {
+            SessionManager::InMemory(manager) => manager.user_sessions(user_id).await,
+            SessionManager::Database(manager) => manager.user_sessions(user_id).await,
+        }
+    }
+
+    async fn end_other_sessions(&self, user_id: i32, keep: &TokenHash) -> Result<usize> {
+        match self {
+            SessionManager::InMemory(manager) => manager.end_other_sessions(user_id, keep).await,
+            SessionManager::Database(manager) => manager.end_other_sessions(user_id, keep).await,
+        }
+    }
+
+    async fn cleanup(&self) -> Result<()> {
+        match self {
+            SessionManager::InMemory(manager) => manager.cleanup().await,
+            SessionManager::Database(manager) => manager.cleanup().await,
+        }
+    }
+}
+
+/// Keeps sessions in this process's memory.
+#[derive(Debug, Default)]
+pub struct InMemorySessionManager {
+    config: SessionConfig,
+    sessions: Arc<RwLock<Sessions>>,
+}
+
+impl InMemorySessionManager {
+    pub fn new(config: SessionConfig) -> Self {
+        Self {
+            config,
+            sessions: Default::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl SessionManagerImpl for InMemorySessionManager {
+    async fn get_session(&self, token: impl Into<SessionToken> + Send) -> Result<Option<Session>> {
+        let token_hash = token.into().hash();
+        if !self.config.sliding_expiry {
+            return Ok(self
+                .sessions
+                .read()
+                .unwrap()
+                .by_token
+                .get(&token_hash)
+                .cloned());
+        }
 
         let mut sessions = self.sessions.write().unwrap();
-        sessions.insert(session.token.clone(), session.clone());
-        Some(session)
+        let Some(session) = sessions.by_token.get_mut(&token_hash) else {
+            return Ok(None);
+        };
+        if session.is_valid() {
+            session.expire_time = session.slid_expire_time(self.config.token_timeout);
+        }
+        Ok(Some(session.clone()))
     }
 
-    fn cleanup(&self) {
-        self.sessions
-            .write()
-            .unwrap()
-            .retain(|_, session| session.is_valid());
+    async fn validate_session(&self, session: Session) -> Result<Option<User>> {
+        if session.is_valid() {
+            return Ok(Some(session.user));
+        }
+        // an expired session is kept for as long as it can still be refreshed
+        if !session.can_refresh() {
+            self.sessions.write().unwrap().remove(&session.token_hash);
+        }
+        Ok(None)
+    }
+
+    async fn new_session(&self, user: User, client: ClientInfo) -> Result<IssuedSession> {
+        if user.id.is_none() {
+            return Err(anyhow!("Can't start a session for a user without an id"));
+        }
+        let mut sessions = self.sessions.write().unwrap();
+        let issued = Session::issue(user, client, &self.config, sessions.next_family)?;
+        sessions.next_family += 1;
+        sessions.insert(issued.session.clone());
+        Ok(issued)
+    }
+
+    async fn refresh_session(
+        &self,
+        refresh_token: impl Into<RefreshToken> + Send,
+    ) -> Result<IssuedSession, RefreshError> {
+        let refresh_token_hash = refresh_token.into().hash();
+        let mut sessions = self.sessions.write().unwrap();
+
+        if let Some(used) = sessions.used_refresh_tokens.get(&refresh_token_hash) {
+            let family = used.family;
+            sessions.remove_family(family);
+            return Err(RefreshError::Reused);
+        }
+
+        let token_hash = *sessions
+            .by_refresh_token
+            .get(&refresh_token_hash)
+            .ok_or(RefreshError::Unknown)?;
+        let old = sessions
+            .by_token
+            .get(&token_hash)
+            .cloned()
+            .ok_or(RefreshError::Unknown)?;
+        if !old.can_refresh() {
+            sessions.remove(&token_hash);
+            return Err(RefreshError::Expired);
+        }
+        // nothing changes until the new tokens exist, so a failure here leaves the old session
+        // usable
+        let issued = Session::issue(old.user, old.client, &self.config, old.family)
+            .map_err(|e| RefreshError::Internal(format!("{e:#}")))?;
+
+        sessions.remove(&token_hash);
+        sessions.used_refresh_tokens.insert(
+            refresh_token_hash,
+            UsedRefreshToken {
+                family: old.family,
+                expire_time: old.refresh_expire_time,
+            },
+        );
+        sessions.insert(issued.session.clone());
+        Ok(issued)
+    }
+
+    async fn end_session(&self, token_hash: &TokenHash) -> Result<Option<Session>> {
+        Ok(self.sessions.write().unwrap().remove(token_hash))
+    }
+
+    async fn user_sessions(&self, user_id: i32) -> Result<Vec<Session>> {
+        let sessions = self.sessions.read().unwrap();
+        let mut user_sessions = sessions
+            .user_tokens(user_id)
+            .iter()
+            .filter_map(|token_hash| sessions.by_token.get(token_hash))
+            .filter(|session| session.is_valid() || session.can_refresh())
+            .cloned()
+            .collect::<Vec<_>>();
+        user_sessions.sort_by_key(|session| session.create_time);
+        Ok(user_sessions)
+    }
+
+    async fn end_other_sessions(&self, user_id: i32, keep: &TokenHash) -> Result<usize> {
+        let mut sessions = self.sessions.write().unwrap();
+        Ok(sessions
+            .user_tokens(user_id)
+            .into_iter()
+            .filter(|token_hash| token_hash != keep)
+            .filter_map(|token_hash| sessions.remove(&token_hash))
+            .count())
+    }
+
+    async fn cleanup(&self) -> Result<()> {
+        let now = get_time();
+        let mut sessions = self.sessions.write().unwrap();
+        let expired = sessions
+            .by_token
+            .values()
+            .filter(|session| !session.is_valid() && !session.can_refresh())
+            .map(|session| session.token_hash)
+            .collect::<Vec<_>>();
+        for token_hash in expired {
+            sessions.remove(&token_hash);
+        }
+        sessions
+            .used_refresh_tokens
+            .retain(|_, used| now < used.expire_time);
+        Ok(())
     }
 }
 
@@ -136,7 +641,7 @@ impl SessionManagerImpl for SessionManager {
 /// enabled to accelerate time for Utc. Since Utc is not compatible with tokio::Instant this is
 /// necessary to mock the chrono timestamps. If testing is enabled then this will always return the
 /// same time
-fn get_time() -> DateTime<Utc> {
+pub(super) fn get_time() -> DateTime<Utc> {
     #[cfg(not(test))]
     {
         Utc::now()
@@ -149,17 +654,21 @@ fn get_time() -> DateTime<Utc> {
 }
 
 #[cfg(test)]
-mod test_utils {
-    use std::sync::atomic::{AtomicI64, Ordering};
-    // Atomic clock mock
-    static MOCK_TIME: AtomicI64 = AtomicI64::new(0);
+pub(super) mod test_utils {
+    use std::cell::Cell;
+
+    // Clock mock. Every test runs on its own thread, so one test moving the clock doesn't
+    // expire sessions in another.
+    thread_local! {
+        static MOCK_TIME: Cell<i64> = const { Cell::new(0) };
+    }
 
     pub fn set_mock_time(seconds: i64) {
-        MOCK_TIME.store(seconds, Ordering::SeqCst);
+        MOCK_TIME.with(|time| time.set(seconds));
     }
 
     pub fn get_mock_time() -> i64 {
-        MOCK_TIME.load(Ordering::SeqCst)
+        MOCK_TIME.with(|time| time.get())
     }
 }
 
@@ -174,6 +683,7 @@ mod test {
             password: "123".to_owned(),
             first_name: "bob".to_owned(),
             last_name: "bob".to_owned(),
+            disabled: false,
         }
     }
 
@@ -181,8 +691,13 @@ mod test {
     fn test_session_is_valid() {
         let user = fake_user();
 
-        let expire_duration = Duration::seconds(1);
-        let session = Session::new(user, 123, expire_duration);
+        let config = SessionConfig {
+            token_timeout: Duration::seconds(1),
+            ..Default::default()
+        };
+        let session = Session::issue(user, ClientInfo::default(), &config, 0)
+            .unwrap()
+            .session;
 
         assert!(session.is_valid());
 
@@ -190,63 +705,255 @@ mod test {
         assert!(!session.is_valid());
     }
 
-    #[test]
-    fn test_new_session() {
-        let manager = SessionManager::default();
+    #[tokio::test]
+    async fn test_new_session() {
+        let manager = InMemorySessionManager::default();
         let user = fake_user();
 
         let session = manager
-            .new_session(user.clone())
+            .new_session(user.clone(), ClientInfo::default())
+            .await
             .expect("Session should be created");
-        assert_eq!(session.user, user);
-        assert!(session.is_valid());
+        assert_eq!(session.session.user, user);
+        assert!(session.session.is_valid());
+        assert_eq!(session.token.0.len(), TOKEN_BYTES * 2);
+        // the store only knows the hash
+        assert_eq!(session.session.token_hash, session.token.hash());
     }
 
-    #[test]
-    fn test_get_session() {
-        let manager = SessionManager::default();
+    #[tokio::test]
+    async fn test_get_session() {
+        let manager = InMemorySessionManager::default();
         let user = fake_user();
         let session = manager
-            .new_session(user.clone())
+            .new_session(user.clone(), ClientInfo::default())
+            .await
             .expect("Session should be created");
 
         let retrieved = manager
             .get_session(session.token.clone())
+            .await
+            .unwrap()
             .expect("Session should exist");
         assert_eq!(retrieved.user, user);
     }
 
-    #[test]
-    fn test_validate_session() {
-        let manager = SessionManager::default();
+    #[tokio::test]
+    async fn test_validate_session() {
+        let manager = InMemorySessionManager::default();
         let user = fake_user();
         let session = manager
-            .new_session(user.clone())
+            .new_session(user.clone(), ClientInfo::default())
+            .await
             .expect("Session should be created");
 
         let validated_user = manager
-            .validate_session(session.clone())
+            .validate_session(session.session.clone())
+            .await
+            .unwrap()
             .expect("Session should be valid");
         assert_eq!(validated_user, user);
     }
 
-    #[test]
-    fn test_expired_session_cleanup() {
-        let manager = SessionManager::default();
+    #[tokio::test]
+    async fn test_expired_session_cleanup() {
+        let manager = InMemorySessionManager::default();
         let user = fake_user();
         test_utils::set_mock_time(Utc::now().timestamp());
         let session = manager
-            .new_session(user.clone())
+            .new_session(user.clone(), ClientInfo::default())
+            .await
             .expect("Session should be created");
 
         test_utils::set_mock_time(
             test_utils::get_mock_time() + DEFAULT_TOKEN_TIMEOUT_DURATION.num_seconds(),
         ); // Fast-forward time
-        manager.cleanup();
+        manager.cleanup().await.unwrap();
+        assert!(
+            manager
+                .get_session(session.token.clone())
+                .await
+                .unwrap()
+                .is_some(),
+            "Session should be kept while it can still be refreshed"
+        );
 
+        test_utils::set_mock_time(
+            test_utils::get_mock_time() + DEFAULT_REFRESH_TOKEN_TIMEOUT_DURATION.num_seconds(),
+        );
+        manager.cleanup().await.unwrap();
         assert!(
-            manager.get_session(session.token).is_none(),
+            manager.get_session(session.token).await.unwrap().is_none(),
             "Expired session should be removed"
         );
     }
+
+    #[tokio::test]
+    async fn test_refresh_rotates_tokens() {
+        let manager = InMemorySessionManager::default();
+        let session = manager
+            .new_session(fake_user(), ClientInfo::default())
+            .await
+            .unwrap();
+
+        let refreshed = manager
+            .refresh_session(session.refresh_token.clone())
+            .await
+            .unwrap();
+        assert_ne!(refreshed.token.0, session.token.0);
+        assert_ne!(refreshed.refresh_token.0, session.refresh_token.0);
+        assert!(manager
+            .get_session(session.token.clone())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(manager
+            .get_session(refreshed.token.clone())
+            .await
+            .unwrap()
+            .is_some());
+
+        assert_eq!(
+            manager
+                .refresh_session(RefreshToken::from("made up".to_owned()))
+                .await
+                .unwrap_err(),
+            RefreshError::Unknown
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_reuse_ends_every_session_of_the_login() {
+        let manager = InMemorySessionManager::default();
+        let other = manager
+            .new_session(fake_user(), ClientInfo::default())
+            .await
+            .unwrap();
+        let session = manager
+            .new_session(fake_user(), ClientInfo::default())
+            .await
+            .unwrap();
+        let refreshed = manager
+            .refresh_session(session.refresh_token.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            manager
+                .refresh_session(session.refresh_token)
+                .await
+                .unwrap_err(),
+            RefreshError::Reused
+        );
+        assert!(manager
+            .get_session(refreshed.token)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            manager
+                .refresh_session(refreshed.refresh_token)
+                .await
+                .unwrap_err(),
+            RefreshError::Unknown
+        );
+        // logins on other devices are left alone
+        assert!(manager.get_session(other.token).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_user_sessions() {
+        let manager = InMemorySessionManager::default();
+        let laptop = ClientInfo {
+            user_agent: Some("laptop".to_owned()),
+            address: None,
+        };
+        let current = manager
+            .new_session(fake_user(), laptop.clone())
+            .await
+            .unwrap();
+        test_utils::set_mock_time(test_utils::get_mock_time() + 1);
+        manager
+            .new_session(fake_user(), ClientInfo::default())
+            .await
+            .unwrap();
+        test_utils::set_mock_time(test_utils::get_mock_time() + 1);
+        let refreshed = manager
+            .refresh_session(
+                manager
+                    .new_session(fake_user(), ClientInfo::default())
+                    .await
+                    .unwrap()
+                    .refresh_token,
+            )
+            .await
+            .unwrap();
+        let mut someone_else = fake_user();
+        someone_else.id = Some(456);
+        let theirs = manager
+            .new_session(someone_else, ClientInfo::default())
+            .await
+            .unwrap();
+
+        let sessions = manager.user_sessions(123).await.unwrap();
+        assert_eq!(sessions.len(), 3);
+        assert_eq!(sessions[0].client, laptop);
+        assert!(sessions
+            .iter()
+            .any(|s| s.token_hash == refreshed.session.token_hash));
+
+        let current = current.session.token_hash;
+        assert_eq!(manager.end_other_sessions(123, &current).await.unwrap(), 2);
+        let sessions = manager.user_sessions(123).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0].info(&current).current);
+        assert!(manager.get_session(theirs.token).await.unwrap().is_some());
+
+        manager.end_session(&current).await.unwrap();
+        assert!(manager.user_sessions(123).await.unwrap().is_empty());
+        assert!(!manager.sessions.read().unwrap().by_user.contains_key(&123));
+    }
+
+    #[test]
+    fn test_token_hash() {
+        let token = SessionToken::from(generate_token().unwrap());
+        assert_eq!(token.hash(), TokenHash::of(&token.0));
+        assert_ne!(token.hash(), TokenHash::of("something else"));
+        assert_ne!(generate_token().unwrap(), token.0);
+        assert_eq!(format!("{token:?}"), "SessionToken(..)");
+    }
+
+    #[tokio::test]
+    async fn test_sliding_expiry() {
+        let manager = InMemorySessionManager::new(SessionConfig {
+            sliding_expiry: true,
+            ..Default::default()
+        });
+        let session = manager
+            .new_session(fake_user(), ClientInfo::default())
+            .await
+            .unwrap();
+
+        test_utils::set_mock_time(test_utils::get_mock_time() + 200);
+        let used = manager
+            .get_session(session.token.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(used.expire_time > session.session.expire_time);
+
+        // used all the time, but still over once the refresh token is
+        let refresh_expire_time = session.session.refresh_expire_time;
+        let mut used = used;
+        while used.is_valid() {
+            test_utils::set_mock_time(test_utils::get_mock_time() + 200);
+            used = manager
+                .get_session(session.token.clone())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(used.expire_time <= refresh_expire_time);
+        }
+        assert!(manager.validate_session(used).await.unwrap().is_none());
+    }
 }
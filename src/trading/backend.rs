@@ -1,18 +1,918 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use crate::db::manager::DBManager;
+use anyhow::Result;
+use diesel::dsl::sql;
+use diesel::sql_types::BigInt;
+use diesel::{ExpressionMethods, RunQueryDsl};
 
-use super::market::Market;
+use crate::db::{
+    connection::DbConnection,
+    filter::Filter,
+    manager::{db_error, DBManager, DatabaseImpl, Transaction},
+    models::{order, trade},
+    repository::stock::StockRepositoryImpl,
+};
+
+use super::error::TradeError;
+use super::market::{Market, SwapPair, DEFAULT_QUOTE_CURRENCY};
+use super::order::{Fill, Order, OrderExecution, OrderId, OrderRequest, OrderStatus};
+use super::order_book::OrderBook;
+use super::settlement;
+
+/// How often the listed stocks are re-read so markets follow listings and delistings.
+pub const MARKET_SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+type Markets = RwLock<HashMap<SwapPair, Arc<Mutex<Market>>>>;
 
 #[derive(Debug)]
 pub struct TradeBackend {
-    markets: HashSet<Market>,
+    db_manager: Arc<DBManager>,
+    stocks: Arc<dyn StockRepositoryImpl>,
+    // Shared with the blocking tasks that match and settle orders. The map is only ever locked
+    // long enough to look a market up, each market has a lock of its own that is held while its
+    // orders are written, so orders in one market never wait on another.
+    markets: Arc<Markets>,
 }
 
 impl TradeBackend {
-    pub fn new(_db_manager: &DBManager) -> Self {
-        // TODO: make this init markets from database
-        let markets = HashSet::new();
-        Self { markets }
+    /// Creates a backend with one market for every stock currently in the database, and puts
+    /// every order that was still resting back into its book.
+    pub async fn new(
+        db_manager: Arc<DBManager>,
+        stocks: Arc<dyn StockRepositoryImpl>,
+    ) -> Result<Self> {
+        let backend = Self {
+            db_manager,
+            stocks,
+            markets: Arc::new(RwLock::new(HashMap::new())),
+        };
+        backend.sync_markets().await?;
+        backend.restore_orders().await?;
+        Ok(backend)
+    }
+
+    pub fn add_market(&self, swap_pair: SwapPair) {
+        self.markets.write().unwrap().entry(swap_pair).or_default();
+    }
+
+    /// Removes a market, cancelling every order resting in its book and releasing the funds they
+    /// held in one transaction. The market is left open if that fails.
+    ///
+    /// # Returns
+    /// Whether there was a market to remove.
+    pub async fn remove_market(&self, swap_pair: &SwapPair) -> Result<bool> {
+        let markets = self.markets.clone();
+        let swap_pair = swap_pair.clone();
+
+        self.db_manager
+            .run(move |db| {
+                let Some(shared) = market(&markets, &swap_pair) else {
+                    return Ok(false);
+                };
+                let mut market = shared.lock().unwrap();
+                if market.closed {
+                    return Ok(false);
+                }
+
+                db.transaction(|tx| cancel_all(tx, market.order_book.orders()))
+                    .map_err(|e| e.into_anyhow().context("Failed to close market"))?;
+
+                market.closed = true;
+                market.order_book = OrderBook::default();
+                markets.write().unwrap().remove(&swap_pair);
+                Ok(true)
+            })
+            .await
+    }
+
+    /// Brings the set of markets in line with the stock table: newly listed stocks get an empty
+    /// market and markets for delisted stocks are dropped. The stock row for the quote currency
+    /// only backs cash wallets and never gets a market of its own.
+    pub async fn sync_markets(&self) -> Result<()> {
+        let stocks = self.stocks.list().await?;
+        let listed = stocks
+            .iter()
+            .filter(|s| !s.delisted && s.symbol != DEFAULT_QUOTE_CURRENCY)
+            .map(SwapPair::from)
+            .collect::<HashSet<_>>();
+
+        let delisted = self
+            .markets
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|pair| !listed.contains(*pair))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for swap_pair in delisted {
+            self.remove_market(&swap_pair).await?;
+        }
+        for swap_pair in listed {
+            self.add_market(swap_pair);
+        }
+
+        Ok(())
+    }
+
+    /// Re-syncs markets from the database every `period` until the task is dropped.
+    pub async fn watch_markets(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.sync_markets().await {
+                eprintln!("Failed to sync markets: {e:#}");
+            }
+        }
+    }
+
+    /// Puts every resting order back into its market's book. Orders whose market no longer
+    /// exists, because their stock was delisted while the server was down, are cancelled and
+    /// release their funds in one transaction instead.
+    async fn restore_orders(&self) -> Result<()> {
+        let rows: Vec<order::Order> = self
+            .db_manager
+            .query_rows(order::schema::orders::table, vec![])
+            .await?;
+        let mut orders = rows
+            .into_iter()
+            .map(Order::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        orders.sort_by_key(|o| o.id);
+
+        // the backend hasn't been shared yet, so nothing else can be holding a market's lock
+        let mut orphaned = Vec::new();
+        for order in orders.into_iter().filter(|o| o.status.is_resting()) {
+            match market(&self.markets, &order.swap_pair) {
+                Some(market) => market.lock().unwrap().order_book.restore(order),
+                None => orphaned.push(order),
+            }
+        }
+        if orphaned.is_empty() {
+            return Ok(());
+        }
+
+        self.db_manager
+            .run(move |db| {
+                db.transaction(|tx| cancel_all(tx, orphaned.iter()))
+                    .map_err(|e| {
+                        e.into_anyhow()
+                            .context("Failed to cancel orders of removed markets")
+                    })
+            })
+            .await
+    }
+
+    /// Places a limit order for `user_id`, matching it against the market's book.
+    ///
+    /// The order is written first so the database assigns its id. The funds it needs are held,
+    /// every fill is settled, and every order it traded against and the resulting trades are
+    /// written in the same transaction, and only once that commits is the match applied to the
+    /// book, so a rejected order or a failed write leaves both the book and wallets untouched.
+    pub async fn place_order(
+        &self,
+        user_id: i32,
+        request: OrderRequest,
+    ) -> Result<OrderExecution, TradeError> {
+        let markets = self.markets.clone();
+
+        self.db_manager
+            .run(move |db| {
+                let unknown = || TradeError::UnknownMarket(request.swap_pair.clone());
+                let shared = market(&markets, &request.swap_pair).ok_or_else(unknown)?;
+                let mut market = shared.lock().unwrap();
+                // the market was removed while this order waited for it
+                if market.closed {
+                    return Err(unknown());
+                }
+
+                let execution = db.transaction(|tx| -> Result<_, TradeError> {
+                    let mut order = insert_order(tx, Order::new(0, user_id, &request))?;
+                    settlement::reserve(tx, &mut order)?;
+                    let mut execution = market.order_book.match_order(order);
+                    settlement::settle(tx, &mut execution)?;
+                    save(tx, &execution)?;
+                    Ok(execution)
+                })?;
+                market.order_book.apply(&execution);
+
+                Ok(execution)
+            })
+            .await
+    }
+
+    /// Looks up an order by id, whether it is still resting or not.
+    pub async fn order(&self, id: OrderId) -> Result<Option<Order>> {
+        let rows: Vec<order::Order> = self
+            .db_manager
+            .query_rows(
+                order::schema::orders::table,
+                vec![Filter::eq(order::schema::orders::id, id)],
+            )
+            .await?;
+
+        rows.into_iter().next().map(Order::try_from).transpose()
+    }
+
+    /// Returns every fill an order took part in, oldest first.
+    pub async fn fills(&self, id: OrderId) -> Result<Vec<Fill>> {
+        use trade::schema::trades;
+
+        let mut trades: Vec<trade::Trade> = self
+            .db_manager
+            .query_rows(trades::table, vec![Filter::eq(trades::maker_order_id, id)])
+            .await?;
+        trades.extend(
+            self.db_manager
+                .query_rows::<_, trade::Trade>(
+                    trades::table,
+                    vec![Filter::eq(trades::taker_order_id, id)],
+                )
+                .await?,
+        );
+        trades.sort_by_key(|t| t.id);
+
+        Ok(trades.into_iter().map(Fill::from).collect())
+    }
+
+    /// Cancels an order that is still resting in its market's book and releases the funds it
+    /// held.
+    ///
+    /// # Returns
+    /// The cancelled order, or `None` if the order is not resting in any book.
+    pub async fn cancel_order(&self, swap_pair: &SwapPair, id: OrderId) -> Result<Option<Order>> {
+        let markets = self.markets.clone();
+        let swap_pair = swap_pair.clone();
+
+        self.db_manager
+            .run(move |db| {
+                let Some(shared) = market(&markets, &swap_pair) else {
+                    return Ok(None);
+                };
+                let mut market = shared.lock().unwrap();
+                let Some(mut order) = market.order_book.get(id).cloned() else {
+                    return Ok(None);
+                };
+
+                order.status = OrderStatus::Cancelled;
+                let order = db
+                    .transaction(|tx| {
+                        let mut order = order.clone();
+                        settlement::release(tx, &mut order)?;
+                        update_order(tx, &order)?;
+                        Ok(order)
+                    })
+                    .map_err(|e: TradeError| e.into_anyhow().context("Failed to cancel order"))?;
+                market.order_book.cancel(id);
+
+                Ok(Some(order))
+            })
+            .await
+    }
+}
+
+/// Looks a market up, holding the lock on the map only for as long as that takes.
+fn market(markets: &Markets, swap_pair: &SwapPair) -> Option<Arc<Mutex<Market>>> {
+    markets.read().unwrap().get(swap_pair).cloned()
+}
+
+/// Cancels every order in `orders` and releases the funds they held.
+fn cancel_all<'o>(
+    tx: &Transaction,
+    orders: impl Iterator<Item = &'o Order>,
+) -> Result<(), TradeError> {
+    for order in orders {
+        let mut order = order.clone();
+        order.status = OrderStatus::Cancelled;
+        settlement::release(tx, &mut order)?;
+        update_order(tx, &order)?;
+    }
+    Ok(())
+}
+
+/// Writes the fill state of an order that is already stored.
+fn update_order(tx: &Transaction, order: &Order) -> Result<usize> {
+    use order::schema::orders;
+
+    tx.update_rows(
+        orders::table,
+        vec![Filter::eq(orders::id, order.id)],
+        (
+            orders::remaining.eq(order.remaining),
+            orders::held.eq(order.held),
+            orders::status.eq(order.status.as_str()),
+        ),
+    )
+}
+
+/// Writes a newly placed order and returns it with the id the database assigned it.
+fn insert_order(tx: &Transaction, mut order: Order) -> Result<Order> {
+    let row = order::Order {
+        id: None,
+        ..order::Order::from(&order)
+    };
+    tx.insert_row(order::schema::orders::table, &row)?;
+
+    // both are per connection, so rows other connections insert meanwhile can't get in the way
+    let id = tx.with_connection(|conn| {
+        let last_id = match conn {
+            DbConnection::Sqlite(_) => "last_insert_rowid()",
+            DbConnection::Postgres(_) => "currval(pg_get_serial_sequence('orders', 'id'))",
+        };
+        diesel::select(sql::<BigInt>(last_id))
+            .get_result::<i64>(conn)
+            .map_err(db_error("Read new order id"))
+    })?;
+    order.id = OrderId::try_from(id)?;
+    Ok(order)
+}
+
+fn save(tx: &Transaction, execution: &OrderExecution) -> Result<(), TradeError> {
+    update_order(tx, &execution.order)?;
+    for maker in &execution.makers {
+        update_order(tx, maker)?;
+    }
+    let trades = execution
+        .fills
+        .iter()
+        .map(|fill| fill.to_trade(&execution.order.swap_pair))
+        .collect::<Vec<_>>();
+    tx.insert_rows(trade::schema::trades::table, trades.iter().collect())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::error::DbError;
+    use crate::db::models::{
+        stock::{self, Stock, StockBuilder},
+        user, wallet,
+    };
+    use crate::db::repository::stock::StockRepository;
+    use crate::db::test_utils::memory_db_manager;
+    use crate::money::Amount;
+    use crate::trading::order::{OrderStatus, Side};
+
+    impl TradeBackend {
+        fn has_market(&self, swap_pair: &SwapPair) -> bool {
+            self.markets.read().unwrap().contains_key(swap_pair)
+        }
+    }
+
+    fn test_db_manager() -> Arc<DBManager> {
+        let db_manager = memory_db_manager();
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock(DEFAULT_QUOTE_CURRENCY))
+            .unwrap();
+        // wallets must belong to a real user, tests trade as users 1 to 3
+        for i in 1..=3 {
+            let user = user::UserBuilder::default()
+                .id(None)
+                .email(format!("trader{i}@example.com"))
+                .password("hash".to_owned())
+                .first_name("Trader".to_owned())
+                .last_name(i.to_string())
+                .build()
+                .unwrap();
+            db_manager
+                .insert_row(user::schema::users::table, &user)
+                .unwrap();
+        }
+        Arc::new(db_manager)
+    }
+
+    async fn trade_backend(db_manager: &Arc<DBManager>) -> Result<TradeBackend> {
+        let stocks = Arc::new(StockRepository::new(DBManager::clone(db_manager)));
+        TradeBackend::new(db_manager.clone(), stocks).await
+    }
+
+    async fn stock_id(db_manager: &DBManager, symbol: &str) -> i32 {
+        let stocks: Vec<Stock> = db_manager
+            .query_rows(
+                stock::schema::stock::table,
+                vec![Filter::eq(stock::schema::stock::symbol, symbol)],
+            )
+            .await
+            .unwrap();
+        stocks[0].id.unwrap()
+    }
+
+    fn amount(val: f64) -> Amount {
+        Amount::try_from(val).unwrap()
+    }
+
+    async fn fund(db_manager: &DBManager, user_id: i32, symbol: &str, balance: f64) {
+        let stock_id = stock_id(db_manager, symbol).await;
+        let wallet = wallet::WalletBuilder::default()
+            .id(None)
+            .stock_id(stock_id)
+            .user_id(user_id)
+            .balance(amount(balance))
+            .reserved(Amount::ZERO)
+            .build()
+            .unwrap();
+        db_manager
+            .insert_row(wallet::schema::wallets::table, &wallet)
+            .unwrap();
+    }
+
+    async fn wallet(db_manager: &DBManager, user_id: i32, symbol: &str) -> wallet::Wallet {
+        use wallet::schema::wallets;
+
+        let stock_id = stock_id(db_manager, symbol).await;
+        let wallets: Vec<wallet::Wallet> = db_manager
+            .query_rows(
+                wallets::table,
+                vec![
+                    Filter::eq(wallets::user_id, user_id),
+                    Filter::eq(wallets::stock_id, stock_id),
+                ],
+            )
+            .await
+            .unwrap();
+        wallets[0].clone()
+    }
+
+    fn stock(symbol: &str) -> Stock {
+        StockBuilder::default()
+            .id(None)
+            .name(format!("{symbol} Inc"))
+            .symbol(symbol.to_owned())
+            .exchange_name("NASDAQ".to_owned())
+            .build()
+            .unwrap()
+    }
+
+    fn order_request(symbol: &str, side: Side, price: f64, quantity: f64) -> OrderRequest {
+        OrderRequest {
+            swap_pair: SwapPair::new(symbol, DEFAULT_QUOTE_CURRENCY),
+            side,
+            price: amount(price),
+            quantity: amount(quantity),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_markets_follow_stock_table() {
+        let db_manager = test_db_manager();
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock("AAPL"))
+            .unwrap();
+
+        let backend = trade_backend(&db_manager).await.unwrap();
+        let aapl = SwapPair::new("AAPL", DEFAULT_QUOTE_CURRENCY);
+        let msft = SwapPair::new("MSFT", DEFAULT_QUOTE_CURRENCY);
+        assert!(backend.has_market(&aapl));
+        assert!(!backend.has_market(&msft));
+        assert!(!backend.has_market(&SwapPair::new(
+            DEFAULT_QUOTE_CURRENCY,
+            DEFAULT_QUOTE_CURRENCY
+        )));
+
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock("MSFT"))
+            .unwrap();
+        let delisted = db_manager
+            .update_rows(
+                stock::schema::stock::table,
+                vec![Filter::eq(stock::schema::stock::symbol, "AAPL")],
+                stock::schema::stock::delisted.eq(true),
+            )
+            .unwrap();
+        assert_eq!(delisted, 1);
+        backend.sync_markets().await.unwrap();
+
+        assert!(!backend.has_market(&aapl));
+        assert!(backend.has_market(&msft));
+    }
+
+    // holding one market's lock across the await is the point of the test
+    #[allow(clippy::await_holding_lock)]
+    #[tokio::test]
+    async fn test_markets_lock_independently() {
+        let db_manager = test_db_manager();
+        db_manager
+            .insert_rows(
+                stock::schema::stock::table,
+                vec![&stock("AAPL"), &stock("MSFT")],
+            )
+            .unwrap();
+        fund(&db_manager, 1, "MSFT", 5.0).await;
+        let backend = trade_backend(&db_manager).await.unwrap();
+
+        let aapl = market(
+            &backend.markets,
+            &SwapPair::new("AAPL", DEFAULT_QUOTE_CURRENCY),
+        )
+        .unwrap();
+        let _busy = aapl.lock().unwrap();
+
+        let execution = tokio::time::timeout(
+            Duration::from_secs(5),
+            backend.place_order(1, order_request("MSFT", Side::Sell, 10.0, 5.0)),
+        )
+        .await
+        .expect("An order in another market should not wait for this one")
+        .unwrap();
+        assert_eq!(execution.order.status, OrderStatus::Open);
+        assert!(backend.has_market(&execution.order.swap_pair));
+    }
+
+    #[tokio::test]
+    async fn test_delisting_cancels_resting_orders() {
+        let db_manager = test_db_manager();
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock("AAPL"))
+            .unwrap();
+        fund(&db_manager, 1, "AAPL", 5.0).await;
+        fund(&db_manager, 2, DEFAULT_QUOTE_CURRENCY, 50.0).await;
+        let backend = trade_backend(&db_manager).await.unwrap();
+
+        let ask = backend
+            .place_order(1, order_request("AAPL", Side::Sell, 12.0, 5.0))
+            .await
+            .unwrap()
+            .order;
+        let bid = backend
+            .place_order(2, order_request("AAPL", Side::Buy, 10.0, 4.0))
+            .await
+            .unwrap()
+            .order;
+
+        db_manager
+            .update_rows(
+                stock::schema::stock::table,
+                vec![Filter::eq(stock::schema::stock::symbol, "AAPL")],
+                stock::schema::stock::delisted.eq(true),
+            )
+            .unwrap();
+        backend.sync_markets().await.unwrap();
+        assert!(!backend.has_market(&ask.swap_pair));
+
+        for id in [ask.id, bid.id] {
+            let stored = backend.order(id).await.unwrap().unwrap();
+            assert_eq!(stored.status, OrderStatus::Cancelled);
+        }
+        let seller_stock = wallet(&db_manager, 1, "AAPL").await;
+        assert_eq!(seller_stock.balance, amount(5.0));
+        assert_eq!(seller_stock.reserved, amount(0.0));
+        let buyer_cash = wallet(&db_manager, 2, DEFAULT_QUOTE_CURRENCY).await;
+        assert_eq!(buyer_cash.balance, amount(50.0));
+        assert_eq!(buyer_cash.reserved, amount(0.0));
+    }
+
+    #[tokio::test]
+    async fn test_restart_cancels_orders_of_delisted_stocks() {
+        let db_manager = test_db_manager();
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock("AAPL"))
+            .unwrap();
+        fund(&db_manager, 1, "AAPL", 5.0).await;
+        fund(&db_manager, 2, DEFAULT_QUOTE_CURRENCY, 50.0).await;
+
+        let backend = trade_backend(&db_manager).await.unwrap();
+        let ask = backend
+            .place_order(1, order_request("AAPL", Side::Sell, 12.0, 5.0))
+            .await
+            .unwrap()
+            .order;
+        let bid = backend
+            .place_order(2, order_request("AAPL", Side::Buy, 10.0, 4.0))
+            .await
+            .unwrap()
+            .order;
+        drop(backend);
+
+        // delisted while the server was down
+        db_manager
+            .update_rows(
+                stock::schema::stock::table,
+                vec![Filter::eq(stock::schema::stock::symbol, "AAPL")],
+                stock::schema::stock::delisted.eq(true),
+            )
+            .unwrap();
+        let backend = trade_backend(&db_manager).await.unwrap();
+        assert!(!backend.has_market(&ask.swap_pair));
+
+        for id in [ask.id, bid.id] {
+            let stored = backend.order(id).await.unwrap().unwrap();
+            assert_eq!(stored.status, OrderStatus::Cancelled);
+            assert_eq!(stored.held, Amount::ZERO);
+        }
+        let seller_stock = wallet(&db_manager, 1, "AAPL").await;
+        assert_eq!(seller_stock.balance, amount(5.0));
+        assert_eq!(seller_stock.reserved, amount(0.0));
+        let buyer_cash = wallet(&db_manager, 2, DEFAULT_QUOTE_CURRENCY).await;
+        assert_eq!(buyer_cash.balance, amount(50.0));
+        assert_eq!(buyer_cash.reserved, amount(0.0));
+    }
+
+    #[tokio::test]
+    async fn test_book_survives_restart() {
+        let db_manager = test_db_manager();
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock("AAPL"))
+            .unwrap();
+
+        fund(&db_manager, 1, "AAPL", 5.0).await;
+        fund(&db_manager, 2, DEFAULT_QUOTE_CURRENCY, 20.0).await;
+        fund(&db_manager, 3, DEFAULT_QUOTE_CURRENCY, 30.0).await;
+
+        let backend = trade_backend(&db_manager).await.unwrap();
+        let maker = backend
+            .place_order(1, order_request("AAPL", Side::Sell, 10.0, 5.0))
+            .await
+            .unwrap();
+        let taker = backend
+            .place_order(2, order_request("AAPL", Side::Buy, 10.0, 2.0))
+            .await
+            .unwrap();
+        assert_eq!(taker.order.status, OrderStatus::Filled);
+        drop(backend);
+
+        let backend = trade_backend(&db_manager).await.unwrap();
+        let execution = backend
+            .place_order(3, order_request("AAPL", Side::Buy, 10.0, 3.0))
+            .await
+            .unwrap();
+        assert_eq!(execution.order.status, OrderStatus::Filled);
+        assert_eq!(execution.fills.len(), 1);
+        assert_eq!(execution.fills[0].maker_order_id, maker.order.id);
+        assert!(execution.order.id > taker.order.id);
+
+        let trades: Vec<trade::Trade> = db_manager
+            .query_rows(trade::schema::trades::table, vec![])
+            .await
+            .unwrap();
+        assert_eq!(trades.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_order_lookup_and_cancel() {
+        let db_manager = test_db_manager();
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock("AAPL"))
+            .unwrap();
+        fund(&db_manager, 1, "AAPL", 5.0).await;
+        fund(&db_manager, 2, DEFAULT_QUOTE_CURRENCY, 20.0).await;
+        let backend = trade_backend(&db_manager).await.unwrap();
+
+        let maker = backend
+            .place_order(1, order_request("AAPL", Side::Sell, 10.0, 5.0))
+            .await
+            .unwrap()
+            .order;
+        backend
+            .place_order(2, order_request("AAPL", Side::Buy, 10.0, 2.0))
+            .await
+            .unwrap();
+
+        let stored = backend.order(maker.id).await.unwrap().unwrap();
+        assert_eq!(stored.status, OrderStatus::PartiallyFilled);
+        assert_eq!(stored.remaining, amount(3.0));
+        assert_eq!(backend.fills(maker.id).await.unwrap().len(), 1);
+
+        let cancelled = backend
+            .cancel_order(&maker.swap_pair, maker.id)
+            .await
+            .unwrap()
+            .expect("Resting order should be cancellable");
+        assert_eq!(cancelled.status, OrderStatus::Cancelled);
+        assert!(backend
+            .cancel_order(&maker.swap_pair, maker.id)
+            .await
+            .unwrap()
+            .is_none());
+
+        let stored = backend.order(maker.id).await.unwrap().unwrap();
+        assert_eq!(stored.status, OrderStatus::Cancelled);
+
+        let seller_stock = wallet(&db_manager, 1, "AAPL").await;
+        assert_eq!(seller_stock.balance, amount(3.0));
+        assert_eq!(seller_stock.reserved, amount(0.0));
+    }
+
+    #[tokio::test]
+    async fn test_insufficient_funds_rejected() {
+        let db_manager = test_db_manager();
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock("AAPL"))
+            .unwrap();
+        fund(&db_manager, 1, DEFAULT_QUOTE_CURRENCY, 50.0).await;
+        let backend = trade_backend(&db_manager).await.unwrap();
+
+        backend
+            .place_order(1, order_request("AAPL", Side::Buy, 10.0, 4.0))
+            .await
+            .unwrap();
+        // 40 of the 50 is now held by the first order
+        let result = backend
+            .place_order(1, order_request("AAPL", Side::Buy, 10.0, 2.0))
+            .await;
+        assert!(matches!(result, Err(TradeError::InsufficientFunds { .. })));
+
+        let result = backend
+            .place_order(2, order_request("AAPL", Side::Sell, 10.0, 1.0))
+            .await;
+        assert!(matches!(result, Err(TradeError::InsufficientFunds { .. })));
+
+        let cash = wallet(&db_manager, 1, DEFAULT_QUOTE_CURRENCY).await;
+        assert_eq!(cash.balance, amount(50.0));
+        assert_eq!(cash.reserved, amount(40.0));
+    }
+
+    #[tokio::test]
+    async fn test_constraint_violations_stay_visible() {
+        let db_manager = test_db_manager();
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock("AAPL"))
+            .unwrap();
+        let backend = trade_backend(&db_manager).await.unwrap();
+
+        // there is no user 99 for the order to belong to
+        let Err(TradeError::Internal(err)) = backend
+            .place_order(99, order_request("AAPL", Side::Buy, 10.0, 1.0))
+            .await
+        else {
+            panic!("the order should have been refused by the database");
+        };
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::ForeignKeyViolation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fills_release_the_whole_hold() {
+        let db_manager = test_db_manager();
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock("AAPL"))
+            .unwrap();
+        fund(&db_manager, 1, "AAPL", 1.0).await;
+        fund(&db_manager, 2, DEFAULT_QUOTE_CURRENCY, 10.0).await;
+        fund(&db_manager, 3, DEFAULT_QUOTE_CURRENCY, 10.0).await;
+        let backend = trade_backend(&db_manager).await.unwrap();
+
+        // 0.5 at this price holds 0.50000001, but each fill of 0.25 only uses 0.25
+        let price = 1.00000001;
+        backend
+            .place_order(2, order_request("AAPL", Side::Buy, price, 0.5))
+            .await
+            .unwrap();
+        // the first two fill the resting buy, the other two rest for the buy placed after them
+        for _ in 0..4 {
+            backend
+                .place_order(1, order_request("AAPL", Side::Sell, price, 0.25))
+                .await
+                .unwrap();
+        }
+        let taker = backend
+            .place_order(3, order_request("AAPL", Side::Buy, price, 0.5))
+            .await
+            .unwrap();
+        assert_eq!(taker.order.status, OrderStatus::Filled);
+        assert_eq!(taker.fills.len(), 2);
+
+        for user_id in [2, 3] {
+            let cash = wallet(&db_manager, user_id, DEFAULT_QUOTE_CURRENCY).await;
+            assert_eq!(cash.balance, amount(9.5));
+            assert_eq!(cash.reserved, amount(0.0));
+        }
+        let seller_stock = wallet(&db_manager, 1, "AAPL").await;
+        assert_eq!(seller_stock.balance, amount(0.0));
+        assert_eq!(seller_stock.reserved, amount(0.0));
+    }
+
+    #[tokio::test]
+    async fn test_rounded_fills_never_cost_more_than_the_hold() {
+        let db_manager = test_db_manager();
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock("AAPL"))
+            .unwrap();
+        fund(&db_manager, 1, "AAPL", 1.0).await;
+        // exactly what 0.5 at this price holds, while each fill of 0.25 rounds up to 0.25000001
+        fund(&db_manager, 2, DEFAULT_QUOTE_CURRENCY, 0.50000001).await;
+        fund(&db_manager, 3, DEFAULT_QUOTE_CURRENCY, 0.50000001).await;
+        let backend = trade_backend(&db_manager).await.unwrap();
+
+        let price = 1.00000002;
+        backend
+            .place_order(2, order_request("AAPL", Side::Buy, price, 0.5))
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            backend
+                .place_order(1, order_request("AAPL", Side::Sell, price, 0.25))
+                .await
+                .unwrap();
+        }
+        let taker = backend
+            .place_order(3, order_request("AAPL", Side::Buy, price, 0.5))
+            .await
+            .unwrap();
+        assert_eq!(taker.fills.len(), 2);
+
+        for user_id in [2, 3] {
+            let cash = wallet(&db_manager, user_id, DEFAULT_QUOTE_CURRENCY).await;
+            assert_eq!(cash.balance, amount(0.0));
+            assert_eq!(cash.reserved, amount(0.0));
+            assert_eq!(
+                wallet(&db_manager, user_id, "AAPL").await.balance,
+                amount(0.5)
+            );
+        }
+        let seller_cash = wallet(&db_manager, 1, DEFAULT_QUOTE_CURRENCY).await;
+        assert_eq!(seller_cash.balance, amount(1.00000002));
+    }
+
+    #[tokio::test]
+    async fn test_wallets_are_never_overdrawn() {
+        let db_manager = test_db_manager();
+        fund(&db_manager, 1, DEFAULT_QUOTE_CURRENCY, 1.0).await;
+
+        let result = db_manager
+            .transaction(|tx| settlement::deposit(tx, 1, DEFAULT_QUOTE_CURRENCY, amount(-2.0)));
+        assert!(matches!(result, Err(TradeError::Overdrawn { .. })));
+        let cash = wallet(&db_manager, 1, DEFAULT_QUOTE_CURRENCY).await;
+        assert_eq!(cash.balance, amount(1.0));
+        assert_eq!(cash.available(), amount(1.0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs a PostgreSQL database in POSTGRES_TEST_URL"]
+    async fn test_concurrent_deposits_on_postgres() {
+        let db_manager = crate::db::test_utils::postgres_db_manager("backend_test");
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock(DEFAULT_QUOTE_CURRENCY))
+            .unwrap();
+        let user = user::UserBuilder::default()
+            .id(None)
+            .email("trader@example.com".to_owned())
+            .password("hash".to_owned())
+            .first_name("Trader".to_owned())
+            .last_name("1".to_owned())
+            .build()
+            .unwrap();
+        db_manager
+            .insert_row(user::schema::users::table, &user)
+            .unwrap();
+        fund(&db_manager, 1, DEFAULT_QUOTE_CURRENCY, 0.0).await;
+
+        // every deposit reads the balance before writing it back, none of them may be lost
+        let mut deposits = tokio::task::JoinSet::new();
+        for _ in 0..20 {
+            let db_manager = db_manager.clone();
+            deposits.spawn(async move {
+                db_manager
+                    .run(|db| {
+                        db.transaction(|tx| {
+                            settlement::deposit(tx, 1, DEFAULT_QUOTE_CURRENCY, amount(1.0))
+                        })
+                    })
+                    .await
+            });
+        }
+        while let Some(result) = deposits.join_next().await {
+            result.unwrap().unwrap();
+        }
+
+        let cash = wallet(&db_manager, 1, DEFAULT_QUOTE_CURRENCY).await;
+        assert_eq!(cash.balance, amount(20.0));
+    }
+
+    #[tokio::test]
+    async fn test_fills_settle_wallets() {
+        let db_manager = test_db_manager();
+        db_manager
+            .insert_row(stock::schema::stock::table, &stock("AAPL"))
+            .unwrap();
+        fund(&db_manager, 1, "AAPL", 5.0).await;
+        fund(&db_manager, 2, DEFAULT_QUOTE_CURRENCY, 100.0).await;
+        let backend = trade_backend(&db_manager).await.unwrap();
+
+        backend
+            .place_order(1, order_request("AAPL", Side::Sell, 8.0, 5.0))
+            .await
+            .unwrap();
+        // buying 3 at a limit of 10 executes at the resting price of 8
+        backend
+            .place_order(2, order_request("AAPL", Side::Buy, 10.0, 3.0))
+            .await
+            .unwrap();
+
+        let buyer_cash = wallet(&db_manager, 2, DEFAULT_QUOTE_CURRENCY).await;
+        assert_eq!(buyer_cash.balance, amount(76.0));
+        assert_eq!(buyer_cash.reserved, amount(0.0));
+        let buyer_stock = wallet(&db_manager, 2, "AAPL").await;
+        assert_eq!(buyer_stock.balance, amount(3.0));
+
+        let seller_stock = wallet(&db_manager, 1, "AAPL").await;
+        assert_eq!(seller_stock.balance, amount(2.0));
+        assert_eq!(seller_stock.reserved, amount(2.0));
+        let seller_cash = wallet(&db_manager, 1, DEFAULT_QUOTE_CURRENCY).await;
+        assert_eq!(seller_cash.balance, amount(24.0));
     }
 }
@@ -0,0 +1,96 @@
+use std::fmt;
+
+use crate::{
+    db::{error::DbError, manager::TransactionError},
+    money::Amount,
+};
+
+use super::market::SwapPair;
+
+/// Reasons an order can be rejected or fail to execute.
+#[derive(Debug)]
+pub enum TradeError {
+    UnknownMarket(SwapPair),
+    UnknownStock(String),
+    InsufficientFunds {
+        symbol: String,
+        required: Amount,
+        available: Amount,
+    },
+    /// Settlement would have left a wallet with a negative balance, or holding more than its
+    /// balance. Nothing was written.
+    Overdrawn {
+        user_id: i32,
+        stock_id: i32,
+        balance: Amount,
+        reserved: Amount,
+    },
+    Internal(anyhow::Error),
+}
+
+impl fmt::Display for TradeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TradeError::UnknownMarket(swap_pair) => write!(
+                f,
+                "No market exists for {}/{}",
+                swap_pair.base(),
+                swap_pair.quote()
+            ),
+            TradeError::UnknownStock(symbol) => write!(f, "No stock is listed as {symbol}"),
+            TradeError::InsufficientFunds {
+                symbol,
+                required,
+                available,
+            } => write!(
+                f,
+                "Insufficient {symbol}: {required} is required but only {available} is available"
+            ),
+            TradeError::Overdrawn {
+                user_id,
+                stock_id,
+                balance,
+                reserved,
+            } => write!(
+                f,
+                "Wallet of user {user_id} for stock {stock_id} would be overdrawn: balance \
+                 {balance}, reserved {reserved}"
+            ),
+            TradeError::Internal(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for TradeError {}
+
+impl TradeError {
+    /// Turns the error back into an `anyhow::Error`, handing internal failures back as they were
+    /// so a [`DbError`] inside them can still be downcast.
+    pub fn into_anyhow(self) -> anyhow::Error {
+        match self {
+            TradeError::Internal(e) => e,
+            e => anyhow::Error::new(e),
+        }
+    }
+}
+
+impl From<anyhow::Error> for TradeError {
+    fn from(val: anyhow::Error) -> Self {
+        TradeError::Internal(val)
+    }
+}
+
+impl From<diesel::result::Error> for TradeError {
+    fn from(val: diesel::result::Error) -> Self {
+        TradeError::Internal(anyhow::Error::new(DbError::from(val)).context("Database error"))
+    }
+}
+
+impl TransactionError for TradeError {
+    fn db_error(&self) -> Option<&DbError> {
+        match self {
+            TradeError::Internal(e) => e.db_error(),
+            _ => None,
+        }
+    }
+}
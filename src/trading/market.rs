@@ -1,7 +1,37 @@
-#[derive(Debug)]
+use crate::db::models::stock::Stock;
+
+use super::order_book::OrderBook;
+
+/// The currency every listed stock is currently quoted in.
+pub const DEFAULT_QUOTE_CURRENCY: &str = "USD";
+
+#[derive(Debug, Default)]
 pub struct Market {
-    pub swap_pair: SwapPair,
+    pub order_book: OrderBook,
+    // set once the market has been removed, so orders that looked it up before then are refused
+    pub closed: bool,
 }
 
-#[derive(Debug)]
+/// A (base, quote) pair, e.g. ("AAPL", "USD") is AAPL priced in USD.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct SwapPair(String, String);
+
+impl SwapPair {
+    pub fn new(base: impl Into<String>, quote: impl Into<String>) -> Self {
+        Self(base.into(), quote.into())
+    }
+
+    pub fn base(&self) -> &str {
+        &self.0
+    }
+
+    pub fn quote(&self) -> &str {
+        &self.1
+    }
+}
+
+impl From<&Stock> for SwapPair {
+    fn from(val: &Stock) -> Self {
+        SwapPair::new(val.symbol.clone(), DEFAULT_QUOTE_CURRENCY)
+    }
+}
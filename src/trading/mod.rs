@@ -1,2 +1,6 @@
 pub(crate) mod backend;
+pub(crate) mod error;
 pub(crate) mod market;
+pub(crate) mod order;
+pub(crate) mod order_book;
+pub(crate) mod settlement;
@@ -0,0 +1,320 @@
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDateTime, Utc};
+use prost_types::Timestamp;
+use rust_models::common::{TradeFill, TradeRequest, TradeSide, TradeStatus};
+
+use crate::db::models::{order, trade};
+use crate::money::Amount;
+
+use super::market::{SwapPair, DEFAULT_QUOTE_CURRENCY};
+
+pub type OrderId = i32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Side::Buy => "buy",
+            Side::Sell => "sell",
+        }
+    }
+}
+
+impl TryFrom<&str> for Side {
+    type Error = anyhow::Error;
+
+    fn try_from(val: &str) -> Result<Self> {
+        match val {
+            "buy" => Ok(Side::Buy),
+            "sell" => Ok(Side::Sell),
+            _ => Err(anyhow!("Unknown order side {val}")),
+        }
+    }
+}
+
+impl From<TradeSide> for Side {
+    fn from(val: TradeSide) -> Self {
+        match val {
+            TradeSide::Buy => Side::Buy,
+            TradeSide::Sell => Side::Sell,
+        }
+    }
+}
+
+impl From<Side> for TradeSide {
+    fn from(val: Side) -> Self {
+        match val {
+            Side::Buy => TradeSide::Buy,
+            Side::Sell => TradeSide::Sell,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderStatus {
+    Open,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+}
+
+impl OrderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Open => "open",
+            OrderStatus::PartiallyFilled => "partially_filled",
+            OrderStatus::Filled => "filled",
+            OrderStatus::Cancelled => "cancelled",
+        }
+    }
+
+    /// Returns true if an order in this status can still sit in the book.
+    pub fn is_resting(&self) -> bool {
+        matches!(self, OrderStatus::Open | OrderStatus::PartiallyFilled)
+    }
+}
+
+impl From<OrderStatus> for TradeStatus {
+    fn from(val: OrderStatus) -> Self {
+        match val {
+            OrderStatus::Open => TradeStatus::Open,
+            OrderStatus::PartiallyFilled => TradeStatus::PartiallyFilled,
+            OrderStatus::Filled => TradeStatus::Filled,
+            OrderStatus::Cancelled => TradeStatus::Cancelled,
+        }
+    }
+}
+
+impl TryFrom<&str> for OrderStatus {
+    type Error = anyhow::Error;
+
+    fn try_from(val: &str) -> Result<Self> {
+        match val {
+            "open" => Ok(OrderStatus::Open),
+            "partially_filled" => Ok(OrderStatus::PartiallyFilled),
+            "filled" => Ok(OrderStatus::Filled),
+            "cancelled" => Ok(OrderStatus::Cancelled),
+            _ => Err(anyhow!("Unknown order status {val}")),
+        }
+    }
+}
+
+/// A validated limit order as submitted by a user, before it has been assigned an id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderRequest {
+    pub swap_pair: SwapPair,
+    pub side: Side,
+    pub price: Amount,
+    pub quantity: Amount,
+}
+
+impl TryFrom<TradeRequest> for OrderRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(val: TradeRequest) -> Result<Self> {
+        let side = TradeSide::try_from(val.side)
+            .map_err(|_| anyhow!("Unknown trade side {}", val.side))?;
+
+        if val.symbol.is_empty() {
+            return Err(anyhow!("Trade request is missing a symbol"));
+        }
+        let price = Amount::try_from(val.price)?;
+        if !price.is_positive() {
+            return Err(anyhow!("Price must be a positive number"));
+        }
+        let quantity = Amount::try_from(val.quantity)?;
+        if !quantity.is_positive() {
+            return Err(anyhow!("Quantity must be a positive number"));
+        }
+
+        Ok(Self {
+            swap_pair: SwapPair::new(val.symbol, DEFAULT_QUOTE_CURRENCY),
+            side: side.into(),
+            price,
+            quantity,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order {
+    pub id: OrderId,
+    pub user_id: i32,
+    pub swap_pair: SwapPair,
+    pub side: Side,
+    pub price: Amount,
+    // the quantity the order was placed with
+    pub quantity: Amount,
+    // the quantity that has not been filled yet
+    pub remaining: Amount,
+    // the funds the order still holds, see `settlement`
+    pub held: Amount,
+    pub status: OrderStatus,
+    pub created_at: NaiveDateTime,
+}
+
+impl Order {
+    pub fn new(id: OrderId, user_id: i32, request: &OrderRequest) -> Self {
+        Self {
+            id,
+            user_id,
+            swap_pair: request.swap_pair.clone(),
+            side: request.side,
+            price: request.price,
+            quantity: request.quantity,
+            remaining: request.quantity,
+            held: Amount::ZERO,
+            status: OrderStatus::Open,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn filled(&self) -> Amount {
+        // remaining never exceeds quantity, so this can't underflow
+        self.quantity
+            .checked_sub(self.remaining)
+            .unwrap_or(Amount::ZERO)
+    }
+
+    /// Returns true if this order is willing to trade against a resting order at `price`.
+    pub fn crosses(&self, price: Amount) -> bool {
+        match self.side {
+            Side::Buy => self.price >= price,
+            Side::Sell => self.price <= price,
+        }
+    }
+
+    /// Records a fill of `quantity`, which must not be more than what is remaining.
+    pub(crate) fn fill(&mut self, quantity: Amount) {
+        self.remaining = self.remaining.checked_sub(quantity).unwrap_or(Amount::ZERO);
+        self.status = if self.remaining.is_zero() {
+            OrderStatus::Filled
+        } else {
+            OrderStatus::PartiallyFilled
+        };
+    }
+}
+
+impl From<&Order> for TradeRequest {
+    fn from(val: &Order) -> Self {
+        TradeRequest {
+            symbol: val.swap_pair.base().to_owned(),
+            side: TradeSide::from(val.side).into(),
+            price: val.price.into(),
+            quantity: val.quantity.into(),
+        }
+    }
+}
+
+impl From<&Order> for order::Order {
+    fn from(val: &Order) -> Self {
+        order::Order {
+            id: Some(val.id),
+            user_id: val.user_id,
+            base_symbol: val.swap_pair.base().to_owned(),
+            quote_symbol: val.swap_pair.quote().to_owned(),
+            side: val.side.as_str().to_owned(),
+            price: val.price,
+            quantity: val.quantity,
+            remaining: val.remaining,
+            held: val.held,
+            status: val.status.as_str().to_owned(),
+            created_at: val.created_at,
+        }
+    }
+}
+
+impl TryFrom<order::Order> for Order {
+    type Error = anyhow::Error;
+
+    fn try_from(val: order::Order) -> Result<Self> {
+        Ok(Self {
+            id: val
+                .id
+                .ok_or_else(|| anyhow!("Order row is missing an id"))?,
+            user_id: val.user_id,
+            swap_pair: SwapPair::new(val.base_symbol, val.quote_symbol),
+            side: Side::try_from(val.side.as_str())?,
+            price: val.price,
+            quantity: val.quantity,
+            remaining: val.remaining,
+            held: val.held,
+            status: OrderStatus::try_from(val.status.as_str())?,
+            created_at: val.created_at,
+        })
+    }
+}
+
+/// A single execution between a resting (maker) order and an incoming (taker) order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fill {
+    pub maker_order_id: OrderId,
+    pub taker_order_id: OrderId,
+    pub buyer_id: i32,
+    pub seller_id: i32,
+    // fills always execute at the maker's price
+    pub price: Amount,
+    pub quantity: Amount,
+    pub executed_at: NaiveDateTime,
+}
+
+impl Fill {
+    pub fn to_trade(&self, swap_pair: &SwapPair) -> trade::Trade {
+        trade::Trade {
+            id: None,
+            maker_order_id: self.maker_order_id,
+            taker_order_id: self.taker_order_id,
+            buyer_id: self.buyer_id,
+            seller_id: self.seller_id,
+            base_symbol: swap_pair.base().to_owned(),
+            quote_symbol: swap_pair.quote().to_owned(),
+            price: self.price,
+            quantity: self.quantity,
+            executed_at: self.executed_at,
+        }
+    }
+}
+
+impl From<trade::Trade> for Fill {
+    fn from(val: trade::Trade) -> Self {
+        Fill {
+            maker_order_id: val.maker_order_id,
+            taker_order_id: val.taker_order_id,
+            buyer_id: val.buyer_id,
+            seller_id: val.seller_id,
+            price: val.price,
+            quantity: val.quantity,
+            executed_at: val.executed_at,
+        }
+    }
+}
+
+impl From<&Fill> for TradeFill {
+    fn from(val: &Fill) -> Self {
+        let executed_at = val.executed_at.and_utc();
+        TradeFill {
+            price: val.price.into(),
+            quantity: val.quantity.into(),
+            executed_at: Some(Timestamp {
+                seconds: executed_at.timestamp(),
+                nanos: executed_at.timestamp_subsec_nanos() as i32,
+            }),
+        }
+    }
+}
+
+/// The result of submitting an order to a book.
+#[derive(Debug, Clone)]
+pub struct OrderExecution {
+    // the submitted order as it stands after matching
+    pub order: Order,
+    // every resting order that was traded against, as it stands after matching
+    pub makers: Vec<Order>,
+    // every fill produced, in execution order
+    pub fills: Vec<Fill>,
+}
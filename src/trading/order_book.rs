@@ -0,0 +1,261 @@
+use std::collections::VecDeque;
+
+use chrono::Utc;
+
+use super::order::{Fill, Order, OrderExecution, OrderId, OrderStatus, Side};
+
+/// A limit order book with price-time priority.
+///
+/// Each side is kept sorted best price first, and orders at the same price are kept in the order
+/// they arrived in, so the front of each queue is always the next order to be matched.
+#[derive(Debug, Default, Clone)]
+pub struct OrderBook {
+    bids: VecDeque<Order>,
+    asks: VecDeque<Order>,
+}
+
+impl OrderBook {
+    /// Works out how `order` would execute against the book without changing it. Pass the result
+    /// to [`OrderBook::apply`] to make it take effect.
+    pub fn match_order(&self, mut order: Order) -> OrderExecution {
+        let executed_at = Utc::now().naive_utc();
+        let mut makers = Vec::new();
+        let mut fills = Vec::new();
+        let opposite = match order.side {
+            Side::Buy => &self.asks,
+            Side::Sell => &self.bids,
+        };
+
+        for resting in opposite {
+            if !order.remaining.is_positive() || !order.crosses(resting.price) {
+                break;
+            }
+
+            let mut maker = resting.clone();
+            let quantity = order.remaining.min(maker.remaining);
+            let (buyer_id, seller_id) = match order.side {
+                Side::Buy => (order.user_id, maker.user_id),
+                Side::Sell => (maker.user_id, order.user_id),
+            };
+            fills.push(Fill {
+                maker_order_id: maker.id,
+                taker_order_id: order.id,
+                buyer_id,
+                seller_id,
+                price: maker.price,
+                quantity,
+                executed_at,
+            });
+
+            maker.fill(quantity);
+            order.fill(quantity);
+            makers.push(maker);
+        }
+
+        OrderExecution {
+            order,
+            makers,
+            fills,
+        }
+    }
+
+    /// Applies an execution from [`OrderBook::match_order`]: the makers it filled leave the book,
+    /// the one it partially filled is updated and whatever is left of the taker rests. The book
+    /// must not have changed since the execution was matched.
+    pub fn apply(&mut self, execution: &OrderExecution) {
+        let opposite = match execution.order.side {
+            Side::Buy => &mut self.asks,
+            Side::Sell => &mut self.bids,
+        };
+
+        // makers are matched front to back, so each one is at the front when its turn comes
+        for maker in &execution.makers {
+            debug_assert_eq!(opposite.front().map(|o| o.id), Some(maker.id));
+            if maker.status == OrderStatus::Filled {
+                opposite.pop_front();
+            } else if let Some(front) = opposite.front_mut() {
+                *front = maker.clone();
+            }
+        }
+
+        if execution.order.remaining.is_positive() {
+            self.rest(execution.order.clone());
+        }
+    }
+
+    /// Puts an order that was previously resting back into the book without matching it, used
+    /// when rebuilding a book from storage. Orders must be restored in the order they were placed.
+    pub fn restore(&mut self, order: Order) {
+        self.rest(order);
+    }
+
+    /// Removes a resting order from the book.
+    pub fn cancel(&mut self, id: OrderId) -> Option<Order> {
+        [&mut self.bids, &mut self.asks]
+            .into_iter()
+            .find_map(|side| {
+                let index = side.iter().position(|o| o.id == id)?;
+                side.remove(index)
+            })
+    }
+
+    pub fn get(&self, id: OrderId) -> Option<&Order> {
+        self.orders().find(|o| o.id == id)
+    }
+
+    /// Every order resting in the book, bids first.
+    pub fn orders(&self) -> impl Iterator<Item = &Order> {
+        self.bids.iter().chain(self.asks.iter())
+    }
+
+    fn rest(&mut self, order: Order) {
+        // Orders at an equal price go behind the ones already resting there to keep time priority
+        let (side, index) = match order.side {
+            Side::Buy => {
+                let index = self.bids.partition_point(|o| o.price >= order.price);
+                (&mut self.bids, index)
+            }
+            Side::Sell => {
+                let index = self.asks.partition_point(|o| o.price <= order.price);
+                (&mut self.asks, index)
+            }
+        };
+        side.insert(index, order);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::Amount;
+    use crate::trading::{market::SwapPair, order::OrderRequest};
+
+    impl OrderBook {
+        /// Matches `order` against the opposite side of the book and rests whatever is left of
+        /// it, as the backend does in two steps.
+        fn submit(&mut self, order: Order) -> OrderExecution {
+            let execution = self.match_order(order);
+            self.apply(&execution);
+            execution
+        }
+
+        fn best_bid(&self) -> Option<&Order> {
+            self.bids.front()
+        }
+
+        fn best_ask(&self) -> Option<&Order> {
+            self.asks.front()
+        }
+    }
+
+    fn amount(val: f64) -> Amount {
+        Amount::try_from(val).unwrap()
+    }
+
+    fn order(id: OrderId, user_id: i32, side: Side, price: f64, quantity: f64) -> Order {
+        let request = OrderRequest {
+            swap_pair: SwapPair::new("ABC", "USD"),
+            side,
+            price: amount(price),
+            quantity: amount(quantity),
+        };
+        Order::new(id, user_id, &request)
+    }
+
+    #[test]
+    fn test_non_crossing_orders_rest() {
+        let mut book = OrderBook::default();
+
+        let execution = book.submit(order(1, 1, Side::Buy, 10.0, 5.0));
+        assert!(execution.fills.is_empty());
+        assert_eq!(execution.order.status, OrderStatus::Open);
+
+        let execution = book.submit(order(2, 2, Side::Sell, 11.0, 5.0));
+        assert!(execution.fills.is_empty());
+
+        assert_eq!(book.best_bid().map(|o| o.id), Some(1));
+        assert_eq!(book.best_ask().map(|o| o.id), Some(2));
+    }
+
+    #[test]
+    fn test_price_priority() {
+        let mut book = OrderBook::default();
+        book.submit(order(1, 1, Side::Sell, 12.0, 1.0));
+        book.submit(order(2, 1, Side::Sell, 10.0, 1.0));
+        book.submit(order(3, 1, Side::Sell, 11.0, 1.0));
+
+        let OrderExecution {
+            order: taker,
+            fills,
+            ..
+        } = book.submit(order(4, 2, Side::Buy, 11.5, 3.0));
+
+        assert_eq!(
+            fills.iter().map(|f| f.maker_order_id).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(fills[0].price, amount(10.0));
+        assert_eq!(fills[1].price, amount(11.0));
+        assert_eq!(taker.status, OrderStatus::PartiallyFilled);
+        assert_eq!(taker.remaining, amount(1.0));
+        assert_eq!(book.best_bid().map(|o| o.id), Some(4));
+        assert_eq!(book.best_ask().map(|o| o.id), Some(1));
+    }
+
+    #[test]
+    fn test_time_priority_and_partial_fill() {
+        let mut book = OrderBook::default();
+        book.submit(order(1, 1, Side::Buy, 10.0, 2.0));
+        book.submit(order(2, 2, Side::Buy, 10.0, 2.0));
+
+        let OrderExecution {
+            order: taker,
+            makers,
+            fills,
+        } = book.submit(order(3, 3, Side::Sell, 9.0, 3.0));
+
+        assert_eq!(taker.status, OrderStatus::Filled);
+        assert_eq!(fills.len(), 2);
+        assert_eq!(fills[0].maker_order_id, 1);
+        assert_eq!(fills[0].quantity, amount(2.0));
+        assert_eq!(fills[0].buyer_id, 1);
+        assert_eq!(fills[0].seller_id, 3);
+        assert_eq!(fills[1].maker_order_id, 2);
+        assert_eq!(fills[1].quantity, amount(1.0));
+        assert_eq!(makers[0].status, OrderStatus::Filled);
+        assert_eq!(makers[1].status, OrderStatus::PartiallyFilled);
+
+        let resting = book.get(2).expect("Order 2 should still be resting");
+        assert_eq!(resting.status, OrderStatus::PartiallyFilled);
+        assert_eq!(resting.remaining, amount(1.0));
+        assert!(book.get(1).is_none());
+    }
+
+    #[test]
+    fn test_matching_leaves_book_until_applied() {
+        let mut book = OrderBook::default();
+        book.submit(order(1, 1, Side::Sell, 10.0, 2.0));
+        book.submit(order(2, 1, Side::Sell, 11.0, 2.0));
+
+        let execution = book.match_order(order(3, 2, Side::Buy, 11.0, 3.0));
+        assert_eq!(execution.fills.len(), 2);
+        assert_eq!(book.get(1).map(|o| o.remaining), Some(amount(2.0)));
+        assert_eq!(book.get(2).map(|o| o.remaining), Some(amount(2.0)));
+
+        book.apply(&execution);
+        assert!(book.get(1).is_none());
+        assert_eq!(book.get(2).map(|o| o.remaining), Some(amount(1.0)));
+        assert_eq!(book.best_ask().map(|o| o.id), Some(2));
+        assert!(book.best_bid().is_none());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut book = OrderBook::default();
+        book.submit(order(1, 1, Side::Buy, 10.0, 2.0));
+
+        assert!(book.cancel(1).is_some());
+        assert!(book.cancel(1).is_none());
+        assert!(book.best_bid().is_none());
+    }
+}
@@ -0,0 +1,240 @@
+//! Wallet holds and settlement for orders.
+//!
+//! Every order holds the funds it could spend: a buy holds `price * quantity` of the quote
+//! currency, a sell holds `quantity` of the stock being sold. The order keeps track of what it
+//! still holds, so fills never release more than was held and the last fill releases whatever is
+//! left over from rounding. Fills move the held funds from one user to the other and release
+//! whatever was held above the execution price. All functions here run inside an open
+//! transaction so holds, fills and order rows commit together.
+
+use anyhow::anyhow;
+use diesel::prelude::*;
+
+use crate::db::connection::DbConnection;
+use crate::db::filter::Filter;
+use crate::db::manager::{DatabaseImpl, Transaction};
+use crate::db::models::{
+    stock,
+    wallet::{schema::wallets, Wallet},
+};
+
+use crate::money::Amount;
+
+use super::error::TradeError;
+use super::order::{Order, OrderExecution, OrderStatus, Side};
+
+fn stock_id(tx: &Transaction, symbol: &str) -> Result<i32, TradeError> {
+    use stock::schema::stock;
+
+    tx.with_connection(|conn| {
+        stock::table
+            .filter(stock::symbol.eq(symbol))
+            .select(stock::id)
+            .first::<Option<i32>>(conn)
+            .optional()?
+            .flatten()
+            .ok_or_else(|| TradeError::UnknownStock(symbol.to_owned()))
+    })
+}
+
+/// Reads a wallet and locks it until the transaction ends, since it is about to be written from
+/// what was read. SQLite transactions already hold the write lock for the whole database.
+fn find_wallet(
+    tx: &Transaction,
+    user_id: i32,
+    stock_id: i32,
+) -> Result<Option<Wallet>, TradeError> {
+    tx.with_connection(|conn| {
+        let query = wallets::table
+            .filter(wallets::user_id.eq(user_id))
+            .filter(wallets::stock_id.eq(stock_id));
+        let wallet = match conn {
+            DbConnection::Postgres(conn) => query.for_update().first::<Wallet>(conn),
+            conn => query.first::<Wallet>(conn),
+        };
+        Ok(wallet.optional()?)
+    })
+}
+
+/// Returns the user's wallet for a stock, opening an empty one if they don't have one yet.
+fn wallet(tx: &Transaction, user_id: i32, stock_id: i32) -> Result<Wallet, TradeError> {
+    if let Some(wallet) = find_wallet(tx, user_id, stock_id)? {
+        return Ok(wallet);
+    }
+
+    tx.insert_row(
+        wallets::table,
+        &Wallet {
+            id: None,
+            stock_id,
+            user_id,
+            balance: Amount::ZERO,
+            reserved: Amount::ZERO,
+        },
+    )?;
+
+    find_wallet(tx, user_id, stock_id)?
+        .ok_or_else(|| TradeError::Internal(anyhow!("Wallet was not created")))
+}
+
+fn overflow() -> TradeError {
+    TradeError::Internal(anyhow!("Amount overflowed while settling"))
+}
+
+/// Adds `balance` and `reserved` to the user's wallet for a stock. Fails with
+/// [`TradeError::Overdrawn`] rather than leave either negative or hold more than the balance.
+fn adjust(
+    tx: &Transaction,
+    user_id: i32,
+    stock_id: i32,
+    balance: Amount,
+    reserved: Amount,
+) -> Result<(), TradeError> {
+    let wallet = wallet(tx, user_id, stock_id)?;
+    let id = wallet
+        .id
+        .ok_or_else(|| anyhow!("Wallet row is missing an id"))?;
+    let balance = wallet.balance.checked_add(balance).ok_or_else(overflow)?;
+    let reserved = wallet.reserved.checked_add(reserved).ok_or_else(overflow)?;
+    if reserved < Amount::ZERO || reserved > balance {
+        return Err(TradeError::Overdrawn {
+            user_id,
+            stock_id,
+            balance,
+            reserved,
+        });
+    }
+
+    tx.update_rows(
+        wallets::table,
+        vec![Filter::eq(wallets::id, id)],
+        (wallets::balance.eq(balance), wallets::reserved.eq(reserved)),
+    )?;
+    Ok(())
+}
+
+/// The symbol of the wallet an order holds its funds in.
+fn held_symbol(order: &Order) -> &str {
+    match order.side {
+        Side::Buy => order.swap_pair.quote(),
+        Side::Sell => order.swap_pair.base(),
+    }
+}
+
+/// The amount an order holds while `quantity` of it is unfilled.
+fn hold(order: &Order, quantity: Amount) -> Result<Amount, TradeError> {
+    match order.side {
+        Side::Buy => order.price.checked_mul(quantity).ok_or_else(overflow),
+        Side::Sell => Ok(quantity),
+    }
+}
+
+/// Checks the user can cover a newly placed order and holds the funds for it.
+pub fn reserve(tx: &Transaction, order: &mut Order) -> Result<(), TradeError> {
+    let symbol = held_symbol(order);
+    let required = hold(order, order.quantity)?;
+    let stock_id = stock_id(tx, symbol)?;
+    let wallet = wallet(tx, order.user_id, stock_id)?;
+
+    if wallet.available() < required {
+        return Err(TradeError::InsufficientFunds {
+            symbol: symbol.to_owned(),
+            required,
+            available: wallet.available(),
+        });
+    }
+
+    adjust(tx, order.user_id, stock_id, Amount::ZERO, required)?;
+    order.held = required;
+    Ok(())
+}
+
+/// Adds `amount` to the user's wallet for a stock, opening the wallet if they don't have one.
+pub fn deposit(
+    tx: &Transaction,
+    user_id: i32,
+    symbol: &str,
+    amount: Amount,
+) -> Result<Wallet, TradeError> {
+    let stock_id = stock_id(tx, symbol)?;
+    adjust(tx, user_id, stock_id, amount, Amount::ZERO)?;
+    wallet(tx, user_id, stock_id)
+}
+
+/// Releases whatever an order still holds, used when it is cancelled.
+pub fn release(tx: &Transaction, order: &mut Order) -> Result<(), TradeError> {
+    let stock_id = stock_id(tx, held_symbol(order))?;
+    let held = order.held.checked_neg().ok_or_else(overflow)?;
+    adjust(tx, order.user_id, stock_id, Amount::ZERO, held)?;
+    order.held = Amount::ZERO;
+    Ok(())
+}
+
+/// Takes what a fill of `quantity` uses from an order's hold. That is never more than the order
+/// still holds, and is all of it once `last_fill` has filled the order.
+fn take_hold(order: &mut Order, quantity: Amount, last_fill: bool) -> Result<Amount, TradeError> {
+    let taken = if last_fill && order.status == OrderStatus::Filled {
+        order.held
+    } else {
+        order.held.min(hold(order, quantity)?)
+    };
+    order.held = order.held.checked_sub(taken).ok_or_else(overflow)?;
+    Ok(taken)
+}
+
+/// Moves funds between buyer and seller for every fill in an execution, and takes what each fill
+/// used from the holds of the orders in it.
+pub fn settle(tx: &Transaction, execution: &mut OrderExecution) -> Result<(), TradeError> {
+    if execution.fills.is_empty() {
+        return Ok(());
+    }
+
+    let swap_pair = &execution.order.swap_pair;
+    let base_id = stock_id(tx, swap_pair.base())?;
+    let quote_id = stock_id(tx, swap_pair.quote())?;
+    let OrderExecution {
+        order: taker,
+        makers,
+        fills,
+    } = execution;
+
+    for (index, fill) in fills.iter().enumerate() {
+        let maker = makers
+            .iter_mut()
+            .find(|o| o.id == fill.maker_order_id)
+            .ok_or_else(|| anyhow!("Fill refers to a maker that isn't in the execution"))?;
+        // a maker takes part in one fill per execution, the taker in all of them
+        let taker_done = index + 1 == fills.len();
+        let (buyer_held, seller_held) = match taker.side {
+            Side::Buy => (
+                take_hold(taker, fill.quantity, taker_done)?,
+                take_hold(maker, fill.quantity, true)?,
+            ),
+            Side::Sell => (
+                take_hold(maker, fill.quantity, true)?,
+                take_hold(taker, fill.quantity, taker_done)?,
+            ),
+        };
+
+        // the buyer held funds at their own limit price, which can be above the execution price.
+        // Each fill's cost is rounded on its own, so at the limit price the costs can add up to
+        // a little more than the hold, which was rounded once. The buyer never pays more than
+        // this fill took from their hold.
+        let cost = fill.price.checked_mul(fill.quantity).ok_or_else(overflow)?;
+        let cost = cost.min(buyer_held);
+        let neg = |amount: Amount| amount.checked_neg().ok_or_else(overflow);
+
+        adjust(tx, fill.buyer_id, quote_id, neg(cost)?, neg(buyer_held)?)?;
+        adjust(tx, fill.buyer_id, base_id, fill.quantity, Amount::ZERO)?;
+        adjust(
+            tx,
+            fill.seller_id,
+            base_id,
+            neg(fill.quantity)?,
+            neg(seller_held)?,
+        )?;
+        adjust(tx, fill.seller_id, quote_id, cost, Amount::ZERO)?;
+    }
+
+    Ok(())
+}